@@ -0,0 +1,965 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Receiver,
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use log::{info, warn, Level};
+use openh264::decoder::Decoder;
+
+use rover_rev::relay;
+use rover_rev::rover::{
+    arbiter::Operator,
+    discovery, duration,
+    media::StreamPacket,
+    mission::{Mission, MissionOutcome},
+    patrol::PatrolConfig,
+    settings::CameraSettings,
+    turret::{TurretPosition, TurretPresets},
+    wifi::{WifiConfig, WifiMode, WifiSecurity},
+    Camera, Command, Direction, HorizontalDirection, Resolution, Rover, Speed, VerticalDirection,
+    DEFAULT_ADDRESS, MAX_CONTRAST, MAX_FRAME_RATE,
+};
+use rover_rev::server::{
+    self,
+    hls::{HlsConfig, HlsRecorder},
+    mjpeg::MjpegConfig,
+    mqtt::{self, MqttConfig},
+    transcode::TranscodeConfig,
+};
+
+/// Headless control of the Brookstone Rover Revolution
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Address of the rover, either `ip` or `ip:port`
+    #[arg(long, default_value_t = DEFAULT_ADDRESS, value_parser = parse_address)]
+    address: SocketAddr,
+
+    /// Log everything down to trace level
+    #[arg(long, short)]
+    verbose: bool,
+
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Drive for a fixed duration, then stop
+    Drive {
+        direction: DriveDirection,
+
+        #[arg(long, default_value = "neutral")]
+        steer: Steer,
+
+        #[arg(long = "for", value_parser = duration::parse)]
+        duration: Duration,
+
+        #[arg(long)]
+        slow: bool,
+    },
+    /// Move the turret for a fixed duration, then stop
+    Turret {
+        direction: TurretDirection,
+
+        #[arg(long = "for", value_parser = duration::parse)]
+        duration: Duration,
+    },
+    /// Aim the turret at a preset, or at `center`
+    Aim {
+        target: String,
+
+        /// TOML file with named positions, as saved by the viewer
+        #[arg(long, default_value = "turret-presets.toml")]
+        presets: PathBuf,
+
+        /// Drive into the end stops first, otherwise the turret is assumed to be centered
+        #[arg(long)]
+        home: bool,
+    },
+    /// Sweep the turret back and forth, press enter to stop
+    Patrol {
+        /// The turret pans between -width and width, 1 being the end stops
+        #[arg(long, default_value_t = 0.8)]
+        width: f32,
+
+        /// Tilt of each sweep from -1 to 1, repeat for several sweeps at different tilts
+        #[arg(long = "tilt", allow_negative_numbers = true)]
+        tilts: Vec<f32>,
+
+        /// Pause at each end of a sweep
+        #[arg(long, default_value = "2s", value_parser = duration::parse)]
+        dwell: Duration,
+
+        /// Stop after this long instead of waiting for enter
+        #[arg(long = "for", value_parser = duration::parse)]
+        duration: Option<Duration>,
+
+        /// Drive into the end stops first, otherwise the turret is assumed to be centered
+        #[arg(long)]
+        home: bool,
+    },
+    /// Select the camera that is streamed
+    Camera { camera: CameraChoice },
+    /// Switch the infrared lights on or off
+    Stealth { state: Toggle },
+    /// Save the next decoded frame as PNG
+    Snapshot { path: PathBuf },
+    /// Write the raw H.264 stream to a file
+    Record {
+        path: PathBuf,
+
+        #[arg(long, value_parser = duration::parse)]
+        duration: Duration,
+    },
+    /// List rovers answering the discovery probe
+    Discover {
+        #[arg(long, default_value = "2s", value_parser = duration::parse)]
+        timeout: Duration,
+    },
+    /// Print camera id and firmware versions
+    Info,
+    /// Wait for telemetry and print battery and signal state
+    Status {
+        #[arg(long, default_value = "10s", value_parser = duration::parse)]
+        timeout: Duration,
+    },
+    /// Show or change the WiFi configuration
+    Wifi {
+        #[command(subcommand)]
+        command: WifiCommand,
+    },
+    /// Show the camera image settings, or change those given
+    Settings {
+        #[arg(long)]
+        brightness: Option<u8>,
+
+        /// From 0 to 6
+        #[arg(long, value_parser = clap::value_parser!(u8).range(0..=MAX_CONTRAST as i64))]
+        contrast: Option<u8>,
+
+        #[arg(long)]
+        resolution: Option<ResolutionChoice>,
+
+        /// Frames per second, from 1 to 30
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=MAX_FRAME_RATE as i64))]
+        frame_rate: Option<u8>,
+
+        /// Upside down
+        #[arg(long)]
+        flip: Option<Toggle>,
+
+        /// Left and right swapped
+        #[arg(long)]
+        mirror: Option<Toggle>,
+    },
+    /// Share the rover with other clients on the network until enter is pressed
+    Serve(Box<ServeArgs>),
+    /// Play back a mission script (TOML or JSON), press enter to abort
+    Mission { path: PathBuf },
+    /// Relay the rover over TLS, or reach it through such a relay, until enter is pressed
+    Relay {
+        #[command(subcommand)]
+        command: RelayCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum RelayCommand {
+    /// Accept relay clients on this address and forward them to the rover at --address
+    Serve {
+        listen: SocketAddr,
+
+        /// PEM certificate chain of the relay
+        #[arg(long)]
+        cert: PathBuf,
+
+        /// PEM private key of the relay
+        #[arg(long)]
+        key: PathBuf,
+
+        /// Require client certificates issued by these PEM CA certificates
+        #[arg(long)]
+        client_ca: Option<PathBuf>,
+
+        /// Require one of these tokens, may be repeated
+        #[arg(long = "token")]
+        tokens: Vec<String>,
+    },
+    /// Forward connections on a local address through a relay at `host:port`, for the viewer or
+    /// --address of other commands
+    Connect {
+        relay: String,
+
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: SocketAddr,
+
+        /// PEM CA certificates the relay's certificate has to chain to
+        #[arg(long)]
+        ca: PathBuf,
+
+        /// PEM client certificate chain, for relays requiring one
+        #[arg(long, requires = "key")]
+        cert: Option<PathBuf>,
+
+        /// PEM private key of the client certificate
+        #[arg(long, requires = "cert")]
+        key: Option<PathBuf>,
+
+        #[arg(long)]
+        token: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Serve browser remote control on this address, e.g. 0.0.0.0:8000
+    #[arg(long)]
+    web: Option<SocketAddr>,
+
+    /// Serve the HTTP/JSON control API on this address, e.g. 0.0.0.0:8001
+    #[arg(long)]
+    api: Option<SocketAddr>,
+
+    /// Token that lets API clients take control over from other operators
+    #[arg(long, requires = "api")]
+    api_admin_token: Option<String>,
+
+    /// Re-publish the camera over RTSP on this address, e.g. 0.0.0.0:8554
+    #[arg(long)]
+    rtsp: Option<SocketAddr>,
+
+    /// Record the video as rolling HLS segments and a playlist into this directory
+    #[arg(long)]
+    hls: Option<PathBuf>,
+
+    /// Target length of each HLS segment
+    #[arg(long, default_value = "4s", value_parser = duration::parse)]
+    hls_segment: Duration,
+
+    /// Delete HLS segments older than this, e.g. 24h
+    #[arg(long, value_parser = duration::parse)]
+    hls_max_age: Option<Duration>,
+
+    /// Delete the oldest HLS segments while all of them take up more megabytes than this
+    #[arg(long)]
+    hls_max_size: Option<u64>,
+
+    /// Serve MJPEG on /video.mjpg and /snapshot.jpg at this address, e.g. 0.0.0.0:8080
+    #[arg(long)]
+    mjpeg: Option<SocketAddr>,
+
+    /// MJPEG frames per second
+    #[arg(long, default_value_t = MjpegConfig::default().frame_rate)]
+    mjpeg_rate: f32,
+
+    /// MJPEG quality from 1 to 100
+    #[arg(long, default_value_t = MjpegConfig::default().quality,
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    mjpeg_quality: u8,
+
+    /// Bridge to this MQTT broker, `host`, `host:port` or `[ipv6]:port`
+    #[arg(long)]
+    mqtt: Option<String>,
+
+    /// MQTT user name and password as `user:password`
+    #[arg(long)]
+    mqtt_credentials: Option<String>,
+
+    /// MQTT base topic, rover/<camera id> by default
+    #[arg(long)]
+    mqtt_topic: Option<String>,
+
+    /// Do not publish Home Assistant discovery configs
+    #[arg(long)]
+    no_mqtt_discovery: bool,
+
+    /// Export Prometheus metrics on /metrics at this address, e.g. 0.0.0.0:9100
+    #[arg(long)]
+    metrics: Option<SocketAddr>,
+
+    /// Scale down and re-encode the video for these outputs, may be repeated
+    #[arg(long)]
+    transcode: Vec<Output>,
+
+    /// Size of the transcoded video, `widthxheight`
+    #[arg(long, default_value = "320x240", value_parser = parse_size)]
+    transcode_size: (u32, u32),
+
+    /// Frames per second of the transcoded video
+    #[arg(long, default_value_t = TranscodeConfig::default().frame_rate)]
+    transcode_rate: f32,
+
+    /// Kilobits per second of the transcoded video while clients keep up
+    #[arg(
+        long,
+        default_value_t = TranscodeConfig::default().bitrate / 1000,
+        value_parser = clap::value_parser!(u32).range(..=MAX_KBPS)
+    )]
+    transcode_bitrate: u32,
+
+    /// Kilobits per second the transcoded video does not go below on slow links
+    #[arg(
+        long,
+        default_value_t = TranscodeConfig::default().min_bitrate / 1000,
+        value_parser = clap::value_parser!(u32).range(..=MAX_KBPS)
+    )]
+    transcode_min_bitrate: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    Web,
+    Rtsp,
+    Hls,
+    Mjpeg,
+}
+
+#[derive(Subcommand)]
+enum WifiCommand {
+    /// Print the current WiFi configuration
+    Show,
+    /// Switch the rover to infrastructure mode, joining an existing network
+    Join {
+        ssid: String,
+
+        #[arg(long, default_value = "wpa2-psk")]
+        security: Security,
+
+        #[arg(long, default_value = "")]
+        passphrase: String,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Security {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DriveDirection {
+    Forward,
+    Backward,
+    Neutral,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Steer {
+    Left,
+    Neutral,
+    Right,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TurretDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CameraChoice {
+    Driving,
+    Turret,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ResolutionChoice {
+    /// 640x480
+    Vga,
+    /// 320x240
+    Qvga,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Toggle {
+    On,
+    Off,
+}
+
+/// Highest bitrate option in kbit/s whose value in bit/s still fits into a `u32`.
+const MAX_KBPS: i64 = (u32::MAX / 1000) as i64;
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+const TURRET_TIMEOUT: Duration = Duration::from_secs(30);
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+/// Who the commands given on the command line come from, in the audit log.
+const OPERATOR: &str = "cli";
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    simple_logger::init_with_level(if cli.verbose {
+        Level::Trace
+    } else {
+        Level::Info
+    })?;
+
+    if let CliCommand::Discover { timeout } = cli.command {
+        for rover in discovery::discover(timeout)? {
+            println!("{rover}");
+        }
+
+        return Ok(());
+    }
+
+    if let CliCommand::Relay { command } = cli.command {
+        return run_relay(cli.address, command);
+    }
+
+    let (rover, frame_receiver) = Rover::connect(cli.address)?;
+
+    if let CliCommand::Serve(args) = cli.command {
+        return serve(rover, frame_receiver, *args);
+    }
+
+    let operator = Operator::admin(OPERATOR);
+    match cli.command {
+        CliCommand::Drive {
+            direction,
+            steer,
+            duration,
+            slow,
+        } => {
+            let direction = match direction {
+                DriveDirection::Forward => Direction::Forward,
+                DriveDirection::Backward => Direction::Backward,
+                DriveDirection::Neutral => Direction::Neutral,
+            };
+            let steer = match steer {
+                Steer::Left => HorizontalDirection::Left,
+                Steer::Neutral => HorizontalDirection::Neutral,
+                Steer::Right => HorizontalDirection::Right,
+            };
+            let speed = if slow { Speed::Slow } else { Speed::Fast };
+
+            rover.send_command(&operator, Command::Drive(direction, steer, speed))?;
+            hold(&rover, &operator, duration);
+            rover.send_command(
+                &operator,
+                Command::Drive(Direction::Neutral, HorizontalDirection::Neutral, speed),
+            )?;
+        }
+        CliCommand::Turret {
+            direction,
+            duration,
+        } => {
+            let (start, stop) = match direction {
+                TurretDirection::Up => (
+                    Command::CameraMoveVertical(VerticalDirection::Up),
+                    Command::CameraMoveVertical(VerticalDirection::Neutral),
+                ),
+                TurretDirection::Down => (
+                    Command::CameraMoveVertical(VerticalDirection::Down),
+                    Command::CameraMoveVertical(VerticalDirection::Neutral),
+                ),
+                TurretDirection::Left => (
+                    Command::CameraMoveHorizontal(HorizontalDirection::Left),
+                    Command::CameraMoveHorizontal(HorizontalDirection::Neutral),
+                ),
+                TurretDirection::Right => (
+                    Command::CameraMoveHorizontal(HorizontalDirection::Right),
+                    Command::CameraMoveHorizontal(HorizontalDirection::Neutral),
+                ),
+            };
+
+            rover.send_command(&operator, start)?;
+            hold(&rover, &operator, duration);
+            rover.send_command(&operator, stop)?;
+        }
+        CliCommand::Aim {
+            target,
+            presets,
+            home,
+        } => {
+            let position = match target.as_str() {
+                "center" => TurretPosition::CENTER,
+                name => TurretPresets::load(&presets)?.get(name)?,
+            };
+
+            if home {
+                rover.home_turret(&operator)?;
+                if !rover.wait_for_turret(TURRET_TIMEOUT) {
+                    anyhow::bail!("turret homing timed out");
+                }
+            }
+
+            rover.turret_goto(&operator, position)?;
+            if !rover.wait_for_turret(TURRET_TIMEOUT) {
+                anyhow::bail!("turret did not reach {target}");
+            }
+        }
+        CliCommand::Patrol {
+            width,
+            tilts,
+            dwell,
+            duration,
+            home,
+        } => {
+            if home {
+                rover.home_turret(&operator)?;
+                if !rover.wait_for_turret(TURRET_TIMEOUT) {
+                    anyhow::bail!("turret homing timed out");
+                }
+            }
+
+            let abort = abort_on_enter();
+            rover.start_patrol(
+                &operator,
+                PatrolConfig {
+                    width,
+                    tilts,
+                    dwell,
+                    ..PatrolConfig::default()
+                },
+            )?;
+
+            let end = duration.map(|duration| Instant::now() + duration);
+            while !abort.load(Ordering::Relaxed) && end.is_none_or(|end| Instant::now() < end) {
+                std::thread::sleep(REFRESH_INTERVAL);
+            }
+
+            rover.stop_patrol(&operator)?;
+        }
+        CliCommand::Camera { camera } => {
+            rover.send_command(
+                &operator,
+                Command::UseCamera(match camera {
+                    CameraChoice::Driving => Camera::Driving,
+                    CameraChoice::Turret => Camera::Turret,
+                }),
+            )?;
+        }
+        CliCommand::Stealth { state } => {
+            rover.send_command(&operator, Command::StealthMode(matches!(state, Toggle::On)))?;
+        }
+        CliCommand::Snapshot { path } => {
+            snapshot(&frame_receiver, &path)?;
+            info!("saved snapshot to {}", path.display());
+        }
+        CliCommand::Record { path, duration } => {
+            let bytes = record(&frame_receiver, &path, duration)?;
+            info!("recorded {bytes} bytes to {}", path.display());
+        }
+        CliCommand::Discover { .. } | CliCommand::Serve(_) | CliCommand::Relay { .. } => {
+            unreachable!()
+        }
+        CliCommand::Info => {
+            let info = rover.device_info();
+            println!("camera id:      {}", info.camera_id);
+            println!("system version: {}", info.system_version);
+            println!("app version:    {}", info.app_version);
+        }
+        CliCommand::Status { timeout } => {
+            let updates = rover.telemetry();
+            if updates.recv_timeout(timeout).is_err() {
+                anyhow::bail!("no telemetry received within {}", duration::format(timeout));
+            }
+
+            let telemetry = rover.latest_telemetry();
+            let show = |value: Option<u8>| value.map_or("unknown".to_string(), |v| format!("{v}%"));
+            println!("battery:  {}", show(telemetry.battery_level));
+            println!(
+                "charging: {}",
+                telemetry
+                    .charging
+                    .map_or("unknown".to_string(), |c| c.to_string())
+            );
+            println!("signal:   {}", show(telemetry.signal_strength));
+            if telemetry.is_low_battery() {
+                warn!("battery is low");
+            }
+        }
+        CliCommand::Wifi {
+            command: WifiCommand::Show,
+        } => print_wifi_config(&rover.wifi_config()?),
+        CliCommand::Wifi {
+            command:
+                WifiCommand::Join {
+                    ssid,
+                    security,
+                    passphrase,
+                },
+        } => {
+            let config = WifiConfig {
+                mode: WifiMode::Infrastructure,
+                ssid,
+                security: match security {
+                    Security::Open => WifiSecurity::Open,
+                    Security::Wep => WifiSecurity::Wep,
+                    Security::WpaPsk => WifiSecurity::WpaPsk,
+                    Security::Wpa2Psk => WifiSecurity::Wpa2Psk,
+                },
+                passphrase,
+            };
+
+            rover.set_wifi_config(&operator, &config)?;
+
+            let stored = rover.wifi_config()?;
+            print_wifi_config(&stored);
+            if stored != config {
+                anyhow::bail!("rover did not store the new wifi config");
+            }
+
+            info!(
+                "rover will join {:?} after a restart, find it with `rover-cli discover`",
+                config.ssid
+            );
+        }
+        CliCommand::Settings {
+            brightness,
+            contrast,
+            resolution,
+            frame_rate,
+            flip,
+            mirror,
+        } => {
+            let mut settings = rover.camera_settings()?;
+            let on = |toggle| matches!(toggle, Toggle::On);
+
+            let mut commands = Vec::new();
+            commands.extend(brightness.map(Command::Brightness));
+            commands.extend(contrast.map(Command::Contrast));
+            commands.extend(resolution.map(|resolution| {
+                Command::Resolution(match resolution {
+                    ResolutionChoice::Vga => Resolution::Vga,
+                    ResolutionChoice::Qvga => Resolution::Qvga,
+                })
+            }));
+            commands.extend(frame_rate.map(Command::FrameRate));
+            if flip.is_some() || mirror.is_some() {
+                settings.orientation.flip = flip.map_or(settings.orientation.flip, on);
+                settings.orientation.mirror = mirror.map_or(settings.orientation.mirror, on);
+                commands.push(Command::Orientation(settings.orientation));
+            }
+
+            if !commands.is_empty() {
+                for command in commands {
+                    rover.send_command(&operator, command)?;
+                }
+                settings = rover.camera_settings()?;
+            }
+
+            print_camera_settings(&settings);
+        }
+        CliCommand::Mission { path } => {
+            let mission = Mission::load(&path)?;
+
+            let abort = abort_on_enter();
+
+            info!(
+                "running mission with {} steps ({}), press enter to abort",
+                mission.steps.len(),
+                duration::format(mission.duration())
+            );
+
+            match mission.run(&rover, &operator, &frame_receiver, &abort)? {
+                MissionOutcome::Completed => info!("mission completed"),
+                MissionOutcome::Aborted => warn!("mission aborted"),
+            }
+        }
+    }
+
+    rover.shutdown()
+}
+
+/// Runs the requested servers until enter is pressed or the rover disconnects. The servers keep
+/// the rover session alive, so it is only stopped, not shut down.
+fn serve(
+    rover: Rover,
+    frame_receiver: Receiver<StreamPacket>,
+    args: ServeArgs,
+) -> anyhow::Result<()> {
+    if [args.web, args.api, args.rtsp, args.mjpeg, args.metrics]
+        .iter()
+        .all(Option::is_none)
+        && args.hls.is_none()
+        && args.mqtt.is_none()
+    {
+        anyhow::bail!(
+            "nothing to serve, pass --web, --api, --rtsp, --hls, --mjpeg, --mqtt or --metrics"
+        );
+    }
+
+    let rover = Arc::new(rover);
+    let (media, media_thread) =
+        server::spawn_media_broadcast(frame_receiver, rover.metrics().clone());
+    let transcoded = if args.transcode.is_empty() {
+        None
+    } else {
+        let (width, height) = args.transcode_size;
+        let (transcoded, _) = server::transcode::spawn(
+            &media,
+            rover.metrics().clone(),
+            TranscodeConfig {
+                width,
+                height,
+                frame_rate: args.transcode_rate,
+                bitrate: args.transcode_bitrate * 1000,
+                min_bitrate: args.transcode_min_bitrate * 1000,
+            },
+        )?;
+        Some(transcoded)
+    };
+    let media_for = |output: Output| match &transcoded {
+        Some(transcoded) if args.transcode.contains(&output) => transcoded.clone(),
+        _ => media.clone(),
+    };
+
+    if let Some(address) = args.web {
+        server::web::spawn(address, rover.clone(), media_for(Output::Web))?;
+    }
+    if let Some(address) = args.api {
+        server::api::spawn(address, rover.clone(), args.api_admin_token.clone())?;
+    }
+    if let Some(address) = args.rtsp {
+        server::rtsp::spawn(address, media_for(Output::Rtsp))?;
+    }
+    if let Some(address) = args.mjpeg {
+        server::mjpeg::spawn(
+            address,
+            media_for(Output::Mjpeg),
+            rover.metrics().clone(),
+            MjpegConfig {
+                frame_rate: args.mjpeg_rate,
+                quality: args.mjpeg_quality,
+            },
+        )?;
+    }
+    if let Some(address) = args.metrics {
+        server::metrics::spawn(address, rover.clone())?;
+    }
+    let recorder = match args.hls {
+        Some(directory) => Some(server::hls::spawn(
+            HlsConfig {
+                segment_duration: args.hls_segment,
+                max_age: args.hls_max_age,
+                max_size: args.hls_max_size.map(|megabytes| megabytes * 1_000_000),
+                ..HlsConfig::new(directory)
+            },
+            &media_for(Output::Hls),
+        )?),
+        None => None,
+    };
+    let bridge = match &args.mqtt {
+        Some(broker) => {
+            let (host, port) = MqttConfig::parse_broker(broker)?;
+            let credentials = match &args.mqtt_credentials {
+                Some(credentials) => Some(
+                    credentials
+                        .split_once(':')
+                        .map(|(user, password)| (user.to_string(), password.to_string()))
+                        .ok_or_else(|| {
+                            anyhow::anyhow!("expected --mqtt-credentials user:password")
+                        })?,
+                ),
+                None => None,
+            };
+
+            Some(mqtt::spawn(
+                MqttConfig {
+                    credentials,
+                    base_topic: args.mqtt_topic,
+                    discovery_prefix: (!args.no_mqtt_discovery)
+                        .then(|| mqtt::DEFAULT_DISCOVERY_PREFIX.to_string()),
+                    ..MqttConfig::new(host, port)
+                },
+                rover.clone(),
+            )?)
+        }
+        None => None,
+    };
+
+    let abort = abort_on_enter();
+    while !abort.load(Ordering::Relaxed)
+        && !media_thread.is_finished()
+        && !recorder.as_ref().is_some_and(HlsRecorder::is_finished)
+    {
+        std::thread::sleep(REFRESH_INTERVAL);
+    }
+
+    if media_thread.is_finished() {
+        warn!("rover disconnected");
+    }
+
+    let stopped = rover.stop(&Operator::admin(OPERATOR));
+    if let Some(recorder) = recorder {
+        recorder.finish()?;
+    }
+    if let Some(bridge) = bridge {
+        bridge.finish()?;
+    }
+    info!("session metrics:\n{}", rover.metrics().snapshot());
+
+    stopped
+}
+
+/// Runs either end of the relay until enter is pressed.
+fn run_relay(rover: SocketAddr, command: RelayCommand) -> anyhow::Result<()> {
+    match command {
+        RelayCommand::Serve {
+            listen,
+            cert,
+            key,
+            client_ca,
+            tokens,
+        } => {
+            relay::serve(
+                listen,
+                relay::ServerConfig {
+                    certificate: cert,
+                    key,
+                    client_ca,
+                    tokens,
+                    rover,
+                },
+            )?;
+        }
+        RelayCommand::Connect {
+            relay,
+            listen,
+            ca,
+            cert,
+            key,
+            token,
+        } => {
+            let (address, _) = relay::forward(
+                listen,
+                relay::ClientConfig {
+                    relay,
+                    ca,
+                    identity: cert.zip(key),
+                    token,
+                },
+            )?;
+            info!("connect to the rover at {address}");
+        }
+    }
+
+    let abort = abort_on_enter();
+    while !abort.load(Ordering::Relaxed) {
+        std::thread::sleep(REFRESH_INTERVAL);
+    }
+
+    Ok(())
+}
+
+/// Returns a flag that is set once enter is pressed.
+fn abort_on_enter() -> Arc<AtomicBool> {
+    let abort = Arc::new(AtomicBool::new(false));
+    {
+        let abort = abort.clone();
+        std::thread::spawn(move || {
+            // a closed stdin (e.g. in CI) must not abort
+            if matches!(std::io::stdin().read_line(&mut String::new()), Ok(n) if n > 0) {
+                abort.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+
+    abort
+}
+
+fn print_wifi_config(config: &WifiConfig) {
+    println!("mode:       {:?}", config.mode);
+    println!("ssid:       {}", config.ssid);
+    println!("security:   {:?}", config.security);
+    println!("passphrase: {}", "*".repeat(config.passphrase.len()));
+}
+
+fn print_camera_settings(settings: &CameraSettings) {
+    let (width, height) = settings.resolution.size();
+    println!("resolution:  {width}x{height}");
+    println!("brightness:  {}", settings.brightness);
+    println!("contrast:    {}", settings.contrast);
+    println!("frame rate:  {} fps", settings.frame_rate);
+    println!("flip:        {}", settings.orientation.flip);
+    println!("mirror:      {}", settings.orientation.mirror);
+    println!("power line:  {} Hz", settings.power_frequency);
+}
+
+/// Keeps the current motion going for `duration` without tripping the motion watchdog.
+fn hold(rover: &Rover, operator: &Operator, duration: Duration) {
+    let end = Instant::now() + duration;
+
+    while let Some(remaining) = end.checked_duration_since(Instant::now()) {
+        std::thread::sleep(remaining.min(REFRESH_INTERVAL));
+        rover.refresh_motion(operator);
+    }
+}
+
+fn snapshot(frame_receiver: &Receiver<StreamPacket>, path: &Path) -> anyhow::Result<()> {
+    let mut decoder = Decoder::new()?;
+
+    loop {
+        let packet = frame_receiver
+            .recv_timeout(FRAME_TIMEOUT)
+            .map_err(|e| anyhow::anyhow!("no video frame received: {e}"))?;
+
+        if let Some(image) = packet.to_rgb(&mut decoder)? {
+            return image.save_png(path);
+        }
+    }
+}
+
+fn record(
+    frame_receiver: &Receiver<StreamPacket>,
+    path: &Path,
+    duration: Duration,
+) -> anyhow::Result<usize> {
+    let mut file = BufWriter::new(
+        File::create(path)
+            .map_err(|e| anyhow::anyhow!("failed to create {}: {e}", path.display()))?,
+    );
+
+    let end = Instant::now() + duration;
+    let mut bytes = 0;
+
+    while let Some(remaining) = end.checked_duration_since(Instant::now()) {
+        let packet = match frame_receiver.recv_timeout(remaining.min(FRAME_TIMEOUT)) {
+            Ok(packet) => packet,
+            Err(_) if Instant::now() >= end => break,
+            Err(e) => anyhow::bail!("media stream stalled: {e}"),
+        };
+
+        if let StreamPacket::Video { data, .. } = packet {
+            file.write_all(&data)?;
+            bytes += data.len();
+        }
+    }
+
+    file.flush()?;
+
+    Ok(bytes)
+}
+
+fn parse_size(s: &str) -> anyhow::Result<(u32, u32)> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| anyhow::anyhow!("expected widthxheight, got {s:?}"))?;
+
+    Ok((width.parse()?, height.parse()?))
+}
+
+fn parse_address(s: &str) -> anyhow::Result<SocketAddr> {
+    match s.parse::<SocketAddr>() {
+        Ok(address) => Ok(address),
+        Err(_) => Ok(SocketAddr::new(
+            s.parse::<IpAddr>()
+                .map_err(|e| anyhow::anyhow!("invalid address {s:?}: {e}"))?,
+            DEFAULT_ADDRESS.port(),
+        )),
+    }
+}
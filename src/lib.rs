@@ -0,0 +1,3 @@
+pub mod relay;
+pub mod rover;
+pub mod server;
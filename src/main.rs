@@ -1,35 +1,81 @@
-use log::{trace, Level};
+use std::{
+    net::SocketAddr,
+    sync::mpsc::Receiver,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, trace, warn, Level};
 use openh264::decoder::Decoder;
 use sdl2::{
-    keyboard::Keycode,
+    event::{Event, WindowEvent},
+    keyboard::{Keycode, Mod},
+    messagebox::{
+        show_message_box, ButtonData, ClickedButton, MessageBoxButtonFlag, MessageBoxFlag,
+    },
     pixels::{Color, PixelFormatEnum},
-    render::TextureAccess,
+    rect::Rect,
+    render::{Texture, TextureAccess, TextureCreator, WindowCanvas},
+    video::WindowContext,
 };
 
-use crate::rover::{
-    media::StreamPacket, Camera, Command, Direction, HorizontalDirection, Rover, Speed,
-    VerticalDirection,
+use rover_rev::rover::{
+    arbiter::Operator,
+    discovery::{self, DiscoveredRover},
+    fleet::{Fleet, FleetMember, FleetPacket},
+    image::RgbImage,
+    media::StreamPacket,
+    mission::MissionRecorder,
+    patrol::PatrolConfig,
+    settings::CameraSettings,
+    telemetry::Telemetry,
+    turret::TurretPresets,
+    Camera, Command, Direction, HorizontalDirection, Resolution, Rover, Speed, VerticalDirection,
+    DEFAULT_ADDRESS, MAX_CONTRAST, MAX_FRAME_RATE,
 };
 
-mod rover;
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(1);
+const PRESETS_PATH: &str = "turret-presets.toml";
+const BRIGHTNESS_STEP: u8 = 16;
+const FRAME_RATE_STEP: u8 = 5;
+const MIN_FRAME_RATE: u8 = 5;
+/// Who the viewer's commands come from, in the audit log.
+const OPERATOR: &str = "viewer";
 
 fn main() {
     simple_logger::init_with_level(Level::Trace).unwrap();
 
-    let (mut rover, frame_receiver) = Rover::init().unwrap();
+    let Some(rovers) = choose_rovers() else {
+        return;
+    };
+
+    let (fleet, frame_receiver) = Fleet::connect(rovers).unwrap();
+    let mut active = 0;
     let mut steer = HorizontalDirection::Neutral;
     let mut direction = Direction::Neutral;
-    let mut stealth = false;
+    let mut snapshot = false;
+    let mut recorder: Option<MissionRecorder> = None;
     let speed = Speed::Fast;
+    // whoever sits at the viewer may take control from remote operators
+    let operator = Operator::admin(OPERATOR);
 
-    rover
-        .send_command(Command::Drive(direction, steer, speed))
-        .unwrap();
+    let mut presets = TurretPresets::load(PRESETS_PATH).unwrap_or_else(|e| {
+        warn!("failed to load turret presets: {e}");
+        TurretPresets::default()
+    });
+
+    for member in fleet.members() {
+        send_command(
+            &member.rover,
+            &operator,
+            &mut recorder,
+            Command::Drive(direction, steer, speed),
+        );
+    }
 
     let context = sdl2::init().unwrap();
     let video = context.video().unwrap();
     let window = video
-        .window("Rover Revolution", 320 * 4, 240 * 4)
+        .window(&title(&fleet, active), 320 * 4, 240 * 4)
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
@@ -37,40 +83,85 @@ fn main() {
 
     let mut event_pump = context.event_pump().unwrap();
 
-    let mut texture1 = textuer_creator
-        .create_texture(PixelFormatEnum::YV12, TextureAccess::Streaming, 640, 480)
-        .unwrap();
-    let mut texture2 = textuer_creator
-        .create_texture(PixelFormatEnum::YV12, TextureAccess::Streaming, 320, 240)
-        .unwrap();
-
-    let mut decoder = Decoder::new().unwrap();
+    let mut tiles = fleet
+        .members()
+        .iter()
+        .map(|member| Tile::new(member, &textuer_creator))
+        .collect::<Vec<_>>();
 
     'lop: loop {
-        if let Ok(packet) = frame_receiver.try_recv() {
-            trace!("packet: {:?}", packet);
+        for member in fleet.members() {
+            member.rover.refresh_motion(&operator);
+        }
+
+        let mut telemetry_changed = false;
+        for (tile, member) in tiles.iter_mut().zip(fleet.members()) {
+            if tile.telemetry.try_iter().count() == 0 {
+                continue;
+            }
+            telemetry_changed = true;
 
+            let telemetry = member.rover.latest_telemetry();
+            if telemetry.is_low_battery() && !tile.low_battery {
+                warn!(
+                    "{}: low battery: {:?}",
+                    member.name, telemetry.battery_level
+                );
+            }
+            tile.low_battery = telemetry.is_low_battery();
+        }
+        if telemetry_changed {
+            canvas
+                .window_mut()
+                .set_title(&title(&fleet, active))
+                .unwrap();
+        }
+
+        let mut redraw = false;
+        for FleetPacket { rover, packet } in frame_receiver.try_iter() {
+            trace!("packet from rover {rover}: {:?}", packet);
+
+            let keyframe = packet.is_keyframe();
             match packet {
-                StreamPacket::Audio {
-                    data,
-                    offset,
-                    index,
-                    ..
-                } => {
+                StreamPacket::Audio { .. } => {
                     //let x = rover::adpcm::adpcm_to_pcm(data.as_slice(), offset, index);
                     //println!("{x:?}")
                 }
                 StreamPacket::Video {
                     video_type, data, ..
                 } => {
-                    if let Some(frame) = decoder.decode(data.as_slice()).unwrap() {
-                        let texture = match video_type {
-                            1 => &mut texture1,
-                            2 => &mut texture2,
+                    let tile = &mut tiles[rover];
+                    let metrics = fleet.members()[rover].rover.metrics();
+
+                    // after an error the decoder can only pick up again at a keyframe
+                    tile.synced |= keyframe;
+                    if !tile.synced {
+                        metrics.record_dropped_frames(1);
+                        continue;
+                    }
+
+                    let decoded = tile.decoder.decode(data.as_slice()).unwrap_or_else(|e| {
+                        warn!(
+                            "failed to decode video of {}: {e}",
+                            fleet.members()[rover].name
+                        );
+                        metrics.record_decode_error();
+                        tile.synced = false;
+                        None
+                    });
+                    if let Some(frame) = decoded {
+                        if snapshot && rover == active {
+                            snapshot = false;
+                            save_snapshot(&RgbImage::from_yuv(&frame));
+                        }
+
+                        let shown = match video_type {
+                            1 => 0,
+                            2 => 1,
                             _ => todo!(),
                         };
 
-                        texture
+                        tile.textures[shown]
                             .update_yuv(
                                 None,
                                 frame.y_with_stride(),
@@ -81,124 +172,264 @@ fn main() {
                                 frame.strides_yuv().2,
                             )
                             .unwrap();
-
-                        canvas.set_draw_color(Color::RGB(20, 20, 20));
-                        canvas.clear();
-
-                        canvas.copy(texture, None, None).unwrap();
-
-                        canvas.present();
+                        tile.shown = Some(shown);
+                        redraw = true;
                     }
                 }
             }
         }
+        if redraw {
+            render(&mut canvas, &tiles, active);
+        }
+
+        let rover = fleet.rover(active).unwrap();
+        let tile = &mut tiles[active];
 
         for event in event_pump.poll_iter() {
             match event {
-                sdl2::event::Event::KeyDown {
+                Event::KeyDown {
                     keycode: Some(keycode),
+                    keymod,
                     repeat: false,
                     ..
                 } => match keycode {
                     Keycode::Q => break 'lop,
-                    Keycode::Num1 => {
-                        rover
-                            .send_command(Command::UseCamera(Camera::Driving))
+                    Keycode::Tab if fleet.len() > 1 => {
+                        // the rover losing drive input must not keep driving
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(Direction::Neutral, HorizontalDirection::Neutral, speed),
+                        );
+                        direction = Direction::Neutral;
+                        steer = HorizontalDirection::Neutral;
+
+                        active = (active + 1) % fleet.len();
+                        info!("driving {}", fleet.members()[active].name);
+                        canvas
+                            .window_mut()
+                            .set_title(&title(&fleet, active))
                             .unwrap();
+                        render(&mut canvas, &tiles, active);
+                        continue 'lop;
+                    }
+                    Keycode::Num1 => {
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::UseCamera(Camera::Driving),
+                        );
                     }
                     Keycode::Num2 => {
-                        rover
-                            .send_command(Command::UseCamera(Camera::Turret))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::UseCamera(Camera::Turret),
+                        );
                     }
                     Keycode::W => {
                         direction = Direction::Forward;
-                        rover
-                            .send_command(Command::Drive(direction, steer, speed))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(direction, steer, speed),
+                        );
                     }
                     Keycode::S => {
                         direction = Direction::Backward;
-                        rover
-                            .send_command(Command::Drive(direction, steer, speed))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(direction, steer, speed),
+                        );
                     }
                     Keycode::A => {
                         steer = HorizontalDirection::Left;
-                        rover
-                            .send_command(Command::Drive(direction, steer, speed))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(direction, steer, speed),
+                        );
                     }
                     Keycode::D => {
                         steer = HorizontalDirection::Right;
-                        rover
-                            .send_command(Command::Drive(direction, steer, speed))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(direction, steer, speed),
+                        );
                     }
                     Keycode::Up => {
-                        rover
-                            .send_command(Command::CameraMoveVertical(VerticalDirection::Up))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::CameraMoveVertical(VerticalDirection::Up),
+                        );
                     }
                     Keycode::Down => {
-                        rover
-                            .send_command(Command::CameraMoveVertical(VerticalDirection::Down))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::CameraMoveVertical(VerticalDirection::Down),
+                        );
                     }
                     Keycode::Left => {
-                        rover
-                            .send_command(Command::CameraMoveHorizontal(HorizontalDirection::Left))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::CameraMoveHorizontal(HorizontalDirection::Left),
+                        );
                     }
                     Keycode::Right => {
-                        rover
-                            .send_command(Command::CameraMoveHorizontal(HorizontalDirection::Right))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::CameraMoveHorizontal(HorizontalDirection::Right),
+                        );
                     }
                     Keycode::E => {
-                        stealth ^= true;
-                        rover.send_command(Command::StealthMode(stealth)).unwrap();
+                        tile.stealth ^= true;
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::StealthMode(tile.stealth),
+                        );
+                    }
+                    Keycode::P => snapshot = true,
+                    Keycode::C => {
+                        if let Err(e) = rover.turret_center(&operator) {
+                            warn!("failed to center turret: {e}");
+                        }
+                    }
+                    Keycode::K => {
+                        if let Err(e) = rover.home_turret(&operator) {
+                            warn!("failed to home turret: {e}");
+                        }
+                    }
+                    Keycode::T if rover.is_patrolling() => {
+                        if let Err(e) = rover.stop_patrol(&operator) {
+                            error!("failed to stop patrol: {e}");
+                        }
+                    }
+                    Keycode::T => {
+                        if let Err(e) = rover.start_patrol(&operator, PatrolConfig::default()) {
+                            warn!("failed to start patrol: {e}");
+                        }
+                    }
+                    Keycode::O => match rover.take_control(&operator) {
+                        Ok(()) => info!("took control of {}", fleet.members()[active].name),
+                        Err(e) => error!("failed to take control: {e}"),
+                    },
+                    Keycode::L => match rover.release_control(&operator) {
+                        Ok(()) => info!("released control of {}", fleet.members()[active].name),
+                        Err(e) => error!("failed to release control: {e}"),
+                    },
+                    Keycode::F1 | Keycode::F2 | Keycode::F3 | Keycode::F4 => {
+                        let name = keycode.name().to_lowercase();
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            presets.insert(name, rover.turret_position());
+                            match presets.save(PRESETS_PATH) {
+                                Ok(()) => info!("saved turret presets to {PRESETS_PATH}"),
+                                Err(e) => error!("failed to save turret presets: {e}"),
+                            }
+                        } else {
+                            let moved = presets
+                                .get(&name)
+                                .and_then(|position| rover.turret_goto(&operator, position));
+                            if let Err(e) = moved {
+                                warn!("{e}");
+                            }
+                        }
+                    }
+                    Keycode::R => match recorder.take() {
+                        Some(recorder) => save_mission(recorder),
+                        None => {
+                            info!("recording mission");
+                            recorder = Some(MissionRecorder::new());
+                        }
+                    },
+                    keycode => {
+                        if let Some(command) = adjust_setting(&mut tile.settings, keycode) {
+                            send_command(rover, &operator, &mut recorder, command);
+                        }
                     }
-
-                    _ => {}
                 },
-                sdl2::event::Event::KeyUp {
+                Event::Quit { .. } => break 'lop,
+                Event::Window {
+                    win_event: WindowEvent::Minimized,
+                    ..
+                } => {
+                    for member in fleet.members() {
+                        if let Err(e) = member.rover.pause_video(&operator) {
+                            warn!("failed to pause video of {}: {e}", member.name);
+                        }
+                    }
+                }
+                Event::Window {
+                    win_event: WindowEvent::Restored,
+                    ..
+                } => {
+                    for member in fleet.members() {
+                        if let Err(e) = member.rover.resume_video(&operator) {
+                            warn!("failed to resume video of {}: {e}", member.name);
+                        }
+                    }
+                }
+                Event::KeyUp {
                     keycode: Some(keycode),
                     repeat: false,
                     ..
                 } => match keycode {
                     Keycode::W | Keycode::S => {
                         direction = Direction::Neutral;
-                        rover
-                            .send_command(Command::Drive(
-                                direction,
-                                HorizontalDirection::Neutral,
-                                speed,
-                            ))
-                            .unwrap();
-                        rover
-                            .send_command(Command::Drive(direction, steer, speed))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(direction, HorizontalDirection::Neutral, speed),
+                        );
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::Drive(direction, steer, speed),
+                        );
                     }
                     Keycode::A if steer == HorizontalDirection::Left => {
                         steer = HorizontalDirection::Neutral;
-                        rover.send_command(Command::SteerStop(speed)).unwrap();
+                        send_command(rover, &operator, &mut recorder, Command::SteerStop(speed));
                     }
                     Keycode::D if steer == HorizontalDirection::Right => {
                         steer = HorizontalDirection::Neutral;
-                        rover.send_command(Command::SteerStop(speed)).unwrap();
+                        send_command(rover, &operator, &mut recorder, Command::SteerStop(speed));
                     }
                     Keycode::Up | Keycode::Down => {
-                        rover
-                            .send_command(Command::CameraMoveVertical(VerticalDirection::Neutral))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::CameraMoveVertical(VerticalDirection::Neutral),
+                        );
                     }
                     Keycode::Left | Keycode::Right => {
-                        rover
-                            .send_command(Command::CameraMoveHorizontal(
-                                HorizontalDirection::Neutral,
-                            ))
-                            .unwrap();
+                        send_command(
+                            rover,
+                            &operator,
+                            &mut recorder,
+                            Command::CameraMoveHorizontal(HorizontalDirection::Neutral),
+                        );
                     }
                     _ => {}
                 },
@@ -206,4 +437,310 @@ fn main() {
             }
         }
     }
+
+    drop(tiles);
+    for member in fleet.members() {
+        info!(
+            "session metrics of {}:\n{}",
+            member.name,
+            member.rover.metrics().snapshot()
+        );
+    }
+    if let Err(e) = fleet.shutdown() {
+        error!("failed to shut down cleanly: {e}");
+    }
+}
+
+fn title(fleet: &Fleet, active: usize) -> String {
+    let member = &fleet.members()[active];
+    let telemetry = member.rover.latest_telemetry();
+
+    let mut title = format!("Rover Revolution - {}", member.rover.device_info());
+    if fleet.len() > 1 {
+        title = format!("[{}/{}] {} - {title}", active + 1, fleet.len(), member.name);
+    }
+
+    if let Some(level) = telemetry.battery_level {
+        title += &format!(" - battery {level}%");
+    }
+    if telemetry.charging == Some(true) {
+        title += " (charging)";
+    }
+    if let Some(signal) = telemetry.signal_strength {
+        title += &format!(" - signal {signal}%");
+    }
+    if telemetry.is_low_battery() {
+        title = format!("LOW BATTERY - {title}");
+    }
+    if let Some(lease) = member.rover.control_lease() {
+        if lease.holder.name != OPERATOR {
+            title += &format!(" - controlled by {}", lease.holder);
+        }
+    }
+
+    title
+}
+
+/// Per rover view state.
+struct Tile<'a> {
+    decoder: Decoder,
+    /// One per video type, 640x480 and 320x240.
+    textures: [Texture<'a>; 2],
+    /// Index of the texture holding the latest frame.
+    shown: Option<usize>,
+    telemetry: Receiver<Telemetry>,
+    low_battery: bool,
+    settings: CameraSettings,
+    stealth: bool,
+    /// Whether the decoder has all it needs for the next video packet.
+    synced: bool,
+}
+
+impl<'a> Tile<'a> {
+    fn new(member: &FleetMember, texture_creator: &'a TextureCreator<WindowContext>) -> Self {
+        let settings = member.rover.camera_settings().unwrap_or_else(|e| {
+            warn!(
+                "failed to read camera settings of {}, assuming defaults: {e}",
+                member.name
+            );
+            CameraSettings::default()
+        });
+        info!("camera settings of {}: {settings:?}", member.name);
+
+        let texture = |width, height| {
+            texture_creator
+                .create_texture(
+                    PixelFormatEnum::YV12,
+                    TextureAccess::Streaming,
+                    width,
+                    height,
+                )
+                .unwrap()
+        };
+
+        Self {
+            decoder: Decoder::new().unwrap(),
+            textures: [texture(640, 480), texture(320, 240)],
+            shown: None,
+            telemetry: member.rover.telemetry(),
+            low_battery: false,
+            settings,
+            stealth: false,
+            synced: true,
+        }
+    }
+}
+
+/// Draws all tiles in a grid, framing the one receiving drive input if there are several.
+fn render(canvas: &mut WindowCanvas, tiles: &[Tile], active: usize) {
+    canvas.set_draw_color(Color::RGB(20, 20, 20));
+    canvas.clear();
+
+    let (width, height) = canvas.output_size().unwrap();
+    let columns = (tiles.len() as f64).sqrt().ceil() as u32;
+    let rows = (tiles.len() as u32).div_ceil(columns);
+    let (tile_width, tile_height) = (width / columns, height / rows);
+
+    for (i, tile) in tiles.iter().enumerate() {
+        let rect = Rect::new(
+            (i as u32 % columns * tile_width) as i32,
+            (i as u32 / columns * tile_height) as i32,
+            tile_width,
+            tile_height,
+        );
+
+        if let Some(shown) = tile.shown {
+            canvas.copy(&tile.textures[shown], None, rect).unwrap();
+        }
+
+        if tiles.len() > 1 && i == active {
+            canvas.set_draw_color(Color::RGB(255, 200, 0));
+            for inset in 0..3 {
+                let frame = Rect::new(
+                    rect.x() + inset,
+                    rect.y() + inset,
+                    rect.width() - 2 * inset as u32,
+                    rect.height() - 2 * inset as u32,
+                );
+                canvas.draw_rect(frame).unwrap();
+            }
+        }
+    }
+
+    canvas.present();
+}
+
+/// Connects to the rovers given as `ip:port` arguments, e.g. a relay forward. Without any, looks
+/// for rovers on the network and lets the user pick one, or all, if several answer. Falls back to
+/// the default address if none do, returns `None` if the picker is closed.
+fn choose_rovers() -> Option<Vec<(String, SocketAddr)>> {
+    let mut given = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.parse() {
+            Ok(address) => given.push((arg, address)),
+            Err(e) => {
+                error!("invalid rover address {arg:?}: {e}");
+                return None;
+            }
+        }
+    }
+    if !given.is_empty() {
+        return Some(given);
+    }
+
+    let rovers = discovery::discover(DISCOVERY_TIMEOUT).unwrap_or_else(|e| {
+        warn!("discovery failed: {e}");
+        Vec::new()
+    });
+
+    match rovers.as_slice() {
+        [] => {
+            info!("no rover answered the discovery probe, using {DEFAULT_ADDRESS}");
+            Some(vec![("rover".to_string(), DEFAULT_ADDRESS)])
+        }
+        [rover] => {
+            info!("found {rover}");
+            Some(vec![(rover.name.clone(), rover.address)])
+        }
+        rovers => pick_rovers(rovers),
+    }
+}
+
+fn pick_rovers(rovers: &[DiscoveredRover]) -> Option<Vec<(String, SocketAddr)>> {
+    let labels = rovers
+        .iter()
+        .map(|rover| format!("{} ({})", rover.name, rover.address.ip()))
+        .chain(["All".to_string()])
+        .collect::<Vec<_>>();
+    let buttons = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| ButtonData {
+            flags: MessageBoxButtonFlag::empty(),
+            button_id: i as i32,
+            text: label,
+        })
+        .collect::<Vec<_>>();
+    let message = rovers
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let chosen = match show_message_box(
+        MessageBoxFlag::INFORMATION,
+        &buttons,
+        "Choose a rover",
+        &message,
+        None,
+        None,
+    )
+    .unwrap()
+    {
+        ClickedButton::CustomButton(button) => match rovers.get(button.button_id as usize) {
+            Some(rover) => std::slice::from_ref(rover),
+            None => rovers,
+        },
+        ClickedButton::CloseButton => return None,
+    };
+
+    Some(
+        chosen
+            .iter()
+            .map(|rover| (rover.name.clone(), rover.address))
+            .collect(),
+    )
+}
+
+/// Maps the image setting keys to the command changing that setting, updating `settings`.
+fn adjust_setting(settings: &mut CameraSettings, keycode: Keycode) -> Option<Command> {
+    let command = match keycode {
+        Keycode::LeftBracket => {
+            settings.brightness = settings.brightness.saturating_sub(BRIGHTNESS_STEP);
+            Command::Brightness(settings.brightness)
+        }
+        Keycode::RightBracket => {
+            settings.brightness = settings.brightness.saturating_add(BRIGHTNESS_STEP);
+            Command::Brightness(settings.brightness)
+        }
+        Keycode::Minus => {
+            settings.contrast = settings.contrast.saturating_sub(1);
+            Command::Contrast(settings.contrast)
+        }
+        Keycode::Equals => {
+            settings.contrast = (settings.contrast + 1).min(MAX_CONTRAST);
+            Command::Contrast(settings.contrast)
+        }
+        Keycode::Comma => {
+            settings.frame_rate = settings
+                .frame_rate
+                .saturating_sub(FRAME_RATE_STEP)
+                .max(MIN_FRAME_RATE);
+            Command::FrameRate(settings.frame_rate)
+        }
+        Keycode::Period => {
+            settings.frame_rate = (settings.frame_rate + FRAME_RATE_STEP).min(MAX_FRAME_RATE);
+            Command::FrameRate(settings.frame_rate)
+        }
+        Keycode::V => {
+            settings.resolution = match settings.resolution {
+                Resolution::Vga => Resolution::Qvga,
+                Resolution::Qvga => Resolution::Vga,
+            };
+            Command::Resolution(settings.resolution)
+        }
+        Keycode::M => {
+            settings.orientation.mirror ^= true;
+            Command::Orientation(settings.orientation)
+        }
+        Keycode::F => {
+            settings.orientation.flip ^= true;
+            Command::Orientation(settings.orientation)
+        }
+        _ => return None,
+    };
+
+    Some(command)
+}
+
+/// Only records commands the rover accepted, e.g. not while someone else has control.
+fn send_command(
+    rover: &Rover,
+    operator: &Operator,
+    recorder: &mut Option<MissionRecorder>,
+    command: Command,
+) {
+    if let Err(e) = rover.send_command(operator, command) {
+        warn!("failed to send {command:?}: {e}");
+        return;
+    }
+
+    if let Some(recorder) = recorder {
+        recorder.record(command);
+    }
+}
+
+fn timestamp() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+}
+
+fn save_snapshot(image: &RgbImage) {
+    let path = format!("snapshot-{}.png", timestamp());
+
+    match image.save_png(&path) {
+        Ok(()) => info!("saved snapshot to {path}"),
+        Err(e) => error!("failed to save snapshot: {e}"),
+    }
+}
+
+fn save_mission(recorder: MissionRecorder) {
+    let path = format!("mission-{}.toml", timestamp());
+
+    match recorder.finish().save(&path) {
+        Ok(()) => info!("saved mission to {path}"),
+        Err(e) => error!("failed to save mission: {e}"),
+    }
 }
@@ -0,0 +1,356 @@
+//! Relay that makes a rover reachable over untrusted networks.
+//!
+//! The rover's own login only knows a fixed Blowfish key, so [`serve`] runs next to the rover and
+//! accepts TLS connections instead, authenticated by a token, a client certificate or both, and
+//! forwards each of them to the rover. [`forward`] runs on the remote side and tunnels plain local
+//! connections through the relay, so [`Rover::connect`](crate::rover::Rover::connect) on its
+//! address works like on the rover's own, with the command and media channels as two tunnels.
+//!
+//! After the TLS handshake the client sends a [`Hello`] as one JSON line and the relay answers with
+//! an `ok` line, or an error line before closing the connection.
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{info, warn};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConnection, Connection, RootCertStore, ServerConnection,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::server;
+
+/// Time a client has for the handshake and its hello.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LINE_LEN: usize = 4096;
+const BUFFER_SIZE: usize = 64 * 1024;
+const OK: &str = "ok";
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// PEM certificate chain of the relay, leaf first.
+    pub certificate: PathBuf,
+    /// PEM private key of the relay.
+    pub key: PathBuf,
+    /// PEM CA certificates that client certificates have to chain to, `None` to accept clients
+    /// without one.
+    pub client_ca: Option<PathBuf>,
+    /// Accepted tokens, empty to accept clients without one.
+    pub tokens: Vec<String>,
+    /// Where connections are forwarded to.
+    pub rover: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// `host:port` of the relay, the host has to match the relay's certificate.
+    pub relay: String,
+    /// PEM CA certificates that the relay's certificate has to chain to.
+    pub ca: PathBuf,
+    /// PEM certificate chain and private key, for relays that require client certificates.
+    pub identity: Option<(PathBuf, PathBuf)>,
+    pub token: Option<String>,
+}
+
+/// First line a client sends after the handshake.
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    token: Option<String>,
+}
+
+/// Accepts relay clients on `address` and forwards each to the rover. Refuses to start without
+/// any way to authenticate clients.
+pub fn serve(address: SocketAddr, config: ServerConfig) -> anyhow::Result<JoinHandle<()>> {
+    if config.client_ca.is_none() && config.tokens.is_empty() {
+        anyhow::bail!("refusing to relay without client certificates or tokens");
+    }
+
+    let builder = rustls::ServerConfig::builder();
+    let builder = match &config.client_ca {
+        Some(path) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder(Arc::new(load_roots(path)?)).build()?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    let tls = Arc::new(builder.with_single_cert(
+        load_certificates(&config.certificate)?,
+        load_key(&config.key)?,
+    )?);
+
+    info!("relaying to the rover at {}", config.rover);
+    server::listen(address, "relay", move |socket| {
+        handle_client(socket, &tls, &config)
+    })
+}
+
+fn handle_client(
+    socket: TcpStream,
+    tls: &Arc<rustls::ServerConfig>,
+    config: &ServerConfig,
+) -> anyhow::Result<()> {
+    let peer = socket.peer_addr()?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let mut connection = ServerConnection::new(tls.clone())?;
+    let mut transport = &socket;
+    let mut stream = rustls::Stream::new(&mut connection, &mut transport);
+    let hello = read_line(&mut stream)
+        .and_then(|line| Ok(serde_json::from_str::<Hello>(&line)?))
+        .inspect_err(|e| warn!("relay client {peer} failed the handshake: {e}"))?;
+
+    if !config.tokens.is_empty()
+        && !hello.token.as_ref().is_some_and(|token| {
+            config
+                .tokens
+                .iter()
+                .any(|accepted| constant_time_eq(accepted.as_bytes(), token.as_bytes()))
+        })
+    {
+        warn!("relay client {peer} sent an invalid token");
+        let _ = writeln!(stream, "invalid token");
+        anyhow::bail!("invalid token");
+    }
+
+    let rover = match TcpStream::connect(config.rover) {
+        Ok(rover) => rover,
+        Err(e) => {
+            let _ = writeln!(stream, "rover unreachable");
+            anyhow::bail!("failed to connect to the rover: {e}");
+        }
+    };
+    writeln!(stream, "{OK}")?;
+
+    info!("relaying {peer} to the rover");
+    socket.set_read_timeout(None)?;
+    let result = tunnel(connection, socket, rover);
+    info!("relay client {peer} disconnected");
+
+    result
+}
+
+/// Accepts plain connections on `address` and tunnels each through the relay. Returns the bound
+/// address, which is useful with port 0.
+pub fn forward(
+    address: SocketAddr,
+    config: ClientConfig,
+) -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let host = match config.relay.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => anyhow::bail!("expected the relay as host:port, got {:?}", config.relay),
+    };
+    let server_name = ServerName::try_from(host.to_string())?;
+
+    let builder = rustls::ClientConfig::builder().with_root_certificates(load_roots(&config.ca)?);
+    let tls = Arc::new(match &config.identity {
+        Some((certificate, key)) => {
+            builder.with_client_auth_cert(load_certificates(certificate)?, load_key(key)?)?
+        }
+        None => builder.with_no_client_auth(),
+    });
+
+    let listener = TcpListener::bind(address)
+        .map_err(|e| anyhow::anyhow!("failed to listen on {address}: {e}"))?;
+    let address = listener.local_addr()?;
+    info!("forwarding {address} through the relay at {}", config.relay);
+
+    let thread = server::accept(listener, "relay forward", move |local| {
+        let (connection, socket) = open(&config, &tls, &server_name)
+            .inspect_err(|e| warn!("failed to open a tunnel through the relay: {e}"))?;
+        tunnel(connection, socket, local)
+    })?;
+
+    Ok((address, thread))
+}
+
+/// Connects to the relay and waits for it to accept the hello.
+fn open(
+    config: &ClientConfig,
+    tls: &Arc<rustls::ClientConfig>,
+    server_name: &ServerName<'static>,
+) -> anyhow::Result<(ClientConnection, TcpStream)> {
+    let socket = TcpStream::connect(&config.relay)
+        .map_err(|e| anyhow::anyhow!("failed to connect to the relay: {e}"))?;
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+
+    let mut connection = ClientConnection::new(tls.clone(), server_name.clone())?;
+    let mut transport = &socket;
+    let mut stream = rustls::Stream::new(&mut connection, &mut transport);
+    let hello = Hello {
+        token: config.token.clone(),
+    };
+    writeln!(stream, "{}", serde_json::to_string(&hello)?)?;
+
+    let reply = read_line(&mut stream)?;
+    if reply != OK {
+        anyhow::bail!("relay refused the connection: {reply}");
+    }
+
+    socket.set_read_timeout(None)?;
+    Ok((connection, socket))
+}
+
+/// Copies between `connection` on `socket` and `plain` in both directions until either side
+/// closes.
+fn tunnel(
+    connection: impl Into<Connection>,
+    socket: TcpStream,
+    plain: TcpStream,
+) -> anyhow::Result<()> {
+    socket.set_nodelay(true)?;
+    plain.set_nodelay(true)?;
+    // either side may stay quiet for long, e.g. between heartbeats
+    socket.set_read_timeout(None)?;
+    plain.set_read_timeout(None)?;
+    let connection = Arc::new(Mutex::new(connection.into()));
+
+    let outbound = {
+        let connection = connection.clone();
+        let (mut socket, mut plain) = (socket.try_clone()?, plain.try_clone()?);
+        std::thread::spawn(move || -> anyhow::Result<()> {
+            let mut buf = vec![0; BUFFER_SIZE];
+            loop {
+                let n = plain.read(&mut buf)?;
+
+                let mut connection = connection.lock().unwrap();
+                if n == 0 {
+                    connection.send_close_notify();
+                } else {
+                    connection.writer().write_all(&buf[..n])?;
+                }
+                while connection.wants_write() {
+                    connection.write_tls(&mut socket)?;
+                }
+
+                if n == 0 {
+                    return Ok(());
+                }
+            }
+        })
+    };
+
+    let result = inbound(&connection, &socket, &plain);
+
+    // unblocks the outbound side, whose errors after that are of no interest
+    let _ = socket.shutdown(Shutdown::Both);
+    let _ = plain.shutdown(Shutdown::Both);
+    let _ = outbound.join();
+
+    result
+}
+
+fn inbound(
+    connection: &Mutex<Connection>,
+    mut socket: &TcpStream,
+    mut plain: &TcpStream,
+) -> anyhow::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE];
+    let mut received = Vec::new();
+    // the hello may have left plaintext behind
+    let mut closed = take_plaintext(&mut connection.lock().unwrap(), &mut received)?;
+
+    loop {
+        plain.write_all(&received)?;
+        received.clear();
+        if closed {
+            return Ok(());
+        }
+
+        // the other end shuts the socket down right after its close notify, which may get lost
+        let n = socket.read(&mut buf)?;
+        if n == 0 {
+            return Ok(());
+        }
+
+        let mut connection = connection.lock().unwrap();
+        let mut tls = &buf[..n];
+        while !tls.is_empty() {
+            connection.read_tls(&mut tls)?;
+            connection.process_new_packets()?;
+            closed |= take_plaintext(&mut connection, &mut received)?;
+        }
+        while connection.wants_write() {
+            connection.write_tls(&mut socket)?;
+        }
+    }
+}
+
+/// Appends the decrypted data to `out`, returns whether the peer closed the connection.
+fn take_plaintext(connection: &mut Connection, out: &mut Vec<u8>) -> anyhow::Result<bool> {
+    match connection.reader().read_to_end(out) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads up to a newline one byte at a time, leaving whatever follows to the tunnel.
+fn read_line(stream: &mut impl Read) -> anyhow::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+
+    while line.len() < MAX_LINE_LEN {
+        stream.read_exact(&mut byte)?;
+        if byte[0] == b'\n' {
+            return Ok(String::from_utf8(line)?);
+        }
+        line.push(byte[0]);
+    }
+
+    anyhow::bail!("line longer than {MAX_LINE_LEN} bytes")
+}
+
+/// Compares secrets without revealing through timing how much of them matched.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn load_certificates(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| anyhow::anyhow!("failed to load certificates from {path:?}: {e}"))?;
+    if certificates.is_empty() {
+        anyhow::bail!("no certificates in {path:?}");
+    }
+
+    Ok(certificates)
+}
+
+fn load_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|e| anyhow::anyhow!("failed to load private key from {path:?}: {e}"))
+}
+
+fn load_roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(path)? {
+        roots.add(certificate)?;
+    }
+
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_line() {
+        let mut input: &[u8] = b"{\"token\":\"abc\"}\nMO_O";
+        let hello: Hello = serde_json::from_str(&read_line(&mut input).unwrap()).unwrap();
+        assert_eq!(hello.token.as_deref(), Some("abc"));
+        assert_eq!(input, b"MO_O");
+
+        assert!(read_line(&mut &[b'x'; MAX_LINE_LEN + 1][..]).is_err());
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"abcd"));
+    }
+}
@@ -8,6 +8,8 @@ const STEP_TABLE: [usize; 89] = [
 
 const INDEX_ADJUST: [isize; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
 
+/// Decodes IMA ADPCM nibbles, high nibble first. Samples saturate at the i16 range and the step
+/// index stays within the table, as in the reference decoder, instead of overflowing.
 pub fn adpcm_to_pcm(bytes: &[u8], mut pre_sample: i16, mut index: u8) -> Vec<i16> {
     let mut decoded = Vec::<i16>::new();
 
@@ -25,14 +27,33 @@ pub fn adpcm_to_pcm(bytes: &[u8], mut pre_sample: i16, mut index: u8) -> Vec<i16
             delta = -delta;
         }
 
-        pre_sample += delta as i16;
-        pre_sample = pre_sample.max(-32768).min(32767);
+        pre_sample = (pre_sample as i32 + delta).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
 
         decoded.push(pre_sample);
 
-        index = (index as isize + INDEX_ADJUST[code as usize]) as u8;
-        index = index.max(0).min(88);
+        index = (index as isize + INDEX_ADJUST[code as usize]).clamp(0, 88) as u8;
     }
 
     decoded
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        // the largest positive step over and over again
+        let decoded = adpcm_to_pcm(&[0x77; 32], 30_000, 88);
+        assert!(decoded.iter().all(|&sample| sample == i16::MAX));
+
+        let decoded = adpcm_to_pcm(&[0xFF; 32], -30_000, 88);
+        assert!(decoded.iter().all(|&sample| sample == i16::MIN));
+    }
+
+    #[test]
+    fn step_index_does_not_wrap_below_zero() {
+        // small codes lower the index, which must stop at the smallest step
+        assert_eq!(adpcm_to_pcm(&[0x00; 4], 0, 0), [0; 8]);
+    }
+}
@@ -0,0 +1,337 @@
+//! Control arbitration between operators sharing one rover session.
+//!
+//! One operator at a time holds the control lease, everyone else is view-only. An operator takes
+//! the lease with its first command while nobody holds it and renews it with every further one,
+//! the lease lapses after [`LEASE_TIMEOUT`] without commands. Others may ask for a handover, which
+//! happens as soon as the holder releases the lease, and admins may take it over at any time.
+
+use std::{
+    fmt::Display,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+/// Log target of the audit log: every command with the operator who sent it, and every change of
+/// the control lease.
+pub const AUDIT_TARGET: &str = "audit";
+
+/// Default time without commands after which a lease lapses.
+pub const LEASE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Someone controlling the rover, e.g. the viewer, a script or a browser. Remote front ends
+/// prefix the names they hand out with their own, like `api:ci` or `web:10.0.0.2:51234`, so a
+/// client of one cannot pose as an operator of another.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Operator {
+    pub name: String,
+    /// Admins may take the lease over from its holder.
+    pub admin: bool,
+}
+
+impl Operator {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            admin: false,
+        }
+    }
+
+    pub fn admin(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            admin: true,
+        }
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// The current holder of the control lease.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub holder: Operator,
+    pub expires_in: Duration,
+    /// Who asked the holder to hand over, if anyone.
+    pub handover_requested_by: Option<Operator>,
+}
+
+/// The error for operators trying to control the rover while someone else holds the lease.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewOnly {
+    pub holder: Operator,
+}
+
+impl Display for ViewOnly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "view only, control is held by {}", self.holder)
+    }
+}
+
+impl std::error::Error for ViewOnly {}
+
+/// How an operator came to hold the lease.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Grant {
+    /// It already held it.
+    Renewed,
+    /// Control changed hands, whatever the previous holder started has to stop.
+    Taken,
+}
+
+pub(crate) struct Arbiter {
+    state: Mutex<State>,
+}
+
+struct State {
+    holder: Option<Holder>,
+    handover: Option<Operator>,
+    timeout: Duration,
+}
+
+struct Holder {
+    operator: Operator,
+    expires: Instant,
+}
+
+impl State {
+    /// The unexpired holder.
+    fn holder(&mut self) -> Option<&mut Holder> {
+        if self
+            .holder
+            .as_ref()
+            .is_some_and(|holder| holder.expires <= Instant::now())
+        {
+            let holder = self.holder.take().unwrap();
+            info!(target: AUDIT_TARGET, "control lease of {} lapsed", holder.operator);
+        }
+
+        self.holder.as_mut()
+    }
+
+    fn acquire(&mut self, operator: &Operator) -> Result<Grant, ViewOnly> {
+        let timeout = self.timeout;
+
+        match self.holder() {
+            Some(holder) if holder.operator == *operator => {
+                holder.expires = Instant::now() + timeout;
+                Ok(Grant::Renewed)
+            }
+            Some(holder) => Err(ViewOnly {
+                holder: holder.operator.clone(),
+            }),
+            None => {
+                info!(target: AUDIT_TARGET, "{operator} took the control lease");
+                self.grant(operator);
+                Ok(Grant::Taken)
+            }
+        }
+    }
+
+    fn grant(&mut self, operator: &Operator) {
+        if self.handover.as_ref() == Some(operator) {
+            self.handover = None;
+        }
+        self.holder = Some(Holder {
+            operator: operator.clone(),
+            expires: Instant::now() + self.timeout,
+        });
+    }
+}
+
+impl Arbiter {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                holder: None,
+                handover: None,
+                timeout: LEASE_TIMEOUT,
+            }),
+        }
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.state.lock().unwrap().timeout = timeout;
+    }
+
+    /// Renews the lease of `operator`, or takes it if nobody holds it.
+    pub fn acquire(&self, operator: &Operator) -> Result<Grant, ViewOnly> {
+        self.state.lock().unwrap().acquire(operator)
+    }
+
+    /// Like [`acquire`](Self::acquire), but if someone else holds the lease `operator` is
+    /// remembered to get it once the holder releases it, replacing any earlier request.
+    pub fn request_handover(&self, operator: &Operator) -> Result<Grant, ViewOnly> {
+        let mut state = self.state.lock().unwrap();
+        let result = state.acquire(operator);
+
+        if let Err(ViewOnly { holder }) = &result {
+            info!(target: AUDIT_TARGET, "{operator} asked {holder} to hand over control");
+            state.handover = Some(operator.clone());
+        }
+
+        result
+    }
+
+    /// Takes the lease from whoever holds it, for admins only.
+    pub fn take_over(&self, operator: &Operator) -> anyhow::Result<Grant> {
+        if !operator.admin {
+            anyhow::bail!("{operator} is not an admin and cannot take control over");
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let renewed = match state.holder() {
+            Some(holder) if holder.operator == *operator => true,
+            Some(holder) => {
+                info!(
+                    target: AUDIT_TARGET,
+                    "{operator} took the control lease over from {}", holder.operator
+                );
+                false
+            }
+            None => {
+                info!(target: AUDIT_TARGET, "{operator} took the control lease");
+                false
+            }
+        };
+        state.grant(operator);
+
+        Ok(if renewed {
+            Grant::Renewed
+        } else {
+            Grant::Taken
+        })
+    }
+
+    /// Gives up the lease of `operator`, passing it on to whoever asked for a handover. Returns
+    /// whether `operator` held it. Also withdraws a handover request by `operator`.
+    pub fn release(&self, operator: &Operator) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        if state.handover.as_ref() == Some(operator) {
+            state.handover = None;
+        }
+        if !state
+            .holder()
+            .is_some_and(|holder| holder.operator == *operator)
+        {
+            return false;
+        }
+
+        info!(target: AUDIT_TARGET, "{operator} released the control lease");
+        state.holder = None;
+        if let Some(next) = state.handover.take() {
+            info!(target: AUDIT_TARGET, "control lease handed over to {next}");
+            state.grant(&next);
+        }
+
+        true
+    }
+
+    /// Whether `operator` holds the lease or could take it right away.
+    pub fn may_control(&self, operator: &Operator) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .holder()
+            .is_none_or(|holder| holder.operator == *operator)
+    }
+
+    /// Extends the lease of `operator` if it holds it.
+    pub fn renew(&self, operator: &Operator) {
+        let mut state = self.state.lock().unwrap();
+        let timeout = state.timeout;
+
+        if let Some(holder) = state.holder() {
+            if holder.operator == *operator {
+                holder.expires = Instant::now() + timeout;
+            }
+        }
+    }
+
+    pub fn lease(&self) -> Option<Lease> {
+        let mut state = self.state.lock().unwrap();
+        let handover_requested_by = state.handover.clone();
+
+        state.holder().map(|holder| Lease {
+            holder: holder.operator.clone(),
+            expires_in: holder.expires.saturating_duration_since(Instant::now()),
+            handover_requested_by,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_operator_gets_control() {
+        let arbiter = Arbiter::new();
+        let (script, browser) = (Operator::new("script"), Operator::new("browser"));
+
+        assert_eq!(arbiter.acquire(&script), Ok(Grant::Taken));
+        assert_eq!(arbiter.acquire(&script), Ok(Grant::Renewed));
+        assert_eq!(
+            arbiter.acquire(&browser),
+            Err(ViewOnly {
+                holder: script.clone()
+            })
+        );
+        assert!(!arbiter.may_control(&browser));
+
+        assert!(!arbiter.release(&browser));
+        assert!(arbiter.release(&script));
+        assert_eq!(arbiter.acquire(&browser), Ok(Grant::Taken));
+    }
+
+    #[test]
+    fn lease_lapses() {
+        let arbiter = Arbiter::new();
+        arbiter.set_timeout(Duration::ZERO);
+        let (script, browser) = (Operator::new("script"), Operator::new("browser"));
+
+        assert_eq!(arbiter.acquire(&script), Ok(Grant::Taken));
+        assert_eq!(arbiter.lease(), None);
+        assert_eq!(arbiter.acquire(&browser), Ok(Grant::Taken));
+    }
+
+    #[test]
+    fn handover_on_release() {
+        let arbiter = Arbiter::new();
+        let (script, browser) = (Operator::new("script"), Operator::new("browser"));
+
+        arbiter.acquire(&script).unwrap();
+        assert!(arbiter.request_handover(&browser).is_err());
+        assert_eq!(
+            arbiter.lease().unwrap().handover_requested_by,
+            Some(browser.clone())
+        );
+
+        arbiter.release(&script);
+        let lease = arbiter.lease().unwrap();
+        assert_eq!(lease.holder, browser);
+        assert_eq!(lease.handover_requested_by, None);
+    }
+
+    #[test]
+    fn admin_override() {
+        let arbiter = Arbiter::new();
+        let (script, viewer) = (Operator::new("script"), Operator::admin("viewer"));
+
+        arbiter.acquire(&viewer).unwrap();
+        assert!(arbiter.take_over(&script).is_err());
+        assert_eq!(arbiter.lease().unwrap().holder, viewer);
+
+        arbiter.release(&viewer);
+        arbiter.acquire(&script).unwrap();
+        assert_eq!(arbiter.take_over(&viewer).unwrap(), Grant::Taken);
+        assert_eq!(arbiter.take_over(&viewer).unwrap(), Grant::Renewed);
+        assert_eq!(arbiter.lease().unwrap().holder, viewer);
+    }
+}
@@ -1,20 +1,25 @@
-use super::request::Request;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use super::request::{opcode, Request, COMMAND_CHANNEL};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Direction {
     Forward,
     Neutral,
     Backward,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum HorizontalDirection {
     Left,
     Neutral,
     Right,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Speed {
     Slow,
     Fast,
@@ -27,9 +32,18 @@ impl Speed {
             Speed::Fast => 0,
         }
     }
+
+    fn decode(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            1 => Ok(Speed::Slow),
+            0 => Ok(Speed::Fast),
+            _ => anyhow::bail!("unknown speed {byte}"),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Camera {
     Driving,
     Turret,
@@ -42,16 +56,96 @@ impl Camera {
             Camera::Turret => 1,
         }
     }
+
+    fn decode(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            2 => Ok(Camera::Driving),
+            1 => Ok(Camera::Turret),
+            _ => anyhow::bail!("unknown camera {byte}"),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum VerticalDirection {
     Up,
     Neutral,
     Down,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// 320x240
+    Qvga,
+    /// 640x480
+    Vga,
+}
+
+impl Resolution {
+    pub(crate) fn encode(self) -> u8 {
+        match self {
+            Resolution::Qvga => 8,
+            Resolution::Vga => 32,
+        }
+    }
+
+    pub(crate) fn decode(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            8 => Ok(Resolution::Qvga),
+            32 => Ok(Resolution::Vga),
+            _ => anyhow::bail!("unknown resolution {byte}"),
+        }
+    }
+
+    pub fn size(self) -> (u32, u32) {
+        match self {
+            Resolution::Qvga => (320, 240),
+            Resolution::Vga => (640, 480),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Orientation {
+    /// Upside down
+    pub flip: bool,
+    /// Left and right swapped
+    pub mirror: bool,
+}
+
+impl Orientation {
+    pub(crate) fn encode(self) -> u8 {
+        self.flip as u8 | (self.mirror as u8) << 1
+    }
+
+    pub(crate) fn decode(byte: u8) -> anyhow::Result<Self> {
+        if byte > 3 {
+            anyhow::bail!("unknown orientation {byte}");
+        }
+
+        Ok(Orientation {
+            flip: byte & 1 != 0,
+            mirror: byte & 2 != 0,
+        })
+    }
+}
+
+/// Highest value accepted by [`Command::Contrast`].
+pub const MAX_CONTRAST: u8 = 6;
+/// Highest value accepted by [`Command::FrameRate`].
+pub const MAX_FRAME_RATE: u8 = 30;
+
+/// Parameters of the camera control request that take a value.
+const PARAM_RESOLUTION: u8 = 0;
+const PARAM_BRIGHTNESS: u8 = 1;
+const PARAM_CONTRAST: u8 = 2;
+const PARAM_ORIENTATION: u8 = 5;
+const PARAM_FRAME_RATE: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Command {
     Drive(Direction, HorizontalDirection, Speed),
     SteerStop(Speed),
@@ -59,9 +153,61 @@ pub enum Command {
     CameraMoveHorizontal(HorizontalDirection),
     CameraMoveVertical(VerticalDirection),
     StealthMode(bool),
+    Brightness(u8),
+    /// Contrast from 0 to [`MAX_CONTRAST`]
+    Contrast(u8),
+    Resolution(Resolution),
+    /// Frames per second from 1 to [`MAX_FRAME_RATE`]
+    FrameRate(u8),
+    Orientation(Orientation),
+}
+
+/// The part of the rover a [`Command`] controls. A command supersedes earlier commands of the
+/// same kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandKind {
+    Drive,
+    Camera,
+    TurretHorizontal,
+    TurretVertical,
+    Stealth,
+    Brightness,
+    Contrast,
+    Resolution,
+    FrameRate,
+    Orientation,
 }
 
 impl Command {
+    pub fn kind(self) -> CommandKind {
+        match self {
+            Command::Drive(..) | Command::SteerStop(_) => CommandKind::Drive,
+            Command::UseCamera(_) => CommandKind::Camera,
+            Command::CameraMoveHorizontal(_) => CommandKind::TurretHorizontal,
+            Command::CameraMoveVertical(_) => CommandKind::TurretVertical,
+            Command::StealthMode(_) => CommandKind::Stealth,
+            Command::Brightness(_) => CommandKind::Brightness,
+            Command::Contrast(_) => CommandKind::Contrast,
+            Command::Resolution(_) => CommandKind::Resolution,
+            Command::FrameRate(_) => CommandKind::FrameRate,
+            Command::Orientation(_) => CommandKind::Orientation,
+        }
+    }
+
+    /// Checks that the value of a camera parameter is in the range the rover accepts.
+    pub fn validate(self) -> anyhow::Result<()> {
+        match self {
+            Command::Contrast(contrast) if contrast > MAX_CONTRAST => {
+                anyhow::bail!("contrast {contrast} is out of range 0..={MAX_CONTRAST}")
+            }
+            Command::FrameRate(fps) if !(1..=MAX_FRAME_RATE).contains(&fps) => {
+                anyhow::bail!("frame rate {fps} is out of range 1..={MAX_FRAME_RATE}")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Encodes the command as is, see [`Command::validate`] for the values the rover accepts.
     pub fn to_request(self) -> Request {
         match self {
             Command::Drive(dir, steer, speed) => {
@@ -86,7 +232,9 @@ impl Command {
                 Request::from_device_control(cmd, speed.encode())
             }
             Command::SteerStop(speed) => Request::from_device_control(3, speed.encode()),
-            Command::UseCamera(camera) => Request::from_command_byte(19, [6, camera.encode()]),
+            Command::UseCamera(camera) => {
+                Request::from_command_byte(opcode::CAMERA_SELECT, [6, camera.encode()])
+            }
             Command::CameraMoveHorizontal(steer) => Request::from_camera_request(match steer {
                 HorizontalDirection::Left => 4,
                 HorizontalDirection::Neutral => 5,
@@ -100,6 +248,17 @@ impl Command {
             Command::StealthMode(enable) => {
                 Request::from_camera_request(if enable { 94 } else { 95 })
             }
+            Command::Brightness(brightness) => {
+                Request::from_camera_parameter(PARAM_BRIGHTNESS, brightness)
+            }
+            Command::Contrast(contrast) => Request::from_camera_parameter(PARAM_CONTRAST, contrast),
+            Command::Resolution(resolution) => {
+                Request::from_camera_parameter(PARAM_RESOLUTION, resolution.encode())
+            }
+            Command::FrameRate(fps) => Request::from_camera_parameter(PARAM_FRAME_RATE, fps),
+            Command::Orientation(orientation) => {
+                Request::from_camera_parameter(PARAM_ORIENTATION, orientation.encode())
+            }
         }
     }
 }
@@ -113,3 +272,141 @@ fn send_command_int_request(sock: &mut TcpStream, id: u8, ints: &[u32]) -> anyho
     send_command_request(sock, id, (4 * ints.len()) as u8, bytevals.as_slice())
 }
 */
+
+impl TryFrom<&Request> for Command {
+    type Error = anyhow::Error;
+
+    fn try_from(request: &Request) -> anyhow::Result<Self> {
+        if request.c != COMMAND_CHANNEL {
+            anyhow::bail!("not a command channel request: {:#04X}", request.c);
+        }
+
+        match (request.id, request.bytes.as_slice()) {
+            (opcode::DEVICE_CONTROL, &[cmd, speed]) => {
+                let speed = Speed::decode(speed)?;
+                let (dir, steer) = match cmd {
+                    3 => return Ok(Command::SteerStop(speed)),
+                    6 => (Direction::Forward, HorizontalDirection::Left),
+                    1 => (Direction::Forward, HorizontalDirection::Neutral),
+                    7 => (Direction::Forward, HorizontalDirection::Right),
+                    5 => (Direction::Neutral, HorizontalDirection::Left),
+                    0 => (Direction::Neutral, HorizontalDirection::Neutral),
+                    4 => (Direction::Neutral, HorizontalDirection::Right),
+                    8 => (Direction::Backward, HorizontalDirection::Left),
+                    2 => (Direction::Backward, HorizontalDirection::Neutral),
+                    9 => (Direction::Backward, HorizontalDirection::Right),
+                    _ => anyhow::bail!("unknown device control command {cmd}"),
+                };
+
+                Ok(Command::Drive(dir, steer, speed))
+            }
+            (opcode::CAMERA_SELECT, &[6, camera]) => {
+                Ok(Command::UseCamera(Camera::decode(camera)?))
+            }
+            (opcode::CAMERA_CONTROL, &[request]) => match request {
+                4 => Ok(Command::CameraMoveHorizontal(HorizontalDirection::Left)),
+                5 => Ok(Command::CameraMoveHorizontal(HorizontalDirection::Neutral)),
+                6 => Ok(Command::CameraMoveHorizontal(HorizontalDirection::Right)),
+                0 => Ok(Command::CameraMoveVertical(VerticalDirection::Up)),
+                1 => Ok(Command::CameraMoveVertical(VerticalDirection::Neutral)),
+                2 => Ok(Command::CameraMoveVertical(VerticalDirection::Down)),
+                94 => Ok(Command::StealthMode(true)),
+                95 => Ok(Command::StealthMode(false)),
+                _ => anyhow::bail!("unknown camera request {request}"),
+            },
+            (opcode::CAMERA_CONTROL, &[param, value]) => match param {
+                PARAM_BRIGHTNESS => Ok(Command::Brightness(value)),
+                PARAM_CONTRAST => Ok(Command::Contrast(value)),
+                PARAM_RESOLUTION => Ok(Command::Resolution(Resolution::decode(value)?)),
+                PARAM_FRAME_RATE => Ok(Command::FrameRate(value)),
+                PARAM_ORIENTATION => Ok(Command::Orientation(Orientation::decode(value)?)),
+                _ => anyhow::bail!("unknown camera parameter {param}"),
+            },
+            (id, bytes) => anyhow::bail!("request {id} with payload {bytes:02X?} is not a command"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_commands() -> Vec<Command> {
+        let directions = [Direction::Forward, Direction::Neutral, Direction::Backward];
+        let steers = [
+            HorizontalDirection::Left,
+            HorizontalDirection::Neutral,
+            HorizontalDirection::Right,
+        ];
+        let speeds = [Speed::Slow, Speed::Fast];
+
+        let mut commands = Vec::new();
+        for dir in directions {
+            for steer in steers {
+                for speed in speeds {
+                    commands.push(Command::Drive(dir, steer, speed));
+                }
+            }
+        }
+        commands.extend(speeds.map(Command::SteerStop));
+        commands.extend([Camera::Driving, Camera::Turret].map(Command::UseCamera));
+        commands.extend(steers.map(Command::CameraMoveHorizontal));
+        commands.extend(
+            [
+                VerticalDirection::Up,
+                VerticalDirection::Neutral,
+                VerticalDirection::Down,
+            ]
+            .map(Command::CameraMoveVertical),
+        );
+        commands.extend([true, false].map(Command::StealthMode));
+        commands.extend([0, 128, 255].map(Command::Brightness));
+        commands.extend((0..=MAX_CONTRAST).map(Command::Contrast));
+        commands.extend([Resolution::Qvga, Resolution::Vga].map(Command::Resolution));
+        commands.extend([1, 15, MAX_FRAME_RATE].map(Command::FrameRate));
+        for flip in [false, true] {
+            for mirror in [false, true] {
+                commands.push(Command::Orientation(Orientation { flip, mirror }));
+            }
+        }
+
+        commands
+    }
+
+    #[test]
+    fn round_trip() {
+        for command in all_commands() {
+            let bytes = command.to_request().to_bytes();
+            let request = Request::from_bytes(&bytes).unwrap();
+
+            assert_eq!(Command::try_from(&request).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        for command in all_commands() {
+            command.validate().unwrap();
+        }
+        for command in [
+            Command::Contrast(MAX_CONTRAST + 1),
+            Command::FrameRate(0),
+            Command::FrameRate(MAX_FRAME_RATE + 1),
+        ] {
+            assert!(command.validate().is_err());
+
+            let request = Request::from_bytes(&command.to_request().to_bytes()).unwrap();
+            assert_eq!(Command::try_from(&request).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn rejects_non_commands() {
+        assert!(Command::try_from(&Request::heartbeat()).is_err());
+        assert!(Command::try_from(&Request::from_device_control(10, 0)).is_err());
+        assert!(Command::try_from(&Request::from_device_control(1, 2)).is_err());
+        assert!(Command::try_from(&Request::from_camera_request(3)).is_err());
+        assert!(Command::try_from(&Request::from_camera_parameter(3, 50)).is_err());
+        assert!(Command::try_from(&Request::from_camera_parameter(PARAM_RESOLUTION, 1)).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt::Display;
+
+/// Length of the reply to the login request, including its header.
+pub(crate) const LOGIN_REPLY_LEN: usize = 82;
+const CAMERA_ID_LEN: usize = 12;
+
+/// Firmware version as four dotted components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareVersion(pub [u8; 4]);
+
+impl Display for FirmwareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{a}.{b}.{c}.{d}")
+    }
+}
+
+/// Identity of a rover as reported in the login handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    /// Camera id without its NUL padding, for display.
+    pub camera_id: String,
+    /// Camera id as sent, padding included.
+    raw_camera_id: [u8; CAMERA_ID_LEN],
+    /// Result code of the login request, 0 on success.
+    pub login_result: u16,
+    pub system_version: FirmwareVersion,
+    pub app_version: FirmwareVersion,
+}
+
+impl Display for DeviceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (system {}, app {})",
+            self.camera_id, self.system_version, self.app_version
+        )
+    }
+}
+
+impl DeviceInfo {
+    /// Decodes the complete login reply, header included.
+    pub fn from_login_reply(reply: &[u8]) -> anyhow::Result<Self> {
+        if reply.len() != LOGIN_REPLY_LEN {
+            anyhow::bail!(
+                "login reply has {} bytes, expected {LOGIN_REPLY_LEN}",
+                reply.len()
+            );
+        }
+
+        let version =
+            |offset: usize| FirmwareVersion(reply[offset..offset + 4].try_into().unwrap());
+
+        let raw_camera_id: [u8; CAMERA_ID_LEN] = reply[25..37].try_into().unwrap();
+
+        Ok(Self {
+            camera_id: c_string(&raw_camera_id),
+            raw_camera_id,
+            login_result: u16::from_le_bytes([reply[23], reply[24]]),
+            system_version: version(46),
+            app_version: version(50),
+        })
+    }
+
+    /// Blowfish key for the login challenge, derived from the raw camera id like the official
+    /// app does, so a padded id keeps its NUL bytes.
+    pub(crate) fn login_key(&self, user: &str, password: &str) -> Vec<u8> {
+        [
+            format!("{user}:").as_bytes(),
+            &self.raw_camera_id,
+            format!("-save-private:{password}").as_bytes(),
+        ]
+        .concat()
+    }
+}
+
+/// Decodes a NUL padded string.
+pub(crate) fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_reply_with_padded_camera_id() {
+        let mut reply = vec![0; LOGIN_REPLY_LEN];
+        reply[25..35].copy_from_slice(b"AC13ROVER1");
+        reply[46..50].copy_from_slice(&[1, 2, 3, 4]);
+        reply[50..54].copy_from_slice(&[5, 6, 7, 8]);
+
+        let info = DeviceInfo::from_login_reply(&reply).unwrap();
+        assert_eq!(info.camera_id, "AC13ROVER1");
+        assert_eq!(info.login_result, 0);
+        assert_eq!(info.system_version.to_string(), "1.2.3.4");
+        assert_eq!(info.app_version.to_string(), "5.6.7.8");
+        assert_eq!(
+            info.login_key("AC13", "AC13"),
+            b"AC13:AC13ROVER1\0\0-save-private:AC13"
+        );
+
+        assert!(DeviceInfo::from_login_reply(&reply[1..]).is_err());
+    }
+}
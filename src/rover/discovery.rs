@@ -0,0 +1,212 @@
+//! Finding rovers on the local network with the UDP search probe of the AC13 camera family.
+
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use super::{
+    device::{c_string, FirmwareVersion},
+    request::{opcode, Request, DISCOVERY_CHANNEL},
+};
+
+pub const DISCOVERY_PORT: u16 = 10000;
+
+/// Length of the search reply payload.
+const REPLY_LEN: usize = 69;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredRover {
+    /// Address to pass to [`Rover::connect`](super::Rover::connect).
+    pub address: SocketAddr,
+    pub camera_id: String,
+    pub name: String,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub dns: Ipv4Addr,
+    pub system_version: FirmwareVersion,
+    pub app_version: FirmwareVersion,
+    pub dhcp: bool,
+}
+
+impl Display for DiscoveredRover {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({}) at {}, firmware {}",
+            self.name, self.camera_id, self.address, self.app_version
+        )
+    }
+}
+
+impl DiscoveredRover {
+    /// Parses a search reply received from `source`.
+    pub fn from_reply(reply: &Request, source: SocketAddr) -> anyhow::Result<Self> {
+        if reply.c != DISCOVERY_CHANNEL || reply.id != opcode::SEARCH_REPLY {
+            anyhow::bail!("not a search reply: {reply:?}");
+        }
+
+        let bytes = reply.bytes.as_slice();
+        if bytes.len() < REPLY_LEN {
+            anyhow::bail!("search reply too short: {} bytes", bytes.len());
+        }
+
+        let ip = |offset: usize| {
+            Ipv4Addr::new(
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            )
+        };
+        let version =
+            |offset: usize| FirmwareVersion(bytes[offset..offset + 4].try_into().unwrap());
+
+        // the reply carries the configured address, which is unset while using DHCP
+        let address = match ip(34) {
+            ip if ip.is_unspecified() => source.ip(),
+            ip => ip.into(),
+        };
+        let port = u16::from_be_bytes([bytes[66], bytes[67]]);
+
+        Ok(Self {
+            address: SocketAddr::new(address, port),
+            camera_id: c_string(&bytes[0..13]),
+            name: c_string(&bytes[13..34]),
+            netmask: ip(38),
+            gateway: ip(42),
+            dns: ip(46),
+            system_version: version(54),
+            app_version: version(58),
+            dhcp: bytes[68] != 0,
+        })
+    }
+}
+
+/// Broadcasts the search probe and collects all replies arriving within `timeout`.
+pub fn discover(timeout: Duration) -> anyhow::Result<Vec<DiscoveredRover>> {
+    discover_at(
+        SocketAddr::from((Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+        timeout,
+    )
+}
+
+/// Sends the search probe to `target`, which may be a broadcast or a unicast address.
+pub fn discover_at(target: SocketAddr, timeout: Duration) -> anyhow::Result<Vec<DiscoveredRover>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+
+    let probe = Request {
+        c: DISCOVERY_CHANNEL,
+        id: opcode::SEARCH_REQUEST,
+        n: 4,
+        bytes: vec![0; 4],
+    };
+    socket
+        .send_to(&probe.to_bytes(), target)
+        .map_err(|e| anyhow::anyhow!("failed to send discovery probe to {target}: {e}"))?;
+
+    let end = Instant::now() + timeout;
+    let mut rovers = Vec::<DiscoveredRover>::new();
+    let mut buf = [0; 1024];
+
+    while let Some(remaining) = end.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (len, source) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let rover = Request::from_bytes(&buf[..len])
+            .and_then(|reply| DiscoveredRover::from_reply(&reply, source));
+
+        match rover {
+            Ok(rover) if rovers.iter().any(|r| r.address == rover.address) => {}
+            Ok(rover) => {
+                debug!("discovered {rover}");
+                rovers.push(rover);
+            }
+            Err(e) => debug!("ignoring reply from {source}: {e}"),
+        }
+    }
+
+    Ok(rovers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(camera_id: &str, name: &str, ip: [u8; 4], port: u16) -> Vec<u8> {
+        let mut bytes = vec![0; REPLY_LEN];
+        bytes[..camera_id.len()].copy_from_slice(camera_id.as_bytes());
+        bytes[13..13 + name.len()].copy_from_slice(name.as_bytes());
+        bytes[34..38].copy_from_slice(&ip);
+        bytes[38..42].copy_from_slice(&[255, 255, 255, 0]);
+        bytes[54..58].copy_from_slice(&[1, 2, 3, 4]);
+        bytes[58..62].copy_from_slice(&[5, 6, 7, 8]);
+        bytes[66..68].copy_from_slice(&port.to_be_bytes());
+
+        Request {
+            c: DISCOVERY_CHANNEL,
+            id: opcode::SEARCH_REPLY,
+            n: REPLY_LEN as u32,
+            bytes,
+        }
+        .to_bytes()
+    }
+
+    #[test]
+    fn discovers_local_responder() {
+        let responder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = responder.local_addr().unwrap();
+
+        let thread = std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            let (len, source) = responder.recv_from(&mut buf).unwrap();
+
+            let probe = Request::from_bytes(&buf[..len]).unwrap();
+            assert_eq!(
+                (probe.c, probe.id),
+                (DISCOVERY_CHANNEL, opcode::SEARCH_REQUEST)
+            );
+
+            for reply in [
+                reply("AC13ROVER001", "lab rover", [0, 0, 0, 0], 80),
+                reply("AC13ROVER002", "arena rover", [127, 0, 0, 2], 8080),
+                b"garbage".to_vec(),
+            ] {
+                responder.send_to(&reply, source).unwrap();
+            }
+        });
+
+        let rovers = discover_at(target, Duration::from_millis(500)).unwrap();
+        thread.join().unwrap();
+
+        assert_eq!(rovers.len(), 2);
+
+        assert_eq!(rovers[0].camera_id, "AC13ROVER001");
+        assert_eq!(rovers[0].name, "lab rover");
+        assert_eq!(rovers[0].address, "127.0.0.1:80".parse().unwrap());
+        assert_eq!(rovers[0].system_version.to_string(), "1.2.3.4");
+        assert_eq!(rovers[0].app_version.to_string(), "5.6.7.8");
+
+        assert_eq!(rovers[1].address, "127.0.0.2:8080".parse().unwrap());
+        assert_eq!(rovers[1].netmask, Ipv4Addr::new(255, 255, 255, 0));
+    }
+}
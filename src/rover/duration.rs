@@ -0,0 +1,67 @@
+//! Human readable durations like `2s`, `500ms`, `1.5s`, `1m` or `24h`, as used by the CLI and
+//! mission scripts. The [`serialize`] and [`deserialize`] functions allow
+//! `#[serde(with = "duration")]`.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer, Serializer};
+
+pub fn parse(s: &str) -> anyhow::Result<Duration> {
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .ok_or_else(|| anyhow::anyhow!("missing unit in duration {s:?}"))?;
+    let (value, unit) = s.split_at(split);
+    let value = value
+        .parse::<f64>()
+        .map_err(|e| anyhow::anyhow!("invalid duration {s:?}: {e}"))?;
+
+    let seconds = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => anyhow::bail!("unknown unit {unit:?} in duration {s:?}"),
+    };
+
+    Duration::try_from_secs_f64(seconds).map_err(|e| anyhow::anyhow!("invalid duration {s:?}: {e}"))
+}
+
+pub fn format(duration: Duration) -> String {
+    format!("{}ms", duration.as_millis())
+}
+
+pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(*duration))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse(&s).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_units() {
+        assert_eq!(parse("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse("1.5s").unwrap(), Duration::from_millis(1500));
+        assert_eq!(parse("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse("24h").unwrap(), Duration::from_secs(86_400));
+        assert_eq!(
+            parse(&format(Duration::from_millis(1234))).unwrap(),
+            Duration::from_millis(1234)
+        );
+
+        assert!(parse("5").is_err());
+        assert!(parse("5d").is_err());
+        assert!(parse("..s").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!(parse("99999999999999999999h").is_err());
+        assert!(parse(&format!("{}s", u64::MAX as f64 * 2.0)).is_err());
+    }
+}
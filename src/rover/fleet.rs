@@ -0,0 +1,183 @@
+use std::{
+    net::SocketAddr,
+    sync::mpsc::{Receiver, Sender},
+    thread::JoinHandle,
+};
+
+use log::error;
+
+use super::{arbiter::Operator, media::StreamPacket, Rover};
+
+/// A media packet and the index of the fleet member it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FleetPacket {
+    pub rover: usize,
+    pub packet: StreamPacket,
+}
+
+pub struct FleetMember {
+    pub name: String,
+    pub address: SocketAddr,
+    pub rover: Rover,
+}
+
+/// Several rover sessions with their media streams merged into one channel.
+pub struct Fleet {
+    members: Vec<FleetMember>,
+    forwarders: Vec<JoinHandle<()>>,
+}
+
+impl Fleet {
+    /// Connects to every `(name, address)` in turn, failing if any of them fails. Names must be
+    /// unique.
+    pub fn connect<I, S>(rovers: I) -> anyhow::Result<(Self, Receiver<FleetPacket>)>
+    where
+        I: IntoIterator<Item = (S, SocketAddr)>,
+        S: Into<String>,
+    {
+        let rovers: Vec<(String, SocketAddr)> = rovers
+            .into_iter()
+            .map(|(name, address)| (name.into(), address))
+            .collect();
+        for (i, (name, _)) in rovers.iter().enumerate() {
+            if rovers[..i].iter().any(|(other, _)| other == name) {
+                anyhow::bail!("rover name {name:?} is used more than once");
+            }
+        }
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut fleet = Fleet {
+            members: Vec::new(),
+            forwarders: Vec::new(),
+        };
+
+        for (name, address) in rovers {
+            let (rover, packets) = Rover::connect(address)
+                .map_err(|e| anyhow::anyhow!("failed to connect to {name} at {address}: {e}"))?;
+
+            let index = fleet.members.len();
+            fleet
+                .forwarders
+                .push(spawn_forwarder(index, packets, tx.clone()));
+            fleet.members.push(FleetMember {
+                name,
+                address,
+                rover,
+            });
+        }
+
+        Ok((fleet, rx))
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn members(&self) -> &[FleetMember] {
+        &self.members
+    }
+
+    pub fn rover(&self, index: usize) -> Option<&Rover> {
+        self.members.get(index).map(|member| &member.rover)
+    }
+
+    /// Index of the member called `key`, or connected to the address or IP `key`.
+    pub fn position(&self, key: &str) -> Option<usize> {
+        self.members
+            .iter()
+            .position(|member| matches_key(&member.name, member.address, key))
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Rover> {
+        self.position(key).and_then(|index| self.rover(index))
+    }
+
+    /// Stops driving and turret movement on every rover, returning the first failure.
+    pub fn stop_all(&self, operator: &Operator) -> anyhow::Result<()> {
+        let mut result = Ok(());
+
+        for member in &self.members {
+            if let Err(e) = member.rover.stop(operator) {
+                error!("failed to stop {}: {e}", member.name);
+                result = result.and(Err(e));
+            }
+        }
+
+        result
+    }
+
+    /// Shuts down every session, returning the first failure.
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+
+        for member in self.members {
+            if let Err(e) = member.rover.shutdown() {
+                error!("failed to shut down {}: {e}", member.name);
+                result = result.and(Err(e));
+            }
+        }
+
+        // the media threads are gone, so the forwarders run out of packets
+        for forwarder in self.forwarders {
+            let _ = forwarder.join();
+        }
+
+        result
+    }
+}
+
+fn matches_key(name: &str, address: SocketAddr, key: &str) -> bool {
+    name == key || address.to_string() == key || address.ip().to_string() == key
+}
+
+fn spawn_forwarder(
+    rover: usize,
+    packets: Receiver<StreamPacket>,
+    tx: Sender<FleetPacket>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for packet in packets {
+            if tx.send(FleetPacket { rover, packet }).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_name_address_and_ip() {
+        let address: SocketAddr = "192.168.1.100:80".parse().unwrap();
+        assert!(matches_key("garage", address, "garage"));
+        assert!(matches_key("garage", address, "192.168.1.100:80"));
+        assert!(matches_key("garage", address, "192.168.1.100"));
+
+        assert!(!matches_key("garage", address, "Garage"));
+        assert!(!matches_key("garage", address, "192.168.1.100:81"));
+        assert!(!matches_key("garage", address, "192.168.1.10"));
+
+        let address: SocketAddr = "[::1]:8080".parse().unwrap();
+        assert!(matches_key("garage", address, "[::1]:8080"));
+        assert!(matches_key("garage", address, "::1"));
+    }
+
+    #[test]
+    fn rejects_duplicate_names_before_connecting() {
+        // nothing listens there, connecting would fail with a different error
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = closed.local_addr().unwrap();
+        drop(closed);
+
+        let error = Fleet::connect([("garage", address), ("shed", address), ("garage", address)])
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("more than once"), "{error}");
+    }
+}
@@ -0,0 +1,54 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use openh264::decoder::DecodedYUV;
+
+/// Tightly packed 8-bit RGB image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<u8>,
+}
+
+impl RgbImage {
+    pub fn from_yuv(frame: &DecodedYUV) -> Self {
+        let (width, height) = frame.dimension_rgb();
+        let mut data = vec![0; width * height * 3];
+        frame.write_rgb8(&mut data);
+
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let file = File::create(path.as_ref())
+            .map_err(|e| anyhow::anyhow!("failed to create {}: {e}", path.as_ref().display()))?;
+
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.data)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+
+    /// Encodes as baseline JPEG, `quality` ranging from 1 to 100.
+    pub fn to_jpeg(&self, quality: u8) -> anyhow::Result<Vec<u8>> {
+        let mut jpeg = Vec::new();
+        jpeg_encoder::Encoder::new(&mut jpeg, quality).encode(
+            &self.data,
+            self.width as u16,
+            self.height as u16,
+            jpeg_encoder::ColorType::Rgb,
+        )?;
+
+        Ok(jpeg)
+    }
+}
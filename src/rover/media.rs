@@ -1,6 +1,11 @@
-use std::net::TcpStream;
+use std::{net::TcpStream, sync::Arc};
 
-use super::socket_receive;
+use log::{debug, error};
+use openh264::decoder::Decoder;
+
+use super::{adpcm, image::RgbImage, metrics::Metrics, socket_receive};
+
+pub const AUDIO_SAMPLE_RATE: u32 = 8000;
 
 #[derive(Clone, PartialEq, Eq)]
 pub enum StreamPacket {
@@ -21,6 +26,82 @@ pub enum StreamPacket {
     },
 }
 
+impl StreamPacket {
+    /// Feeds a video packet into `decoder` and converts the resulting frame to RGB.
+    /// Returns `None` for audio packets and for packets that do not complete a frame.
+    pub fn to_rgb(&self, decoder: &mut Decoder) -> anyhow::Result<Option<RgbImage>> {
+        match self {
+            Self::Video { data, .. } => Ok(decoder
+                .decode(data.as_slice())?
+                .map(|frame| RgbImage::from_yuv(&frame))),
+            Self::Audio { .. } => Ok(None),
+        }
+    }
+
+    /// Decodes an audio packet to mono 16 bit PCM at [`AUDIO_SAMPLE_RATE`].
+    pub fn to_pcm(&self) -> Option<Vec<i16>> {
+        match self {
+            Self::Audio {
+                offset,
+                index,
+                data,
+                ..
+            } => Some(adpcm::adpcm_to_pcm(data, *offset, *index)),
+            Self::Video { .. } => None,
+        }
+    }
+
+    /// Whether decoding can start at this packet, i.e. it carries an IDR frame or the parameter
+    /// sets preceding one.
+    pub fn is_keyframe(&self) -> bool {
+        match self {
+            Self::Video { data, .. } => {
+                nal_units(data).any(|nal| matches!(nal[0] & 0x1F, NAL_IDR | NAL_SPS))
+            }
+            Self::Audio { .. } => false,
+        }
+    }
+}
+
+/// NAL unit types of an IDR slice and a sequence parameter set.
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+
+/// Splits an H.264 Annex B byte stream into its NAL units, without start codes.
+pub fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let ends = starts
+        .iter()
+        .skip(1)
+        .map(|&start| {
+            // a four byte start code leaves a zero behind
+            let end = start - 3;
+            if end > 0 && data[end - 1] == 0 {
+                end - 1
+            } else {
+                end
+            }
+        })
+        .chain([data.len()])
+        .collect::<Vec<_>>();
+
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &data[start..end])
+        .filter(|nal| !nal.is_empty())
+}
+
 impl std::fmt::Debug for StreamPacket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -58,16 +139,24 @@ impl std::fmt::Debug for StreamPacket {
     }
 }
 
+/// Reads packets from the media socket until it is closed or nobody receives them anymore.
 pub fn media_loop(
     mut media_socket: TcpStream,
     tx: std::sync::mpsc::Sender<StreamPacket>,
+    metrics: Arc<Metrics>,
 ) -> impl FnMut() {
     move || {
         let mut buf1 = vec![0_u8; 204800];
         let buf3 = [0x4D, 0x4F, 0x5F, 0x56];
 
         'label: loop {
-            let bytes = socket_receive(&mut media_socket, 23).unwrap();
+            let bytes = match socket_receive(&mut media_socket, 23) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    debug!("media stream stopped: {e}");
+                    break;
+                }
+            };
 
             let mut length = 1;
             for mut k in 0_i16.. {
@@ -80,7 +169,13 @@ pub fn media_loop(
                             buf1.resize(length as usize, 0);
                         }
 
-                        let bytes = socket_receive(&mut media_socket, length as usize).unwrap();
+                        let bytes = match socket_receive(&mut media_socket, length as usize) {
+                            Ok(bytes) => bytes,
+                            Err(e) => {
+                                error!("media stream broke off mid packet: {e}");
+                                break 'label;
+                            }
+                        };
 
                         let timestamp = u32::from_le_bytes(bytes[..4].try_into().unwrap());
                         let packet = match k {
@@ -118,7 +213,11 @@ pub fn media_loop(
                             _ => todo!(),
                         };
 
-                        tx.send(packet).unwrap();
+                        metrics.record_packet(&packet);
+                        if tx.send(packet).is_err() {
+                            debug!("media receiver dropped, stopping stream");
+                            break 'label;
+                        }
 
                         // System.arraycopy(bytes, p2 + 13, this.bArrayImage, 0, this.Video_Data_iVideoLen);
                         // AppDecodeH264.sessionDataCallBack(this.bArrayImage, this.Video_Data_iVideoLen, this.CurrentVideoType);
@@ -126,6 +225,7 @@ pub fn media_loop(
                         continue 'label;
                     }
 
+                    metrics.record_resync();
                     continue 'label;
                 }
 
@@ -0,0 +1,173 @@
+//! Link and stream health counters, collected for the whole lifetime of a [`Metrics`] so they can
+//! be graphed over long sessions.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use super::media::StreamPacket;
+
+/// Shared by everything that touches one rover session, usually through
+/// [`Rover::metrics`](super::Rover::metrics).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    video_packets: AtomicU64,
+    audio_packets: AtomicU64,
+    /// Per video type.
+    video_bytes: Mutex<BTreeMap<u8, u64>>,
+    decode_errors: AtomicU64,
+    resyncs: AtomicU64,
+    dropped_frames: AtomicU64,
+    command_latency: Mutex<Latency>,
+    heartbeat_misses: AtomicU64,
+    /// Requests from the rover on the command socket, for telling whether it went quiet.
+    received: AtomicU64,
+    last_packet: Mutex<Option<Instant>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Latency {
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+/// Counters at one point in time.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub video_packets: u64,
+    pub audio_packets: u64,
+    /// Video payload bytes per video type, 1 is 640x480 and 2 is 320x240.
+    pub video_bytes: BTreeMap<u8, u64>,
+    pub decode_errors: u64,
+    /// Times the media stream lost the packet header and skipped ahead to find it again.
+    pub resyncs: u64,
+    /// Video packets consumers had to skip, because they fell behind or lost the decoder state.
+    pub dropped_frames: u64,
+    /// Commands written to the rover, and the time they spent queued and being written.
+    pub commands_sent: u64,
+    pub command_latency_sum: Duration,
+    pub command_latency_max: Duration,
+    /// Heartbeats after which the rover had not sent anything since the previous one.
+    pub heartbeat_misses: u64,
+    /// Time since the last media packet, `None` before the first one.
+    pub last_packet_age: Option<Duration>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn record_packet(&self, packet: &StreamPacket) {
+        match packet {
+            StreamPacket::Video {
+                video_type, data, ..
+            } => {
+                self.video_packets.fetch_add(1, Ordering::Relaxed);
+                *self
+                    .video_bytes
+                    .lock()
+                    .unwrap()
+                    .entry(*video_type)
+                    .or_default() += data.len() as u64;
+            }
+            StreamPacket::Audio { .. } => {
+                self.audio_packets.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        *self.last_packet.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn record_decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_resync(&self) {
+        self.resyncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped_frames(&self, count: u64) {
+        self.dropped_frames.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// `latency` runs from queueing a command to having written it.
+    pub(crate) fn record_command(&self, latency: Duration) {
+        let mut total = self.command_latency.lock().unwrap();
+        total.count += 1;
+        total.sum += latency;
+        total.max = total.max.max(latency);
+    }
+
+    pub(crate) fn record_heartbeat_miss(&self) {
+        self.heartbeat_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let latency = *self.command_latency.lock().unwrap();
+
+        MetricsSnapshot {
+            video_packets: self.video_packets.load(Ordering::Relaxed),
+            audio_packets: self.audio_packets.load(Ordering::Relaxed),
+            video_bytes: self.video_bytes.lock().unwrap().clone(),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+            commands_sent: latency.count,
+            command_latency_sum: latency.sum,
+            command_latency_max: latency.max,
+            heartbeat_misses: self.heartbeat_misses.load(Ordering::Relaxed),
+            last_packet_age: self.last_packet.lock().unwrap().map(|last| last.elapsed()),
+        }
+    }
+}
+
+impl MetricsSnapshot {
+    pub fn mean_command_latency(&self) -> Option<Duration> {
+        (self.commands_sent > 0).then(|| {
+            Duration::from_secs_f64(
+                self.command_latency_sum.as_secs_f64() / self.commands_sent as f64,
+            )
+        })
+    }
+}
+
+/// Multi-line summary for logs.
+impl Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "packets:          {} video, {} audio",
+            self.video_packets, self.audio_packets
+        )?;
+        for (video_type, bytes) in &self.video_bytes {
+            writeln!(f, "video type {video_type}:     {bytes} bytes")?;
+        }
+        writeln!(f, "decode errors:    {}", self.decode_errors)?;
+        writeln!(f, "resyncs:          {}", self.resyncs)?;
+        writeln!(f, "dropped frames:   {}", self.dropped_frames)?;
+        match self.mean_command_latency() {
+            Some(mean) => writeln!(
+                f,
+                "commands sent:    {}, latency {mean:.1?} mean, {:.1?} max",
+                self.commands_sent, self.command_latency_max
+            )?,
+            None => writeln!(f, "commands sent:    0")?,
+        }
+        write!(f, "heartbeat misses: {}", self.heartbeat_misses)
+    }
+}
@@ -0,0 +1,390 @@
+//! Timed command sequences ("missions") that can be stored as TOML or JSON, played back through
+//! [`Rover::send_command`] and recorded from a manual driving session.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use openh264::decoder::Decoder;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    arbiter::Operator, duration, media::StreamPacket, patrol::PatrolConfig, Command, Rover,
+};
+
+/// How often the abort flag is checked while waiting.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Mission {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Step {
+    /// Send `command`, then wait for `duration` before the next step.
+    Command {
+        command: Command,
+        #[serde(with = "duration")]
+        duration: Duration,
+    },
+    Wait {
+        #[serde(with = "duration")]
+        duration: Duration,
+    },
+    /// Sweep the turret like a patrol for `duration`, then leave it where it is.
+    Scan {
+        #[serde(default)]
+        patrol: PatrolConfig,
+        #[serde(with = "duration")]
+        duration: Duration,
+    },
+    /// Save the next decoded video frame as PNG.
+    Snapshot { path: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissionOutcome {
+    Completed,
+    Aborted,
+}
+
+impl Mission {
+    /// Loads a mission, JSON if the file extension is `json`, TOML otherwise.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("failed to read {}: {e}", path.display()))?;
+
+        if is_json(path) {
+            Ok(serde_json::from_str(&content)?)
+        } else {
+            Ok(toml::from_str(&content)?)
+        }
+    }
+
+    /// Saves the mission, JSON if the file extension is `json`, TOML otherwise.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let content = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+
+        std::fs::write(path, content)
+            .map_err(|e| anyhow::anyhow!("failed to write {}: {e}", path.display()))
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.steps
+            .iter()
+            .map(|step| match step {
+                Step::Command { duration, .. }
+                | Step::Wait { duration }
+                | Step::Scan { duration, .. } => *duration,
+                Step::Snapshot { .. } => Duration::ZERO,
+            })
+            .sum()
+    }
+
+    /// Executes all steps in order. Setting `abort` stops the mission at the next poll, a failed
+    /// command or a closed media stream stops it with an error. In every case all motion is
+    /// stopped before returning. Commands are sent on behalf of `operator`.
+    pub fn run(
+        &self,
+        rover: &Rover,
+        operator: &Operator,
+        frames: &Receiver<StreamPacket>,
+        abort: &AtomicBool,
+    ) -> anyhow::Result<MissionOutcome> {
+        let result = self.run_steps(rover, operator, frames, abort);
+
+        if let Err(e) = rover.stop(operator) {
+            warn!("failed to stop motion after mission: {e}");
+        }
+
+        result
+    }
+
+    fn run_steps(
+        &self,
+        rover: &Rover,
+        operator: &Operator,
+        frames: &Receiver<StreamPacket>,
+        abort: &AtomicBool,
+    ) -> anyhow::Result<MissionOutcome> {
+        let mut decoder = self
+            .steps
+            .iter()
+            .any(|step| matches!(step, Step::Snapshot { .. }))
+            .then(Decoder::new)
+            .transpose()?;
+
+        for (i, step) in self.steps.iter().enumerate() {
+            if abort.load(Ordering::Relaxed) {
+                return Ok(MissionOutcome::Aborted);
+            }
+
+            info!("mission step {}/{}: {step:?}", i + 1, self.steps.len());
+
+            let waited = match step {
+                Step::Command { command, duration } => {
+                    rover.send_command(operator, *command)?;
+                    wait(rover, operator, *duration, frames, decoder.as_mut(), abort)?
+                }
+                Step::Wait { duration } => {
+                    wait(rover, operator, *duration, frames, decoder.as_mut(), abort)?
+                }
+                Step::Scan { patrol, duration } => {
+                    rover.start_patrol(operator, patrol.clone())?;
+                    let waited = wait(rover, operator, *duration, frames, decoder.as_mut(), abort);
+                    rover.stop_patrol(operator)?;
+                    waited?
+                }
+                Step::Snapshot { path } => {
+                    snapshot(path, frames, decoder.as_mut().unwrap())?;
+                    MissionOutcome::Completed
+                }
+            };
+
+            if waited == MissionOutcome::Aborted {
+                return Ok(MissionOutcome::Aborted);
+            }
+        }
+
+        Ok(MissionOutcome::Completed)
+    }
+}
+
+/// Waits for `duration` while draining the media stream, so the decoder stays in sync for later
+/// snapshots and a lost connection is noticed.
+fn wait(
+    rover: &Rover,
+    operator: &Operator,
+    duration: Duration,
+    frames: &Receiver<StreamPacket>,
+    mut decoder: Option<&mut Decoder>,
+    abort: &AtomicBool,
+) -> anyhow::Result<MissionOutcome> {
+    let end = Instant::now() + duration;
+
+    while let Some(remaining) = end.checked_duration_since(Instant::now()) {
+        if abort.load(Ordering::Relaxed) {
+            return Ok(MissionOutcome::Aborted);
+        }
+
+        rover.refresh_motion(operator);
+
+        match frames.recv_timeout(remaining.min(POLL_INTERVAL)) {
+            Ok(StreamPacket::Video { data, .. }) => {
+                if let Some(decoder) = decoder.as_deref_mut() {
+                    if let Err(e) = decoder.decode(data.as_slice()) {
+                        warn!("failed to decode frame: {e}");
+                    }
+                }
+            }
+            Ok(StreamPacket::Audio { .. }) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("media stream closed"),
+        }
+    }
+
+    Ok(MissionOutcome::Completed)
+}
+
+fn snapshot(
+    path: &Path,
+    frames: &Receiver<StreamPacket>,
+    decoder: &mut Decoder,
+) -> anyhow::Result<()> {
+    loop {
+        let packet = frames
+            .recv_timeout(FRAME_TIMEOUT)
+            .map_err(|e| anyhow::anyhow!("no video frame received: {e}"))?;
+
+        if let Some(image) = packet.to_rgb(decoder)? {
+            image.save_png(path)?;
+            info!("saved snapshot to {}", path.display());
+            return Ok(());
+        }
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+/// Turns commands sent during a manual session into a [`Mission`], using the time between two
+/// commands as the duration of the first.
+#[derive(Debug, Default)]
+pub struct MissionRecorder {
+    steps: Vec<Step>,
+    pending: Option<(Command, Instant)>,
+}
+
+impl MissionRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, command: Command) {
+        self.flush();
+        self.pending = Some((command, Instant::now()));
+    }
+
+    pub fn finish(mut self) -> Mission {
+        self.flush();
+
+        Mission { steps: self.steps }
+    }
+
+    fn flush(&mut self) {
+        if let Some((command, sent)) = self.pending.take() {
+            self.steps.push(Step::Command {
+                command,
+                duration: sent.elapsed(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rover::command::{Direction, HorizontalDirection, Speed, VerticalDirection};
+
+    fn example() -> Mission {
+        Mission {
+            steps: vec![
+                Step::Command {
+                    command: Command::Drive(
+                        Direction::Forward,
+                        HorizontalDirection::Neutral,
+                        Speed::Fast,
+                    ),
+                    duration: Duration::from_secs(3),
+                },
+                Step::Wait {
+                    duration: Duration::from_millis(500),
+                },
+                Step::Scan {
+                    patrol: PatrolConfig {
+                        width: 0.5,
+                        ..PatrolConfig::default()
+                    },
+                    duration: Duration::from_secs(20),
+                },
+                Step::Snapshot {
+                    path: "door.png".into(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn parses_toml() {
+        let mission: Mission = toml::from_str(
+            r#"
+            [[steps]]
+            action = "command"
+            command = { drive = ["forward", "neutral", "fast"] }
+            duration = "3s"
+
+            [[steps]]
+            action = "wait"
+            duration = "500ms"
+
+            [[steps]]
+            action = "scan"
+            patrol = { width = 0.5 }
+            duration = "20s"
+
+            [[steps]]
+            action = "snapshot"
+            path = "door.png"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(mission, example());
+        assert_eq!(mission.duration(), Duration::from_millis(23_500));
+    }
+
+    #[test]
+    fn parses_json() {
+        let mission: Mission = serde_json::from_str(
+            r#"{"steps": [
+                {"action": "command", "command": {"drive": ["forward", "neutral", "fast"]}, "duration": "3s"},
+                {"action": "wait", "duration": "0.5s"},
+                {"action": "scan", "patrol": {"width": 0.5}, "duration": "20s"},
+                {"action": "snapshot", "path": "door.png"}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(mission, example());
+    }
+
+    #[test]
+    fn scan_defaults_to_the_patrol_defaults() {
+        let mission: Mission =
+            toml::from_str("[[steps]]\naction = \"scan\"\nduration = \"10s\"").unwrap();
+
+        assert_eq!(
+            mission.steps,
+            [Step::Scan {
+                patrol: PatrolConfig::default(),
+                duration: Duration::from_secs(10),
+            }]
+        );
+    }
+
+    #[test]
+    fn saved_missions_load_again() {
+        let mission = example();
+        let toml = toml::to_string_pretty(&mission).unwrap();
+        assert_eq!(toml::from_str::<Mission>(&toml).unwrap(), mission);
+        let json = serde_json::to_string(&mission).unwrap();
+        assert_eq!(serde_json::from_str::<Mission>(&json).unwrap(), mission);
+    }
+
+    #[test]
+    fn recorder_times_each_command_until_the_next() {
+        let mut recorder = MissionRecorder::new();
+        let turret = Command::CameraMoveVertical(VerticalDirection::Up);
+        let stop = Command::CameraMoveVertical(VerticalDirection::Neutral);
+
+        recorder.record(turret);
+        std::thread::sleep(Duration::from_millis(50));
+        recorder.record(stop);
+        let mission = recorder.finish();
+
+        let [Step::Command {
+            command: first,
+            duration,
+        }, Step::Command {
+            command: last,
+            duration: last_duration,
+        }] = mission.steps.as_slice()
+        else {
+            panic!("unexpected steps: {:?}", mission.steps);
+        };
+        assert_eq!((*first, *last), (turret, stop));
+        assert!(*duration >= Duration::from_millis(50));
+        assert!(*last_duration < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn empty_recording_has_no_steps() {
+        assert_eq!(MissionRecorder::new().finish(), Mission::default());
+    }
+}
@@ -1,52 +1,119 @@
 use std::{
     io::{Read, Write},
-    net::{Ipv4Addr, TcpStream},
-    sync::mpsc::Receiver,
+    net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4, TcpStream},
+    sync::{mpsc::Receiver, Arc, Mutex},
     thread::JoinHandle,
     time::Duration,
 };
 
 use blowfish::cipher::{generic_array::GenericArray, typenum::U8, BlockEncrypt, KeyInit};
-use log::info;
+use log::{error, info, warn};
 
 use crate::rover::media::StreamPacket;
 
-use self::request::Request;
+use self::{
+    arbiter::{Arbiter, Grant, Lease, Operator, AUDIT_TARGET},
+    device::{DeviceInfo, LOGIN_REPLY_LEN},
+    metrics::Metrics,
+    patrol::PatrolConfig,
+    queue::CommandQueue,
+    replies::{wait_reply, Replies},
+    request::{opcode, Request, MEDIA_CHANNEL},
+    settings::CameraSettings,
+    telemetry::{Telemetry, TelemetryHub},
+    turret::{Turret, TurretPosition, TurretTravel},
+    watchdog::Watchdog,
+    wifi::WifiConfig,
+};
 
 pub mod adpcm;
+pub mod arbiter;
 mod command;
+pub mod device;
+pub mod discovery;
+pub mod duration;
+pub mod fleet;
+pub mod image;
 pub mod media;
-mod request;
+pub mod metrics;
+pub mod mission;
+pub mod patrol;
+mod queue;
+mod replies;
+pub mod request;
+pub mod settings;
+pub mod telemetry;
+pub mod turret;
+mod watchdog;
+pub mod wifi;
 
 pub use command::*;
+pub use watchdog::MOTION_TIMEOUT;
 
 const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 100);
 const PORT: u16 = 80;
 
+/// Address of the rover when acting as its own access point.
+pub const DEFAULT_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(IP, PORT));
+
 const TARGET_ID: &str = "AC13";
 const TARGET_PASSWORD: &str = "AC13";
 
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
 pub struct Rover {
     command_socket: TcpStream,
-    heartbeat_thread: JoinHandle<()>,
+    commands: Arc<CommandQueue>,
+    writer_thread: Option<JoinHandle<()>>,
+    replies: Arc<Replies>,
+    reader_thread: Option<JoinHandle<()>>,
+    telemetry: Arc<TelemetryHub>,
+    telemetry_thread: Option<JoinHandle<()>>,
     media_socket: TcpStream,
-    media_thread: JoinHandle<()>,
+    media_thread: Option<JoinHandle<()>>,
+    watchdog: Watchdog,
+    arbiter: Arbiter,
+    turret: Turret,
+    /// Last sent, the rover does not report them.
+    stealth_mode: Mutex<Option<bool>>,
+    active_camera: Mutex<Option<Camera>>,
+    /// Last read, updated by the commands sent since.
+    camera_settings: Mutex<Option<CameraSettings>>,
+    metrics: Arc<Metrics>,
+    device_info: DeviceInfo,
 }
 
 impl Rover {
     pub fn init() -> anyhow::Result<(Self, Receiver<StreamPacket>)> {
-        let mut command_socket = TcpStream::connect((IP, PORT))
+        Self::connect(DEFAULT_ADDRESS)
+    }
+
+    pub fn connect(address: SocketAddr) -> anyhow::Result<(Self, Receiver<StreamPacket>)> {
+        Self::connect_with_metrics(address, Arc::new(Metrics::new()))
+    }
+
+    /// Connects like [`connect`](Self::connect), but adds to existing `metrics`, e.g. those of
+    /// the session this one replaces.
+    pub fn connect_with_metrics(
+        address: SocketAddr,
+        metrics: Arc<Metrics>,
+    ) -> anyhow::Result<(Self, Receiver<StreamPacket>)> {
+        let mut command_socket = TcpStream::connect(address)
             .map_err(|e| anyhow::anyhow!("failed to connect socket: {}", e))?;
         command_socket.set_read_timeout(Some(Duration::from_secs_f32(5.0)))?;
         command_socket.set_write_timeout(Some(Duration::from_secs_f32(5.0)))?;
 
-        socket_send(&mut command_socket, Request::from_u32s(0, &[0, 0, 0, 0]))?;
+        socket_send(
+            &mut command_socket,
+            Request::from_u32s(opcode::LOGIN_REQUEST, [0, 0, 0, 0]),
+        )?;
 
         //let reply = receive_command_reply(&mut socket, 82).unwrap();
-        let reply = socket_receive(&mut command_socket, 82)?;
-        let camera_id = String::from_utf8(reply[25..37].to_vec()).unwrap();
+        let reply = socket_receive(&mut command_socket, LOGIN_REPLY_LEN)?;
+        let device_info = DeviceInfo::from_login_reply(&reply)?;
+        info!("connected to {device_info}");
 
-        let key = format!("{TARGET_ID}:{camera_id}-save-private:{TARGET_PASSWORD}");
+        let key = device_info.login_key(TARGET_ID, TARGET_PASSWORD);
 
         let [l1, r1, l2, r2]: [i32; 4] = reply[66..]
             .chunks_exact(4)
@@ -55,12 +122,12 @@ impl Rover {
             .try_into()
             .unwrap();
 
-        let blowfish = blowfish::BlowfishLE::new_from_slice(key.as_bytes()).unwrap();
+        let blowfish = blowfish::BlowfishLE::new_from_slice(&key).unwrap();
 
         let l1 = l1.to_le_bytes();
         let r1 = r1.to_le_bytes();
         let mut l1r1 = [l1[0], l1[1], l1[2], l1[3], r1[0], r1[1], r1[2], r1[3]];
-        let x: &mut GenericArray<u8, U8> = l1r1.as_mut_slice().try_into().unwrap();
+        let x: &mut GenericArray<u8, U8> = l1r1.as_mut_slice().into();
         blowfish.encrypt_block(x);
         let l1 = u32::from_le_bytes(l1r1[0..4].try_into().unwrap());
         let r1 = u32::from_le_bytes(l1r1[4..].try_into().unwrap());
@@ -68,33 +135,25 @@ impl Rover {
         let l2 = l2.to_le_bytes();
         let r2 = r2.to_le_bytes();
         let mut l2r2 = [l2[0], l2[1], l2[2], l2[3], r2[0], r2[1], r2[2], r2[3]];
-        let x: &mut GenericArray<u8, U8> = l2r2.as_mut_slice().try_into().unwrap();
+        let x: &mut GenericArray<u8, U8> = l2r2.as_mut_slice().into();
         blowfish.encrypt_block(x);
         let l2 = u32::from_le_bytes(l2r2[0..4].try_into().unwrap());
         let r2 = u32::from_le_bytes(l2r2[4..].try_into().unwrap());
 
         socket_send(
             &mut command_socket,
-            Request::from_u32s(2, &[l1, r1, l2, r2]),
+            Request::from_u32s(opcode::VERIFY_REQUEST, [l1, r1, l2, r2]),
         )?;
 
         let _ = socket_receive(&mut command_socket, 26)?;
 
-        let heartbeat_thread = {
-            let mut command_socket = command_socket.try_clone().unwrap();
-            std::thread::spawn(move || {
-                socket_send(&mut command_socket, Request::heartbeat()).unwrap();
-                std::thread::sleep(std::time::Duration::from_secs(60))
-            })
-        };
-
         // video start request
         socket_send(&mut command_socket, Request::video_start())
             .map_err(|e| anyhow::anyhow!("Failed to send video start request: {}", e))?;
 
         let video_start_reply = socket_receive(&mut command_socket, 29)?;
 
-        let mut media_socket = TcpStream::connect((IP, PORT))
+        let mut media_socket = TcpStream::connect(address)
             .map_err(|e| anyhow::anyhow!("failed to connect socket: {}", e))?;
         media_socket.set_read_timeout(Some(Duration::from_secs_f32(5.0)))?;
         media_socket.set_write_timeout(Some(Duration::from_secs_f32(5.0)))?;
@@ -102,43 +161,460 @@ impl Rover {
         socket_send(
             &mut media_socket,
             Request {
-                c: 0x56,
-                id: 0,
+                c: MEDIA_CHANNEL,
+                id: opcode::MEDIA_LOGIN,
                 n: 4,
                 bytes: video_start_reply[25..].to_vec(),
             },
         )?;
 
+        // the stream may be paused for arbitrarily long
+        media_socket.set_read_timeout(None)?;
+
         let (tx, rx) = std::sync::mpsc::channel();
         let media_thread = {
             let media_socket = media_socket.try_clone().unwrap();
-            std::thread::spawn(media::media_loop(media_socket, tx))
+            std::thread::spawn(media::media_loop(media_socket, tx, metrics.clone()))
         };
 
         socket_send(&mut command_socket, Request::audio_start())?;
 
         let _ = socket_receive(&mut command_socket, 25)?;
 
+        let commands = CommandQueue::new();
+        let turret = Turret::spawn(commands.clone());
+        let writer_thread = queue::spawn_writer(
+            commands.clone(),
+            command_socket.try_clone()?,
+            turret.observer(),
+            metrics.clone(),
+        );
+
+        // from here on replies are read by a thread that waits indefinitely
+        command_socket.set_read_timeout(None)?;
+        let replies = Replies::new();
+        let (telemetry, telemetry_thread) =
+            TelemetryHub::spawn(replies.subscribe(&[opcode::STATUS_REPORT, opcode::ALARM_NOTIFY]));
+        let reader_thread = replies::spawn_reader(
+            replies.clone(),
+            command_socket.try_clone()?,
+            metrics.clone(),
+        );
+
+        // detached, it exits on the first beat after the queue shuts down
+        {
+            let commands = commands.clone();
+            let metrics = metrics.clone();
+            std::thread::spawn(move || {
+                let mut received = metrics.received();
+                while commands.push_request(Request::heartbeat()).is_ok() {
+                    std::thread::sleep(HEARTBEAT_INTERVAL);
+
+                    // the rover reports its status regularly, silence means the link is failing
+                    if metrics.received() == received {
+                        warn!("nothing received from the rover since the last heartbeat");
+                        metrics.record_heartbeat_miss();
+                    }
+                    received = metrics.received();
+                }
+            });
+        }
+
+        let watchdog = Watchdog::spawn(commands.clone());
+
         Ok((
             Rover {
                 command_socket,
-                heartbeat_thread,
+                commands,
+                writer_thread: Some(writer_thread),
+                replies,
+                reader_thread: Some(reader_thread),
+                telemetry,
+                telemetry_thread: Some(telemetry_thread),
                 media_socket,
-                media_thread,
+                media_thread: Some(media_thread),
+                watchdog,
+                arbiter: Arbiter::new(),
+                turret,
+                stealth_mode: Mutex::new(None),
+                active_camera: Mutex::new(None),
+                camera_settings: Mutex::new(None),
+                metrics,
+                device_info,
             },
             rx,
         ))
     }
 
-    pub fn send_command(&mut self, command: Command) -> anyhow::Result<()> {
-        info!("sending {command:?}");
-        socket_send(&mut self.command_socket, command.to_request())
+    pub fn device_info(&self) -> &DeviceInfo {
+        &self.device_info
+    }
+
+    /// Queues `command` for the writer thread on behalf of `operator`, who has to hold the control
+    /// lease or takes it if nobody does. Fails with [`ViewOnly`](arbiter::ViewOnly) for everyone
+    /// else, and once the command socket is broken.
+    pub fn send_command(&self, operator: &Operator, command: Command) -> anyhow::Result<()> {
+        command.validate()?;
+        self.authorize(operator, &command)?;
+        info!(target: AUDIT_TARGET, "{operator}: {command:?}");
+        self.push_command(command)
+    }
+
+    fn push_command(&self, command: Command) -> anyhow::Result<()> {
+        if matches!(
+            command.kind(),
+            CommandKind::TurretHorizontal | CommandKind::TurretVertical
+        ) {
+            self.turret.take_over();
+        }
+        match command {
+            Command::StealthMode(enabled) => *self.stealth_mode.lock().unwrap() = Some(enabled),
+            Command::UseCamera(camera) => *self.active_camera.lock().unwrap() = Some(camera),
+            _ => {}
+        }
+        if let Some(settings) = &mut *self.camera_settings.lock().unwrap() {
+            match command {
+                Command::Brightness(brightness) => settings.brightness = brightness,
+                Command::Contrast(contrast) => settings.contrast = contrast,
+                Command::Resolution(resolution) => settings.resolution = resolution,
+                Command::FrameRate(fps) => settings.frame_rate = fps,
+                Command::Orientation(orientation) => settings.orientation = orientation,
+                _ => {}
+            }
+        }
+        self.watchdog.track(command);
+        self.commands.push(command)
+    }
+
+    /// Renews or takes the control lease for `operator`. When control changes hands, whatever the
+    /// previous holder started is stopped first.
+    fn authorize(&self, operator: &Operator, action: &dyn std::fmt::Debug) -> anyhow::Result<()> {
+        match self.arbiter.acquire(operator) {
+            Ok(Grant::Renewed) => Ok(()),
+            Ok(Grant::Taken) => self.stop_everything(),
+            Err(e) => {
+                info!(target: AUDIT_TARGET, "{operator}: {action:?} denied, {e}");
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Takes the control lease for `operator`, or renews it, see [`arbiter`].
+    pub fn acquire_control(&self, operator: &Operator) -> anyhow::Result<()> {
+        self.authorize(operator, &"acquire control")
+    }
+
+    /// Takes the control lease if it is free, otherwise asks the holder to hand over and fails
+    /// with [`ViewOnly`](arbiter::ViewOnly).
+    pub fn request_control(&self, operator: &Operator) -> anyhow::Result<()> {
+        match self.arbiter.request_handover(operator)? {
+            Grant::Renewed => Ok(()),
+            Grant::Taken => self.stop_everything(),
+        }
+    }
+
+    /// Takes the control lease from its holder, for admins only.
+    pub fn take_control(&self, operator: &Operator) -> anyhow::Result<()> {
+        match self.arbiter.take_over(operator)? {
+            Grant::Renewed => Ok(()),
+            Grant::Taken => self.stop_everything(),
+        }
+    }
+
+    /// Gives up the control lease of `operator`, stopping the rover, and hands it over to whoever
+    /// asked for it.
+    pub fn release_control(&self, operator: &Operator) -> anyhow::Result<()> {
+        if self.arbiter.release(operator) {
+            self.stop_everything()?;
+        }
+
+        Ok(())
+    }
+
+    /// The current control lease, `None` while nobody holds it.
+    pub fn control_lease(&self) -> Option<Lease> {
+        self.arbiter.lease()
+    }
+
+    pub fn set_lease_timeout(&self, timeout: Duration) {
+        self.arbiter.set_timeout(timeout);
+    }
+
+    /// Whether stealth mode was last switched on, `None` before it was switched at all.
+    pub fn stealth_mode(&self) -> Option<bool> {
+        *self.stealth_mode.lock().unwrap()
+    }
+
+    /// The camera last selected, `None` before one was selected.
+    pub fn active_camera(&self) -> Option<Camera> {
+        *self.active_camera.lock().unwrap()
+    }
+
+    /// The camera settings as last read with [`camera_settings`](Self::camera_settings) and
+    /// changed since, without asking the rover. `None` before they were read at all.
+    pub fn cached_camera_settings(&self) -> Option<CameraSettings> {
+        *self.camera_settings.lock().unwrap()
+    }
+
+    /// Link and stream health of this session.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Receives every telemetry update from now on.
+    pub fn telemetry(&self) -> Receiver<Telemetry> {
+        self.telemetry.subscribe()
+    }
+
+    /// All telemetry received so far, merged into one.
+    pub fn latest_telemetry(&self) -> Telemetry {
+        self.telemetry.latest()
+    }
+
+    /// Estimated turret position, see [`turret_goto`](Self::turret_goto).
+    pub fn turret_position(&self) -> TurretPosition {
+        self.turret.position()
+    }
+
+    /// Starts moving the turret to `position` in the background. The position is estimated from
+    /// movement times and assumed centered on connect, run
+    /// [`home_turret`](Self::home_turret) first for accurate aiming. Moving the turret
+    /// manually cancels the movement, starting one ends a patrol.
+    pub fn turret_goto(&self, operator: &Operator, position: TurretPosition) -> anyhow::Result<()> {
+        position.validate()?;
+        self.authorize(operator, &format_args!("turret to {position:?}"))?;
+        info!(target: AUDIT_TARGET, "{operator}: moving turret to {position:?}");
+        self.turret.goto(position)
+    }
+
+    pub fn turret_center(&self, operator: &Operator) -> anyhow::Result<()> {
+        self.turret_goto(operator, TurretPosition::CENTER)
+    }
+
+    /// Drives the turret into its end stops to find a known position, then centers it. The travel
+    /// times are not measured on the way, see [`set_turret_travel`](Self::set_turret_travel).
+    pub fn home_turret(&self, operator: &Operator) -> anyhow::Result<()> {
+        self.authorize(operator, &"turret homing")?;
+        info!(target: AUDIT_TARGET, "{operator}: homing turret");
+        self.turret.home();
+
+        Ok(())
+    }
+
+    /// Replaces the default travel times the position estimate is based on.
+    pub fn set_turret_travel(&self, travel: TurretTravel) -> anyhow::Result<()> {
+        self.turret.set_travel(travel)
+    }
+
+    /// Sweeps the turret back and forth in the background until
+    /// [`stop_patrol`](Self::stop_patrol). Moving the turret manually pauses the patrol for
+    /// [`PatrolConfig::resume_after`].
+    pub fn start_patrol(&self, operator: &Operator, config: PatrolConfig) -> anyhow::Result<()> {
+        config.validate()?;
+        self.authorize(operator, &"turret patrol")?;
+        info!(target: AUDIT_TARGET, "{operator}: starting turret patrol: {config:?}");
+        self.turret.start_patrol(config)
+    }
+
+    /// Ends the patrol, leaving the turret where it is. Allowed for every operator, like
+    /// [`stop`](Self::stop).
+    pub fn stop_patrol(&self, operator: &Operator) -> anyhow::Result<()> {
+        info!(target: AUDIT_TARGET, "{operator}: stopping turret patrol");
+        self.turret.stop();
+        self.commands
+            .push(Command::CameraMoveHorizontal(HorizontalDirection::Neutral))?;
+        self.commands
+            .push(Command::CameraMoveVertical(VerticalDirection::Neutral))
+    }
+
+    pub fn is_patrolling(&self) -> bool {
+        self.turret.is_patrolling()
+    }
+
+    /// Blocks until the turret movement started last is done, returns `false` on timeout.
+    pub fn wait_for_turret(&self, timeout: Duration) -> bool {
+        self.turret.wait_idle(timeout)
+    }
+
+    /// Asks the rover to stop sending video. The media connection stays open, so
+    /// [`resume_video`](Self::resume_video) picks up where it left off. Pauses the stream for
+    /// everyone watching, so `operator` needs the control lease like for any command.
+    pub fn pause_video(&self, operator: &Operator) -> anyhow::Result<()> {
+        self.authorize(operator, &"pausing video")?;
+        info!(target: AUDIT_TARGET, "{operator}: pausing video");
+        self.commands.push_request(Request::video_stop())
+    }
+
+    pub fn resume_video(&self, operator: &Operator) -> anyhow::Result<()> {
+        self.authorize(operator, &"resuming video")?;
+        info!(target: AUDIT_TARGET, "{operator}: resuming video");
+        let reply = self.replies.expect(opcode::VIDEO_START_REPLY);
+        self.commands.push_request(Request::video_start())?;
+
+        wait_reply(reply, "video start request").map(drop)
+    }
+
+    pub fn set_audio_enabled(&self, operator: &Operator, enabled: bool) -> anyhow::Result<()> {
+        let action = if enabled {
+            "enabling audio"
+        } else {
+            "disabling audio"
+        };
+        self.authorize(operator, &action)?;
+        info!(target: AUDIT_TARGET, "{operator}: {action}");
+
+        if !enabled {
+            return self.commands.push_request(Request::audio_stop());
+        }
+
+        let reply = self.replies.expect(opcode::AUDIO_START_REPLY);
+        self.commands.push_request(Request::audio_start())?;
+
+        wait_reply(reply, "audio start request").map(drop)
+    }
+
+    /// Reads back the current image settings from the camera.
+    pub fn camera_settings(&self) -> anyhow::Result<CameraSettings> {
+        let reply = self.replies.expect(opcode::CAMERA_PARAMS_REPLY);
+        self.commands.push_request(Request::from_command_byte(
+            opcode::CAMERA_PARAMS_REQUEST,
+            [],
+        ))?;
+
+        let settings = CameraSettings::decode(&wait_reply(reply, "camera settings request")?)?;
+        *self.camera_settings.lock().unwrap() = Some(settings);
+
+        Ok(settings)
+    }
+
+    pub fn wifi_config(&self) -> anyhow::Result<WifiConfig> {
+        let reply = self.replies.expect(opcode::WIFI_CONFIG_GET_REPLY);
+        self.commands.push_request(Request::from_command_byte(
+            opcode::WIFI_CONFIG_GET_REQUEST,
+            [],
+        ))?;
+
+        WifiConfig::decode(&wait_reply(reply, "wifi config request")?)
+    }
+
+    /// Stores a new WiFi configuration on the rover. After switching modes the rover has to be
+    /// restarted and is then reachable on the new network only. Refuses to write anything if the
+    /// current configuration does not decode, as the layout is then not the one this rover uses.
+    /// Like every command it needs the control lease.
+    pub fn set_wifi_config(&self, operator: &Operator, config: &WifiConfig) -> anyhow::Result<()> {
+        self.authorize(operator, &"wifi config update")?;
+        self.wifi_config()
+            .map_err(|e| anyhow::anyhow!("not changing the wifi config: {e}"))?;
+        info!(target: AUDIT_TARGET, "{operator}: changing wifi config to mode {:?}", config.mode);
+        let request = Request::from_command_byte(opcode::WIFI_CONFIG_SET_REQUEST, config.encode()?);
+
+        let reply = self.replies.expect(opcode::WIFI_CONFIG_SET_REPLY);
+        self.commands.push_request(request)?;
+
+        match wait_reply(reply, "wifi config update")?.bytes.as_slice() {
+            [0, 0] => Ok(()),
+            [a, b] => anyhow::bail!(
+                "rover rejected wifi config with code {}",
+                u16::from_le_bytes([*a, *b])
+            ),
+            bytes => anyhow::bail!("invalid wifi config update reply {bytes:02X?}"),
+        }
+    }
+
+    /// Signals that the controller is still alive. While the rover is driving or the turret is
+    /// moving, this (or another motion command) has to be called at least every
+    /// [`MOTION_TIMEOUT`], otherwise all motion is stopped. Only counts for the holder of the
+    /// control lease, and keeps the lease while the rover is moving.
+    pub fn refresh_motion(&self, operator: &Operator) {
+        if !self.arbiter.may_control(operator) {
+            return;
+        }
+
+        self.watchdog.refresh();
+        if self.watchdog.is_moving() {
+            self.arbiter.renew(operator);
+        }
+    }
+
+    pub fn set_motion_timeout(&self, timeout: Duration) {
+        self.watchdog.set_timeout(timeout);
+    }
+
+    /// Stops driving and turret movement. Allowed for every operator, with or without the lease.
+    pub fn stop(&self, operator: &Operator) -> anyhow::Result<()> {
+        info!(target: AUDIT_TARGET, "{operator}: stop");
+        self.stop_motion()
+    }
+
+    fn stop_motion(&self) -> anyhow::Result<()> {
+        self.push_command(Command::Drive(
+            Direction::Neutral,
+            HorizontalDirection::Neutral,
+            Speed::Fast,
+        ))?;
+        self.push_command(Command::CameraMoveHorizontal(HorizontalDirection::Neutral))?;
+        self.push_command(Command::CameraMoveVertical(VerticalDirection::Neutral))
+    }
+
+    /// Also ends turret movements and patrols.
+    fn stop_everything(&self) -> anyhow::Result<()> {
+        self.turret.stop();
+        self.stop_motion()
+    }
+
+    /// Stops motion and both streams, then closes the connection. Dropping the rover does the
+    /// same but can only log failures.
+    pub fn shutdown(mut self) -> anyhow::Result<()> {
+        self.close()
+    }
+
+    fn close(&mut self) -> anyhow::Result<()> {
+        let Some(writer_thread) = self.writer_thread.take() else {
+            return Ok(());
+        };
+
+        info!("closing connection");
+        self.turret.stop();
+
+        // keep going on failure, the sockets have to be closed either way
+        let result = self
+            .stop_motion()
+            .and_then(|()| self.commands.push_request(Request::video_stop()))
+            .and_then(|()| self.commands.push_request(Request::audio_stop()));
+
+        self.commands.shutdown();
+        let _ = writer_thread.join();
+
+        // unblocks the reader and media threads, the telemetry thread follows the reader and the
+        // heartbeat thread exits on its next beat
+        let _ = self.command_socket.shutdown(Shutdown::Both);
+        let _ = self.media_socket.shutdown(Shutdown::Both);
+
+        for thread in [
+            self.reader_thread.take(),
+            self.telemetry_thread.take(),
+            self.media_thread.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            let _ = thread.join();
+        }
+
+        result
+    }
+}
+
+impl Drop for Rover {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            error!("failed to shut down cleanly: {e}");
+        }
     }
 }
 
 fn socket_receive(sock: &mut TcpStream, len: usize) -> anyhow::Result<Vec<u8>> {
-    let mut buf = Vec::new();
-    buf.resize(len, 0);
+    let mut buf = vec![0; len];
     sock.read_exact(&mut buf)
         .map_err(|e| anyhow::anyhow!("Failed to read from socket: {e}"))?;
     Ok(buf)
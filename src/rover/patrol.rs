@@ -0,0 +1,198 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::{duration, turret::TurretPosition};
+
+/// Continuous turret sweep, e.g. for unattended monitoring. Missing fields take their defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PatrolConfig {
+    /// The turret pans between `-width` and `width`, see [`TurretPosition`].
+    pub width: f32,
+    /// Tilt of each sweep, visited in turn. Empty keeps the tilt the patrol started at.
+    #[serde(default)]
+    pub tilts: Vec<f32>,
+    /// Pause at each end of a sweep.
+    #[serde(with = "duration")]
+    pub dwell: Duration,
+    /// How long the patrol stays paused after the operator moved the turret.
+    #[serde(with = "duration")]
+    pub resume_after: Duration,
+}
+
+impl PatrolConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.width.is_finite() {
+            anyhow::bail!("invalid patrol width {}", self.width);
+        }
+        if let Some(tilt) = self.tilts.iter().find(|tilt| !tilt.is_finite()) {
+            anyhow::bail!("invalid patrol tilt {tilt}");
+        }
+        Ok(())
+    }
+}
+
+impl Default for PatrolConfig {
+    fn default() -> Self {
+        Self {
+            width: 0.8,
+            tilts: Vec::new(),
+            dwell: Duration::from_secs(2),
+            resume_after: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Where a running patrol is headed next.
+pub(crate) struct Patrol {
+    config: PatrolConfig,
+    start_tilt: f32,
+    next: usize,
+    resume_at: Option<Instant>,
+}
+
+impl Patrol {
+    pub fn new(config: PatrolConfig, start_tilt: f32) -> Self {
+        Self {
+            config,
+            start_tilt,
+            next: 0,
+            resume_at: None,
+        }
+    }
+
+    /// The next waypoint, or the time to wait for while dwelling or paused.
+    pub fn next(&mut self) -> Result<TurretPosition, Instant> {
+        if let Some(resume_at) = self.resume_at {
+            if Instant::now() < resume_at {
+                return Err(resume_at);
+            }
+            self.resume_at = None;
+        }
+
+        let tilts = match self.config.tilts.as_slice() {
+            [] => std::slice::from_ref(&self.start_tilt),
+            tilts => tilts,
+        };
+        // every other sweep runs backwards, so changing the tilt never crosses the whole range.
+        // With an odd number of tilts that takes two rounds through them to end up on the left.
+        let sweeps = match tilts.len() {
+            1 => 1,
+            n if n.is_multiple_of(2) => n,
+            n => 2 * n,
+        };
+        let sweep = self.next / 2;
+        let left = (self.next + sweep).is_multiple_of(2);
+        self.next = (self.next + 1) % (2 * sweeps);
+
+        let width = self.config.width.clamp(0.0, 1.0);
+        Ok(TurretPosition {
+            pan: if left { -width } else { width },
+            tilt: tilts[sweep % tilts.len()].clamp(-1.0, 1.0),
+        })
+    }
+
+    pub fn arrived(&mut self) {
+        self.resume_at = Some(Instant::now() + self.config.dwell);
+    }
+
+    pub fn pause(&mut self) {
+        self.resume_at = Some(Instant::now() + self.config.resume_after);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first `count` waypoints as (pan, tilt).
+    fn waypoints(tilts: &[f32], count: usize) -> Vec<(f32, f32)> {
+        let config = PatrolConfig {
+            width: 0.5,
+            tilts: tilts.to_vec(),
+            ..PatrolConfig::default()
+        };
+        let mut patrol = Patrol::new(config, 0.25);
+
+        (0..count)
+            .map(|_| patrol.next().unwrap())
+            .map(|position| (position.pan, position.tilt))
+            .collect()
+    }
+
+    #[test]
+    fn sweeps_at_the_start_tilt_without_tilts() {
+        assert_eq!(
+            waypoints(&[], 4),
+            [(-0.5, 0.25), (0.5, 0.25), (-0.5, 0.25), (0.5, 0.25)]
+        );
+    }
+
+    #[test]
+    fn sweeps_back_and_forth_at_one_tilt() {
+        assert_eq!(
+            waypoints(&[-0.5], 4),
+            [(-0.5, -0.5), (0.5, -0.5), (-0.5, -0.5), (0.5, -0.5)]
+        );
+    }
+
+    #[test]
+    fn changes_tilt_at_the_ends_of_a_sweep() {
+        assert_eq!(
+            waypoints(&[0.0, 0.5], 6),
+            [
+                (-0.5, 0.0),
+                (0.5, 0.0),
+                (0.5, 0.5),
+                (-0.5, 0.5),
+                (-0.5, 0.0),
+                (0.5, 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn wraps_around_on_the_same_side_with_odd_tilts() {
+        let waypoints = waypoints(&[0.0, 0.5, 1.0], 14);
+        assert_eq!(
+            waypoints,
+            [
+                (-0.5, 0.0),
+                (0.5, 0.0),
+                (0.5, 0.5),
+                (-0.5, 0.5),
+                (-0.5, 1.0),
+                (0.5, 1.0),
+                (0.5, 0.0),
+                (-0.5, 0.0),
+                (-0.5, 0.5),
+                (0.5, 0.5),
+                (0.5, 1.0),
+                (-0.5, 1.0),
+                (-0.5, 0.0),
+                (0.5, 0.0),
+            ]
+        );
+
+        // only ever one axis changes between waypoints
+        for pair in waypoints.windows(2) {
+            assert!(pair[0].0 == pair[1].0 || pair[0].1 == pair[1].1, "{pair:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_non_finite_values() {
+        assert!(PatrolConfig::default().validate().is_ok());
+        let config = PatrolConfig {
+            width: f32::NAN,
+            ..PatrolConfig::default()
+        };
+        assert!(config.validate().is_err());
+        let config = PatrolConfig {
+            tilts: vec![0.0, f32::INFINITY],
+            ..PatrolConfig::default()
+        };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::TcpStream,
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{error, trace};
+
+use super::{metrics::Metrics, socket_send, Command, CommandKind, HorizontalDirection, Request};
+
+/// Outgoing traffic on the command socket, written by a dedicated thread so callers never block
+/// on the socket.
+///
+/// Commands of the same [`CommandKind`] that are still queued are coalesced into one, commands
+/// that would not change the state last sent to the rover are dropped, and each kind is sent at
+/// most once per [`min_interval`]. Everything else is written in the order it was queued, a
+/// command waiting for its interval delays the commands and requests behind it.
+pub(crate) struct CommandQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<Outgoing>,
+    last_sent: HashMap<CommandKind, (Command, Instant)>,
+    error: Option<String>,
+    shutdown: bool,
+}
+
+enum Outgoing {
+    /// Queued since the instant, kept when coalescing.
+    Command(Command, Instant),
+    Request(Request),
+}
+
+fn min_interval(kind: CommandKind) -> Duration {
+    match kind {
+        CommandKind::Drive | CommandKind::TurretHorizontal | CommandKind::TurretVertical => {
+            Duration::from_millis(50)
+        }
+        CommandKind::Camera
+        | CommandKind::Stealth
+        | CommandKind::Brightness
+        | CommandKind::Contrast
+        | CommandKind::Resolution
+        | CommandKind::FrameRate
+        | CommandKind::Orientation => Duration::from_millis(250),
+    }
+}
+
+/// Merges `next` into the still unsent `pending` command of the same kind, keeping the state
+/// the rover ends up in.
+fn coalesce(pending: Command, next: Command) -> Command {
+    match (pending, next) {
+        // a steer stop only ends the turn, replacing the unsent drive with it would also drop
+        // the direction, so it becomes the straight drive the rover would have ended up with
+        (Command::Drive(direction, _, _), Command::SteerStop(speed)) => {
+            Command::Drive(direction, HorizontalDirection::Neutral, speed)
+        }
+        (_, next) => next,
+    }
+}
+
+impl CommandQueue {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(QueueState::default()),
+            changed: Condvar::new(),
+        })
+    }
+
+    pub fn push(&self, command: Command) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+
+        let kind = command.kind();
+        let last_sent = state.last_sent.get(&kind).map(|(command, _)| *command);
+
+        let queued = state.pending.iter().position(
+            |outgoing| matches!(outgoing, Outgoing::Command(queued, _) if queued.kind() == kind),
+        );
+
+        match queued {
+            Some(i) => {
+                let Outgoing::Command(queued, since) = state.pending[i] else {
+                    unreachable!()
+                };
+                let merged = coalesce(queued, command);
+
+                if last_sent == Some(merged) {
+                    trace!("dropping {command:?}, rover is already in that state");
+                    state.pending.remove(i);
+                } else {
+                    trace!("coalescing {queued:?} and {command:?} into {merged:?}");
+                    state.pending[i] = Outgoing::Command(merged, since);
+                }
+            }
+            None if last_sent == Some(command) => {
+                trace!("dropping {command:?}, rover is already in that state");
+            }
+            None => state
+                .pending
+                .push_back(Outgoing::Command(command, Instant::now())),
+        }
+
+        self.changed.notify_all();
+
+        Ok(())
+    }
+
+    pub fn push_request(&self, request: Request) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+
+        state.pending.push_back(Outgoing::Request(request));
+        self.changed.notify_all();
+
+        Ok(())
+    }
+
+    /// Lets the writer thread exit once everything queued so far has been written.
+    pub fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.changed.notify_all();
+    }
+
+    /// Index of the next entry if it may be sent now, or the time at which it becomes ready.
+    /// Only the oldest entry is considered, so one waiting for its interval holds back the rest.
+    fn next_ready(state: &QueueState) -> Result<usize, Option<Instant>> {
+        let Some(outgoing) = state.pending.front() else {
+            return Err(None);
+        };
+        let Outgoing::Command(command, _) = outgoing else {
+            return Ok(0);
+        };
+
+        let kind = command.kind();
+        match state.last_sent.get(&kind) {
+            Some((_, sent)) if *sent + min_interval(kind) > Instant::now() => {
+                Err(Some(*sent + min_interval(kind)))
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+impl QueueState {
+    fn check(&self) -> anyhow::Result<()> {
+        if let Some(e) = &self.error {
+            anyhow::bail!("command socket failed: {e}");
+        }
+        if self.shutdown {
+            anyhow::bail!("rover connection is shutting down");
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes queued traffic to `socket`, calling `on_sent` with each command as it is written.
+pub(crate) fn spawn_writer(
+    queue: Arc<CommandQueue>,
+    mut socket: TcpStream,
+    on_sent: impl Fn(&Command) + Send + 'static,
+    metrics: Arc<Metrics>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        let mut state = queue.state.lock().unwrap();
+
+        let outgoing = match CommandQueue::next_ready(&state) {
+            Ok(i) => state.pending.remove(i).unwrap(),
+            Err(_) if state.shutdown && state.pending.is_empty() => break,
+            Err(None) => {
+                drop(queue.changed.wait(state).unwrap());
+                continue;
+            }
+            Err(Some(ready)) => {
+                let timeout = ready.saturating_duration_since(Instant::now());
+                drop(queue.changed.wait_timeout(state, timeout).unwrap());
+                continue;
+            }
+        };
+
+        if let Outgoing::Command(command, _) = &outgoing {
+            state
+                .last_sent
+                .insert(command.kind(), (*command, Instant::now()));
+        }
+        drop(state);
+
+        let (request, queued) = match outgoing {
+            Outgoing::Command(command, queued) => {
+                trace!("writing {command:?}");
+                on_sent(&command);
+                (command.to_request(), Some(queued))
+            }
+            Outgoing::Request(request) => (request, None),
+        };
+
+        let result = socket_send(&mut socket, request);
+        if let (Ok(()), Some(queued)) = (&result, queued) {
+            metrics.record_command(queued.elapsed());
+        }
+        if let Err(e) = result {
+            error!("command writer stopped: {e}");
+
+            let mut state = queue.state.lock().unwrap();
+            state.error = Some(e.to_string());
+            state.pending.clear();
+            break;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rover::command::{Direction, Speed, VerticalDirection};
+
+    const FORWARD_LEFT: Command =
+        Command::Drive(Direction::Forward, HorizontalDirection::Left, Speed::Fast);
+    const FORWARD: Command = Command::Drive(
+        Direction::Forward,
+        HorizontalDirection::Neutral,
+        Speed::Slow,
+    );
+
+    fn pending(queue: &CommandQueue) -> Vec<Command> {
+        let state = queue.state.lock().unwrap();
+        state
+            .pending
+            .iter()
+            .filter_map(|outgoing| match outgoing {
+                Outgoing::Command(command, _) => Some(*command),
+                Outgoing::Request(_) => None,
+            })
+            .collect()
+    }
+
+    fn mark_sent(queue: &CommandQueue, command: Command, at: Instant) {
+        let mut state = queue.state.lock().unwrap();
+        state.pending.clear();
+        state.last_sent.insert(command.kind(), (command, at));
+    }
+
+    #[test]
+    fn coalesces_commands_of_the_same_kind() {
+        let queue = CommandQueue::new();
+        let up = Command::CameraMoveVertical(VerticalDirection::Up);
+
+        queue.push(FORWARD_LEFT).unwrap();
+        queue.push(up).unwrap();
+        queue.push(FORWARD).unwrap();
+        assert_eq!(pending(&queue), [FORWARD, up]);
+
+        // the steer stop keeps the direction of the drive it replaces
+        queue.push(FORWARD_LEFT).unwrap();
+        queue.push(Command::SteerStop(Speed::Slow)).unwrap();
+        assert_eq!(pending(&queue), [FORWARD, up]);
+    }
+
+    #[test]
+    fn drops_commands_that_change_nothing() {
+        let queue = CommandQueue::new();
+        mark_sent(&queue, FORWARD, Instant::now());
+
+        queue.push(FORWARD).unwrap();
+        assert!(pending(&queue).is_empty());
+
+        // a queued change that is undone before it was sent
+        queue.push(FORWARD_LEFT).unwrap();
+        queue.push(FORWARD).unwrap();
+        assert!(pending(&queue).is_empty());
+    }
+
+    #[test]
+    fn keeps_order_while_waiting_for_the_minimum_interval() {
+        let queue = CommandQueue::new();
+        let sent = Instant::now();
+        mark_sent(&queue, FORWARD, sent);
+
+        // ready on their own, but queued behind a drive that has to wait
+        queue.push(FORWARD_LEFT).unwrap();
+        queue.push_request(Request::video_stop()).unwrap();
+        queue.push(Command::Brightness(120)).unwrap();
+
+        let mut state = queue.state.lock().unwrap();
+        assert_eq!(
+            CommandQueue::next_ready(&state),
+            Err(Some(sent + min_interval(CommandKind::Drive)))
+        );
+
+        let long_ago = sent - Duration::from_secs(1);
+        state
+            .last_sent
+            .insert(CommandKind::Drive, (FORWARD, long_ago));
+        assert_eq!(CommandQueue::next_ready(&state), Ok(0));
+
+        state.pending.pop_front();
+        assert!(matches!(state.pending[0], Outgoing::Request(_)));
+        assert_eq!(CommandQueue::next_ready(&state), Ok(0));
+
+        state.pending.clear();
+        assert_eq!(CommandQueue::next_ready(&state), Err(None));
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::TcpStream,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{debug, trace, warn};
+
+use super::{metrics::Metrics, request::Request};
+
+/// How long to wait for the reply to a request sent after the handshake.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Routes requests arriving on the command socket to whoever is waiting for their opcode.
+#[derive(Default)]
+pub(crate) struct Replies {
+    waiting: Mutex<HashMap<u8, VecDeque<Sender<Request>>>>,
+    subscribed: Mutex<HashMap<u8, Sender<Request>>>,
+}
+
+impl Replies {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Registers interest in the next request with `opcode`. Must be called before sending the
+    /// request that triggers the reply. Concurrent waiters for the same opcode get the replies in
+    /// the order they registered.
+    pub fn expect(&self, opcode: u8) -> Receiver<Request> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.waiting
+            .lock()
+            .unwrap()
+            .entry(opcode)
+            .or_default()
+            .push_back(tx);
+        rx
+    }
+
+    /// Receives every unsolicited request with one of `opcodes` until the socket closes.
+    pub fn subscribe(&self, opcodes: &[u8]) -> Receiver<Request> {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let mut subscribed = self.subscribed.lock().unwrap();
+        for opcode in opcodes {
+            subscribed.insert(*opcode, tx.clone());
+        }
+
+        rx
+    }
+
+    fn dispatch(&self, request: Request) {
+        let mut request = request;
+        if let Some(waiters) = self.waiting.lock().unwrap().get_mut(&request.id) {
+            // waiters that gave up have dropped their receiver, the reply goes to the next one
+            while let Some(tx) = waiters.pop_front() {
+                match tx.send(request) {
+                    Ok(()) => return,
+                    Err(e) => request = e.0,
+                }
+            }
+        }
+
+        match self.subscribed.lock().unwrap().get(&request.id) {
+            Some(tx) => {
+                let _ = tx.send(request);
+            }
+            None => trace!("unhandled request on command socket: {request:?}"),
+        }
+    }
+}
+
+/// Waits for a reply registered with [`Replies::expect`].
+pub(crate) fn wait_reply(reply: Receiver<Request>, what: &str) -> anyhow::Result<Request> {
+    reply
+        .recv_timeout(REPLY_TIMEOUT)
+        .map_err(|e| anyhow::anyhow!("no reply to {what}: {e}"))
+}
+
+pub(crate) fn spawn_reader(
+    replies: Arc<Replies>,
+    mut socket: TcpStream,
+    metrics: Arc<Metrics>,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        match Request::read_from(&mut socket) {
+            Ok(request) => {
+                metrics.record_received();
+                replies.dispatch(request);
+            }
+            Err(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(e) => {
+                    debug!("command reader stopped: {e}");
+                    // dropping the senders wakes up everyone still waiting
+                    replies.waiting.lock().unwrap().clear();
+                    replies.subscribed.lock().unwrap().clear();
+                    break;
+                }
+                None => warn!("invalid request on command socket: {e}"),
+            },
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_replies_to_waiters_in_order() {
+        let replies = Replies::new();
+        let first = replies.expect(1);
+        let gave_up = replies.expect(1);
+        let second = replies.expect(1);
+        drop(gave_up);
+
+        replies.dispatch(Request::from_command_byte(1, [10]));
+        replies.dispatch(Request::from_command_byte(1, [20]));
+        replies.dispatch(Request::from_command_byte(1, [30]));
+
+        assert_eq!(first.try_recv().unwrap().bytes, [10]);
+        assert_eq!(second.try_recv().unwrap().bytes, [20]);
+        assert!(first.try_recv().is_err());
+        assert!(second.try_recv().is_err());
+    }
+}
@@ -1,58 +1,302 @@
+use std::io::Read;
+
+/// Length of the `MO_` header preceding every payload.
+pub const HEADER_LEN: usize = 23;
+/// Longest payload [`Request::read_from`] accepts, far more than any command reply.
+pub const MAX_PAYLOAD_LEN: u32 = 64 * 1024;
+
+const MAGIC: [u8; 3] = [0x4D, 0x4F, 0x5F];
+
+/// Channel byte (`MO_O`) of requests and replies on the command socket.
+pub const COMMAND_CHANNEL: u8 = 0x4F;
+/// Channel byte (`MO_V`) of requests and packets on the media socket.
+pub const MEDIA_CHANNEL: u8 = 0x56;
+/// Channel byte (`MO_I`) of the UDP discovery probe and its replies.
+pub const DISCOVERY_CHANNEL: u8 = 0x49;
+
+pub mod opcode {
+    pub const LOGIN_REQUEST: u8 = 0;
+    pub const LOGIN_REPLY: u8 = 1;
+    pub const VERIFY_REQUEST: u8 = 2;
+    pub const VERIFY_REPLY: u8 = 3;
+    pub const VIDEO_START_REQUEST: u8 = 4;
+    pub const VIDEO_START_REPLY: u8 = 5;
+    pub const VIDEO_END: u8 = 6;
+    pub const AUDIO_START_REQUEST: u8 = 8;
+    pub const AUDIO_START_REPLY: u8 = 9;
+    pub const AUDIO_END: u8 = 10;
+    pub const CAMERA_CONTROL: u8 = 14;
+    pub const CAMERA_PARAMS_REQUEST: u8 = 15;
+    pub const CAMERA_PARAMS_REPLY: u8 = 16;
+    pub const CAMERA_SELECT: u8 = 19;
+    pub const ALARM_NOTIFY: u8 = 25;
+    pub const WIFI_CONFIG_GET_REQUEST: u8 = 32;
+    pub const WIFI_CONFIG_GET_REPLY: u8 = 33;
+    pub const WIFI_CONFIG_SET_REQUEST: u8 = 34;
+    pub const WIFI_CONFIG_SET_REPLY: u8 = 35;
+    pub const DEVICE_CONTROL: u8 = 0xFA;
+    pub const STATUS_REPORT: u8 = 0xFB;
+    pub const HEARTBEAT: u8 = 0xFF;
+
+    pub const COMMAND_CHANNEL: &[u8] = &[
+        LOGIN_REQUEST,
+        LOGIN_REPLY,
+        VERIFY_REQUEST,
+        VERIFY_REPLY,
+        VIDEO_START_REQUEST,
+        VIDEO_START_REPLY,
+        VIDEO_END,
+        AUDIO_START_REQUEST,
+        AUDIO_START_REPLY,
+        AUDIO_END,
+        CAMERA_CONTROL,
+        CAMERA_PARAMS_REQUEST,
+        CAMERA_PARAMS_REPLY,
+        CAMERA_SELECT,
+        ALARM_NOTIFY,
+        WIFI_CONFIG_GET_REQUEST,
+        WIFI_CONFIG_GET_REPLY,
+        WIFI_CONFIG_SET_REQUEST,
+        WIFI_CONFIG_SET_REPLY,
+        DEVICE_CONTROL,
+        STATUS_REPORT,
+        HEARTBEAT,
+    ];
+
+    pub const MEDIA_LOGIN: u8 = 0;
+    pub const VIDEO_DATA: u8 = 1;
+    pub const AUDIO_DATA: u8 = 2;
+
+    pub const MEDIA_CHANNEL: &[u8] = &[MEDIA_LOGIN, VIDEO_DATA, AUDIO_DATA];
+
+    pub const SEARCH_REQUEST: u8 = 0;
+    pub const SEARCH_REPLY: u8 = 1;
+
+    pub const DISCOVERY_CHANNEL: &[u8] = &[SEARCH_REQUEST, SEARCH_REPLY];
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Request {
     pub c: u8,
     pub id: u8,
-    pub n: u8,
+    /// Payload length, a 4-byte field on the wire.
+    pub n: u32,
     pub bytes: Vec<u8>,
 }
 
 impl Request {
     pub fn heartbeat() -> Self {
-        Self::from_command_byte(0xFF, &[])
+        Self::from_command_byte(opcode::HEARTBEAT, [])
     }
 
     pub fn video_start() -> Self {
-        Self::from_u32s(4, [1])
+        Self::from_u32s(opcode::VIDEO_START_REQUEST, [1])
+    }
+
+    pub fn video_stop() -> Self {
+        Self::from_command_byte(opcode::VIDEO_END, [])
     }
 
     pub fn audio_start() -> Self {
-        Self::from_command_byte(8, [1])
+        Self::from_command_byte(opcode::AUDIO_START_REQUEST, [1])
+    }
+
+    pub fn audio_stop() -> Self {
+        Self::from_command_byte(opcode::AUDIO_END, [])
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        [
-            0x4D, 0x4F, 0x5F, self.c, self.id, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, self.n, 0, 0, 0, 0, 0,
-            0, 0,
-        ]
-        .into_iter()
-        .chain(self.bytes.iter().copied())
-        .collect::<Vec<_>>()
+        let mut bytes = vec![0; HEADER_LEN];
+        bytes[..3].copy_from_slice(&MAGIC);
+        bytes[3] = self.c;
+        bytes[4] = self.id;
+        bytes[15..19].copy_from_slice(&self.n.to_le_bytes());
+        bytes.extend_from_slice(&self.bytes);
+
+        bytes
     }
 
-    pub fn from_command_byte<'a, B: AsRef<[u8]>>(id: u8, bytes: B) -> Self {
+    /// Parses one complete request, validating magic, channel, opcode and length.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        if bytes.len() < HEADER_LEN {
+            anyhow::bail!(
+                "request too short: {} bytes, header alone is {HEADER_LEN}",
+                bytes.len()
+            );
+        }
+
+        let (header, payload) = bytes.split_at(HEADER_LEN);
+        let (c, id, n) = parse_header(header.try_into().unwrap())?;
+
+        if payload.len() != n as usize {
+            anyhow::bail!(
+                "payload length mismatch: header says {n} bytes, got {}",
+                payload.len()
+            );
+        }
+
+        validate(c, id, payload)
+    }
+
+    /// Reads and validates one request from a stream. The payload is consumed even if the request
+    /// turns out to be invalid, so the stream stays in sync unless the header itself is broken.
+    /// Payloads over [`MAX_PAYLOAD_LEN`] are skipped without buffering them.
+    pub fn read_from<R: Read>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        reader.read_exact(&mut header)?;
+
+        let (c, id, n) = parse_header(&header)?;
+
+        if n > MAX_PAYLOAD_LEN {
+            std::io::copy(&mut reader.take(n as u64), &mut std::io::sink())?;
+            anyhow::bail!("payload of {n} bytes is too long, at most {MAX_PAYLOAD_LEN} allowed");
+        }
+
+        let mut bytes = vec![0; n as usize];
+        reader.read_exact(&mut bytes)?;
+
+        validate(c, id, &bytes)
+    }
+
+    pub fn from_command_byte<B: AsRef<[u8]>>(id: u8, bytes: B) -> Self {
         Self {
-            c: 0x4F,
+            c: COMMAND_CHANNEL,
             id,
-            n: bytes.as_ref().len() as u8,
+            n: bytes.as_ref().len() as u32,
             bytes: bytes.as_ref().to_vec(),
         }
     }
 
     pub fn from_device_control(a: u8, b: u8) -> Self {
-        Self::from_command_byte(0xFA, &[a, b])
+        Self::from_command_byte(opcode::DEVICE_CONTROL, [a, b])
     }
 
     pub fn from_camera_request(request: u8) -> Self {
-        Self::from_command_byte(14, [request])
+        Self::from_command_byte(opcode::CAMERA_CONTROL, [request])
     }
 
-    pub fn from_u32s<'a, B: AsRef<[u32]>>(id: u8, ints: B) -> Self {
+    pub fn from_camera_parameter(param: u8, value: u8) -> Self {
+        Self::from_command_byte(opcode::CAMERA_CONTROL, [param, value])
+    }
+
+    pub fn from_u32s<B: AsRef<[u32]>>(id: u8, ints: B) -> Self {
         Self::from_command_byte(
             id,
             ints.as_ref()
-                .into_iter()
+                .iter()
                 .flat_map(|i| i.to_le_bytes())
                 .collect::<Vec<_>>(),
         )
     }
 }
+
+/// Returns channel, raw opcode and payload length of a header.
+fn parse_header(header: &[u8; HEADER_LEN]) -> anyhow::Result<(u8, u16, u32)> {
+    if header[..3] != MAGIC {
+        anyhow::bail!("invalid magic {:02X?}, expected MO_", &header[..3]);
+    }
+
+    let c = header[3];
+    let id = u16::from_le_bytes([header[4], header[5]]);
+    let n = u32::from_le_bytes(header[15..19].try_into().unwrap());
+
+    Ok((c, id, n))
+}
+
+fn validate(c: u8, id: u16, bytes: &[u8]) -> anyhow::Result<Request> {
+    let opcodes = match c {
+        COMMAND_CHANNEL => opcode::COMMAND_CHANNEL,
+        MEDIA_CHANNEL => opcode::MEDIA_CHANNEL,
+        DISCOVERY_CHANNEL => opcode::DISCOVERY_CHANNEL,
+        _ => anyhow::bail!("unknown channel {c:#04X}"),
+    };
+
+    let id = u8::try_from(id)
+        .ok()
+        .filter(|id| opcodes.contains(id))
+        .ok_or_else(|| anyhow::anyhow!("unknown opcode {id} on channel {c:#04X}"))?;
+
+    let n = u32::try_from(bytes.len())
+        .map_err(|_| anyhow::anyhow!("payload of {} bytes is too long", bytes.len()))?;
+
+    Ok(Request {
+        c,
+        id,
+        n,
+        bytes: bytes.to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let request = Request::from_u32s(opcode::VERIFY_REQUEST, [1, 2, 3, 4]);
+        let bytes = request.to_bytes();
+
+        assert_eq!(Request::from_bytes(&bytes).unwrap(), request);
+        assert_eq!(Request::read_from(&mut bytes.as_slice()).unwrap(), request);
+    }
+
+    #[test]
+    fn media_round_trip() {
+        // video payloads are far longer than a byte can count
+        let payload = (0..5000).map(|i| i as u8).collect::<Vec<_>>();
+        let request = Request {
+            c: MEDIA_CHANNEL,
+            id: opcode::VIDEO_DATA,
+            n: payload.len() as u32,
+            bytes: payload,
+        };
+        let bytes = request.to_bytes();
+
+        assert_eq!(bytes[15..19], 5000u32.to_le_bytes());
+        assert_eq!(Request::from_bytes(&bytes).unwrap(), request);
+        assert_eq!(Request::read_from(&mut bytes.as_slice()).unwrap(), request);
+    }
+
+    #[test]
+    fn skips_oversized_payloads() {
+        let mut huge = Request::heartbeat().to_bytes();
+        huge[15..19].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Request::read_from(&mut huge.as_slice()).is_err());
+
+        // the stream stays in sync behind a long payload
+        let mut long = Request::heartbeat().to_bytes();
+        long[15..19].copy_from_slice(&(MAX_PAYLOAD_LEN + 1).to_le_bytes());
+        long.resize(HEADER_LEN + MAX_PAYLOAD_LEN as usize + 1, 0);
+        long.extend(Request::heartbeat().to_bytes());
+
+        let mut reader = long.as_slice();
+        assert!(Request::read_from(&mut reader).is_err());
+        assert_eq!(
+            Request::read_from(&mut reader).unwrap(),
+            Request::heartbeat()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let valid = Request::from_camera_request(94).to_bytes();
+
+        assert!(Request::from_bytes(&valid[..HEADER_LEN - 1]).is_err());
+
+        let mut magic = valid.clone();
+        magic[1] = b'X';
+        assert!(Request::from_bytes(&magic).is_err());
+
+        let mut channel = valid.clone();
+        channel[3] = 0x41;
+        assert!(Request::from_bytes(&channel).is_err());
+
+        let mut unknown = valid.clone();
+        unknown[4] = 0x42;
+        assert!(Request::from_bytes(&unknown).is_err());
+
+        let mut length = valid.clone();
+        length[15] = 2;
+        assert!(Request::from_bytes(&length).is_err());
+    }
+}
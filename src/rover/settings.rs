@@ -0,0 +1,47 @@
+use super::{request::Request, Orientation, Resolution};
+
+/// Image settings as reported by the camera.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CameraSettings {
+    pub resolution: Resolution,
+    pub brightness: u8,
+    pub contrast: u8,
+    /// Power line frequency the exposure is tuned to, 50 or 60 Hz.
+    pub power_frequency: u8,
+    pub orientation: Orientation,
+    pub frame_rate: u8,
+}
+
+impl Default for CameraSettings {
+    /// Factory settings of the rover.
+    fn default() -> Self {
+        Self {
+            resolution: Resolution::Vga,
+            brightness: 128,
+            contrast: 3,
+            power_frequency: 50,
+            orientation: Orientation::default(),
+            frame_rate: 15,
+        }
+    }
+}
+
+impl CameraSettings {
+    /// Payload layout: resolution, brightness, contrast, power line mode, orientation, frame rate.
+    pub(crate) fn decode(reply: &Request) -> anyhow::Result<Self> {
+        let &[resolution, brightness, contrast, mode, orientation, frame_rate] =
+            reply.bytes.as_slice()
+        else {
+            anyhow::bail!("invalid camera settings {:02X?}", reply.bytes);
+        };
+
+        Ok(Self {
+            resolution: Resolution::decode(resolution)?,
+            brightness,
+            contrast,
+            power_frequency: if mode == 0 { 50 } else { 60 },
+            orientation: Orientation::decode(orientation)?,
+            frame_rate,
+        })
+    }
+}
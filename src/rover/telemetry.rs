@@ -0,0 +1,164 @@
+//! Battery and link state, decoded from the status reports and alarms the rover sends on the
+//! command socket without being asked.
+
+use std::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use log::{debug, warn};
+
+use super::request::{opcode, Request};
+
+/// Battery level in percent at or below which [`Telemetry::is_low_battery`] is true.
+pub const LOW_BATTERY_LEVEL: u8 = 20;
+
+const ALARM_LOW_BATTERY: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alarm {
+    LowBattery,
+    Other(u8),
+}
+
+/// One telemetry update. Fields the rover did not report are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Telemetry {
+    /// Battery level in percent.
+    pub battery_level: Option<u8>,
+    pub charging: Option<bool>,
+    /// WiFi signal strength in percent.
+    pub signal_strength: Option<u8>,
+    pub alarm: Option<Alarm>,
+}
+
+impl Telemetry {
+    pub fn decode(request: &Request) -> anyhow::Result<Self> {
+        match (request.id, request.bytes.as_slice()) {
+            (opcode::STATUS_REPORT, [battery, rest @ ..]) => Ok(Self {
+                battery_level: Some((*battery).min(100)),
+                charging: rest.first().map(|flags| flags & 1 != 0),
+                signal_strength: rest.get(1).map(|signal| (*signal).min(100)),
+                alarm: None,
+            }),
+            (opcode::ALARM_NOTIFY, [alarm, ..]) => Ok(Self {
+                alarm: Some(match *alarm {
+                    ALARM_LOW_BATTERY => Alarm::LowBattery,
+                    other => Alarm::Other(other),
+                }),
+                ..Self::default()
+            }),
+            (id, bytes) => anyhow::bail!("request {id} with payload {bytes:02X?} is not telemetry"),
+        }
+    }
+
+    /// Takes over every field that is present in `update`. An alarm lasts until the next status
+    /// report, which always carries the battery level.
+    pub fn merge(&mut self, update: &Telemetry) {
+        self.battery_level = update.battery_level.or(self.battery_level);
+        self.charging = update.charging.or(self.charging);
+        self.signal_strength = update.signal_strength.or(self.signal_strength);
+        self.alarm = match update.battery_level {
+            Some(_) => update.alarm,
+            None => update.alarm.or(self.alarm),
+        };
+    }
+
+    pub fn is_low_battery(&self) -> bool {
+        self.charging != Some(true)
+            && (self.alarm == Some(Alarm::LowBattery)
+                || self
+                    .battery_level
+                    .is_some_and(|level| level <= LOW_BATTERY_LEVEL))
+    }
+}
+
+/// Keeps the merged latest telemetry and forwards every update to all subscribers.
+#[derive(Default)]
+pub(crate) struct TelemetryHub {
+    latest: Mutex<Telemetry>,
+    subscribers: Mutex<Vec<Sender<Telemetry>>>,
+}
+
+impl TelemetryHub {
+    pub fn spawn(requests: Receiver<Request>) -> (Arc<Self>, JoinHandle<()>) {
+        let hub = Arc::new(Self::default());
+
+        let thread = {
+            let hub = hub.clone();
+            std::thread::spawn(move || {
+                for request in requests {
+                    match Telemetry::decode(&request) {
+                        Ok(update) => hub.publish(update),
+                        Err(e) => warn!("invalid telemetry: {e}"),
+                    }
+                }
+            })
+        };
+
+        (hub, thread)
+    }
+
+    pub fn latest(&self) -> Telemetry {
+        *self.latest.lock().unwrap()
+    }
+
+    pub fn subscribe(&self) -> Receiver<Telemetry> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    fn publish(&self, update: Telemetry) {
+        debug!("telemetry: {update:?}");
+
+        self.latest.lock().unwrap().merge(&update);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(update).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_report_clears_alarm() {
+        let status = |battery, flags| {
+            Telemetry::decode(&Request::from_command_byte(
+                opcode::STATUS_REPORT,
+                [battery, flags, 70],
+            ))
+            .unwrap()
+        };
+        let alarm = Telemetry::decode(&Request::from_command_byte(
+            opcode::ALARM_NOTIFY,
+            [ALARM_LOW_BATTERY],
+        ))
+        .unwrap();
+
+        let mut telemetry = Telemetry::default();
+        telemetry.merge(&status(50, 0));
+        telemetry.merge(&alarm);
+        assert_eq!(telemetry.alarm, Some(Alarm::LowBattery));
+        assert_eq!(telemetry.battery_level, Some(50));
+        assert!(telemetry.is_low_battery());
+
+        telemetry.merge(&status(80, 1));
+        assert_eq!(
+            telemetry,
+            Telemetry {
+                battery_level: Some(80),
+                charging: Some(true),
+                signal_strength: Some(70),
+                alarm: None,
+            }
+        );
+        assert!(!telemetry.is_low_battery());
+    }
+}
@@ -0,0 +1,579 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    duration,
+    patrol::{Patrol, PatrolConfig},
+    queue::CommandQueue,
+    Command, HorizontalDirection, VerticalDirection,
+};
+
+const STEP_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Positions closer than the turret moves in this time count as reached. Covers the delay of
+/// the command queue between starting and stopping a movement, which would otherwise overshoot.
+const TOLERANCE_TIME: Duration = Duration::from_millis(60);
+
+/// How much longer than a full sweep homing keeps moving, to make sure the end stops are reached
+/// from anywhere.
+const HOMING_OVERRUN: f32 = 1.25;
+
+/// Estimated turret orientation. Both axes range from -1 to 1 with the turret centered at 0,
+/// `pan` grows to the right and `tilt` upwards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TurretPosition {
+    pub pan: f32,
+    pub tilt: f32,
+}
+
+impl TurretPosition {
+    pub const CENTER: Self = Self {
+        pan: 0.0,
+        tilt: 0.0,
+    };
+
+    /// Checks that both axes are numbers, out of range values are clamped when moving.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.pan.is_finite() || !self.tilt.is_finite() {
+            anyhow::bail!("invalid turret position {self:?}");
+        }
+        Ok(())
+    }
+}
+
+/// Time the turret needs to move from one end stop to the other. The position is estimated
+/// from these, nothing measures them, so the defaults should be replaced with values timed by
+/// hand for each rover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurretTravel {
+    #[serde(with = "duration")]
+    pub pan_travel: Duration,
+    #[serde(with = "duration")]
+    pub tilt_travel: Duration,
+}
+
+impl TurretTravel {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.pan_travel.is_zero() || self.tilt_travel.is_zero() {
+            anyhow::bail!("turret travel times must be longer than zero: {self:?}");
+        }
+        Ok(())
+    }
+}
+
+impl Default for TurretTravel {
+    fn default() -> Self {
+        Self {
+            pan_travel: Duration::from_secs(6),
+            tilt_travel: Duration::from_secs(3),
+        }
+    }
+}
+
+/// Named turret positions, stored as TOML.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TurretPresets {
+    #[serde(flatten)]
+    pub presets: BTreeMap<String, TurretPosition>,
+}
+
+impl TurretPresets {
+    /// Loads presets from `path`, starting out empty if the file does not exist yet.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let presets: Self = match std::fs::read_to_string(path) {
+            Ok(content) => toml::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => anyhow::bail!("failed to read {}: {e}", path.display()),
+        };
+
+        for (name, position) in &presets.presets {
+            position
+                .validate()
+                .map_err(|e| anyhow::anyhow!("turret preset {name:?}: {e}"))?;
+        }
+
+        Ok(presets)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, toml::to_string_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("failed to write {}: {e}", path.display()))
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<TurretPosition> {
+        self.presets
+            .get(name)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("no turret preset named {name:?}"))
+    }
+
+    pub fn insert(&mut self, name: impl Into<String>, position: TurretPosition) {
+        self.presets.insert(name.into(), position);
+    }
+}
+
+/// Dead reckoning of the turret position from the movement commands written to the rover, and
+/// a thread that moves the turret to a requested position.
+///
+/// There is no position feedback, so the estimate starts out centered and drifts with every
+/// movement until [`home`](Self::home) drives into the end stops.
+pub(crate) struct Turret {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<TurretState>,
+    changed: Condvar,
+}
+
+struct TurretState {
+    estimate: Estimate,
+    job: Option<Job>,
+    patrol: Option<Patrol>,
+    shutdown: bool,
+}
+
+struct Estimate {
+    position: TurretPosition,
+    horizontal: HorizontalDirection,
+    vertical: VerticalDirection,
+    since: Instant,
+    travel: TurretTravel,
+}
+
+#[derive(Clone, Copy)]
+enum Job {
+    Goto(TurretPosition),
+    Home { until: Instant },
+}
+
+impl Turret {
+    pub fn spawn(commands: Arc<CommandQueue>) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(TurretState {
+                estimate: Estimate {
+                    position: TurretPosition::CENTER,
+                    horizontal: HorizontalDirection::Neutral,
+                    vertical: VerticalDirection::Neutral,
+                    since: Instant::now(),
+                    travel: TurretTravel::default(),
+                },
+                job: None,
+                patrol: None,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = shared.clone();
+            std::thread::spawn(move || turret_loop(&shared, &commands))
+        };
+
+        Self {
+            shared,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns a callback for the command writer that feeds every written command into the
+    /// estimate.
+    pub fn observer(&self) -> impl Fn(&Command) + Send + 'static {
+        let shared = self.shared.clone();
+        move |command| shared.state.lock().unwrap().estimate.observe(command)
+    }
+
+    pub fn position(&self) -> TurretPosition {
+        self.shared.state.lock().unwrap().estimate.current()
+    }
+
+    pub fn set_travel(&self, travel: TurretTravel) -> anyhow::Result<()> {
+        travel.validate()?;
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.estimate.settle();
+        state.estimate.travel = travel;
+
+        Ok(())
+    }
+
+    pub fn goto(&self, position: TurretPosition) -> anyhow::Result<()> {
+        position.validate()?;
+
+        let position = TurretPosition {
+            pan: position.pan.clamp(-1.0, 1.0),
+            tilt: position.tilt.clamp(-1.0, 1.0),
+        };
+        self.start(Job::Goto(position));
+
+        Ok(())
+    }
+
+    /// Drives into the lower left end stops, where the position is known, then centers.
+    pub fn home(&self) {
+        let travel = self.shared.state.lock().unwrap().estimate.travel;
+        let travel = travel.pan_travel.max(travel.tilt_travel);
+        self.start(Job::Home {
+            until: Instant::now() + travel.mul_f32(HOMING_OVERRUN),
+        });
+    }
+
+    pub fn start_patrol(&self, config: PatrolConfig) -> anyhow::Result<()> {
+        config.validate()?;
+
+        let mut state = self.shared.state.lock().unwrap();
+        let tilt = state.estimate.current().tilt;
+        state.job = None;
+        state.patrol = Some(Patrol::new(config, tilt));
+        self.shared.changed.notify_all();
+
+        Ok(())
+    }
+
+    pub fn is_patrolling(&self) -> bool {
+        self.shared.state.lock().unwrap().patrol.is_some()
+    }
+
+    /// Abandons the current movement and patrol. Does not stop the motors.
+    pub fn stop(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.job = None;
+        state.patrol = None;
+        self.shared.changed.notify_all();
+    }
+
+    /// The operator took over the turret: abandons the current movement and pauses the patrol.
+    /// Does not stop the motors, that is up to the operator.
+    pub fn take_over(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.job = None;
+        if let Some(patrol) = &mut state.patrol {
+            patrol.pause();
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// Waits until the turret has reached its position or `timeout` elapsed, returns whether it
+    /// is idle.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.job.is_some())
+            .unwrap();
+
+        state.job.is_none()
+    }
+
+    fn start(&self, job: Job) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.job = Some(job);
+        state.patrol = None;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for Turret {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Estimate {
+    fn observe(&mut self, command: &Command) {
+        match *command {
+            Command::CameraMoveHorizontal(direction) => {
+                self.settle();
+                self.horizontal = direction;
+            }
+            Command::CameraMoveVertical(direction) => {
+                self.settle();
+                self.vertical = direction;
+            }
+            _ => {}
+        }
+    }
+
+    /// Folds the movement since the last change into `position`.
+    fn settle(&mut self) {
+        self.position = self.current();
+        self.since = Instant::now();
+    }
+
+    fn current(&self) -> TurretPosition {
+        let elapsed = self.since.elapsed();
+        let pan = match self.horizontal {
+            HorizontalDirection::Left => -1.0,
+            HorizontalDirection::Neutral => 0.0,
+            HorizontalDirection::Right => 1.0,
+        };
+        let tilt = match self.vertical {
+            VerticalDirection::Down => -1.0,
+            VerticalDirection::Neutral => 0.0,
+            VerticalDirection::Up => 1.0,
+        };
+
+        // the full range of 2 is covered in one travel time
+        TurretPosition {
+            pan: (self.position.pan
+                + pan * 2.0 * elapsed.as_secs_f32() / self.travel.pan_travel.as_secs_f32())
+            .clamp(-1.0, 1.0),
+            tilt: (self.position.tilt
+                + tilt * 2.0 * elapsed.as_secs_f32() / self.travel.tilt_travel.as_secs_f32())
+            .clamp(-1.0, 1.0),
+        }
+    }
+
+    fn tolerance(travel: Duration) -> f32 {
+        2.0 * TOLERANCE_TIME.as_secs_f32() / travel.as_secs_f32()
+    }
+}
+
+/// Directions that move the turret from `current` towards `target`, neutral on axes that are
+/// already within the tolerance.
+fn heading(
+    current: TurretPosition,
+    target: TurretPosition,
+    travel: TurretTravel,
+) -> (HorizontalDirection, VerticalDirection) {
+    let delta = target.pan - current.pan;
+    let horizontal = if delta.abs() < Estimate::tolerance(travel.pan_travel) {
+        HorizontalDirection::Neutral
+    } else if delta < 0.0 {
+        HorizontalDirection::Left
+    } else {
+        HorizontalDirection::Right
+    };
+
+    let delta = target.tilt - current.tilt;
+    let vertical = if delta.abs() < Estimate::tolerance(travel.tilt_travel) {
+        VerticalDirection::Neutral
+    } else if delta < 0.0 {
+        VerticalDirection::Down
+    } else {
+        VerticalDirection::Up
+    };
+
+    (horizontal, vertical)
+}
+
+fn turret_loop(shared: &Shared, commands: &CommandQueue) {
+    let mut state = shared.state.lock().unwrap();
+
+    loop {
+        if state.shutdown {
+            break;
+        }
+
+        let Some(job) = state.job else {
+            match state.patrol.as_mut().map(Patrol::next) {
+                Some(Ok(target)) => state.job = Some(Job::Goto(target)),
+                Some(Err(resume_at)) => {
+                    let timeout = resume_at.saturating_duration_since(Instant::now());
+                    state = shared.changed.wait_timeout(state, timeout).unwrap().0;
+                }
+                None => state = shared.changed.wait(state).unwrap(),
+            }
+            continue;
+        };
+
+        let (horizontal, vertical) = match job {
+            Job::Goto(target) => {
+                let (horizontal, vertical) =
+                    heading(state.estimate.current(), target, state.estimate.travel);
+
+                if horizontal == HorizontalDirection::Neutral
+                    && vertical == VerticalDirection::Neutral
+                {
+                    info!("turret reached {target:?}");
+                    state.job = None;
+                    if let Some(patrol) = &mut state.patrol {
+                        patrol.arrived();
+                    }
+                    shared.changed.notify_all();
+                }
+
+                (horizontal, vertical)
+            }
+            Job::Home { until } if Instant::now() < until => {
+                (HorizontalDirection::Left, VerticalDirection::Down)
+            }
+            Job::Home { .. } => {
+                info!("turret homed, centering");
+                state.estimate.settle();
+                state.estimate.position = TurretPosition {
+                    pan: -1.0,
+                    tilt: -1.0,
+                };
+                state.job = Some(Job::Goto(TurretPosition::CENTER));
+
+                (HorizontalDirection::Neutral, VerticalDirection::Neutral)
+            }
+        };
+
+        // the queue drops commands that would not change anything
+        let result = commands
+            .push(Command::CameraMoveHorizontal(horizontal))
+            .and_then(|()| commands.push(Command::CameraMoveVertical(vertical)));
+        if let Err(e) = result {
+            error!("turret movement failed: {e}");
+            state.job = None;
+            state.patrol = None;
+            shared.changed.notify_all();
+        }
+
+        state = shared.changed.wait_timeout(state, STEP_INTERVAL).unwrap().0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRAVEL: TurretTravel = TurretTravel {
+        pan_travel: Duration::from_secs(4),
+        tilt_travel: Duration::from_secs(2),
+    };
+
+    fn moving(horizontal: HorizontalDirection, vertical: VerticalDirection) -> Estimate {
+        Estimate {
+            position: TurretPosition::CENTER,
+            horizontal,
+            vertical,
+            since: Instant::now(),
+            travel: TRAVEL,
+        }
+    }
+
+    fn assert_near(actual: TurretPosition, pan: f32, tilt: f32) {
+        // allows for the time the test itself takes
+        assert!((actual.pan - pan).abs() < 0.01, "{actual:?}");
+        assert!((actual.tilt - tilt).abs() < 0.01, "{actual:?}");
+    }
+
+    #[test]
+    fn estimates_the_position_from_movement_time() {
+        let mut estimate = moving(HorizontalDirection::Right, VerticalDirection::Down);
+        estimate.since -= Duration::from_millis(500);
+        // a quarter of the pan travel covers a quarter of the range of 2, half of the tilt travel
+        assert_near(estimate.current(), 0.25, -0.5);
+
+        estimate.since -= Duration::from_secs(10);
+        assert_near(estimate.current(), 1.0, -1.0);
+
+        let mut estimate = moving(HorizontalDirection::Neutral, VerticalDirection::Neutral);
+        estimate.since -= Duration::from_secs(10);
+        assert_near(estimate.current(), 0.0, 0.0);
+    }
+
+    #[test]
+    fn observing_a_movement_settles_the_previous_one() {
+        let mut estimate = moving(HorizontalDirection::Left, VerticalDirection::Neutral);
+        estimate.since -= Duration::from_secs(1);
+
+        estimate.observe(&Command::CameraMoveHorizontal(HorizontalDirection::Neutral));
+        assert_near(estimate.position, -0.5, 0.0);
+        assert_eq!(estimate.horizontal, HorizontalDirection::Neutral);
+
+        estimate.observe(&Command::CameraMoveVertical(VerticalDirection::Up));
+        assert_eq!(estimate.vertical, VerticalDirection::Up);
+
+        // other commands do not move the turret
+        estimate.observe(&Command::Brightness(10));
+        assert_eq!(estimate.vertical, VerticalDirection::Up);
+    }
+
+    #[test]
+    fn heads_towards_the_target_until_within_tolerance() {
+        let target = TurretPosition {
+            pan: 0.5,
+            tilt: -0.5,
+        };
+        assert_eq!(
+            heading(TurretPosition::CENTER, target, TRAVEL),
+            (HorizontalDirection::Right, VerticalDirection::Down)
+        );
+        assert_eq!(
+            heading(target, TurretPosition::CENTER, TRAVEL),
+            (HorizontalDirection::Left, VerticalDirection::Up)
+        );
+
+        // 60ms of a 4s pan travel are 0.03, of a 2s tilt travel 0.06
+        let close = TurretPosition {
+            pan: 0.5 - 0.02,
+            tilt: -0.5 + 0.05,
+        };
+        assert_eq!(
+            heading(close, target, TRAVEL),
+            (HorizontalDirection::Neutral, VerticalDirection::Neutral)
+        );
+        let close = TurretPosition {
+            pan: 0.5 - 0.04,
+            tilt: -0.5 + 0.05,
+        };
+        assert_eq!(
+            heading(close, target, TRAVEL),
+            (HorizontalDirection::Right, VerticalDirection::Neutral)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_travel_and_targets() {
+        assert!(TRAVEL.validate().is_ok());
+        assert!(TurretTravel {
+            pan_travel: Duration::ZERO,
+            ..TRAVEL
+        }
+        .validate()
+        .is_err());
+        assert!(TurretTravel {
+            tilt_travel: Duration::ZERO,
+            ..TRAVEL
+        }
+        .validate()
+        .is_err());
+
+        assert!(TurretPosition {
+            pan: 2.0,
+            tilt: -2.0
+        }
+        .validate()
+        .is_ok());
+        assert!(TurretPosition {
+            pan: f32::NAN,
+            tilt: 0.0
+        }
+        .validate()
+        .is_err());
+        assert!(TurretPosition {
+            pan: 0.0,
+            tilt: f32::INFINITY
+        }
+        .validate()
+        .is_err());
+
+        let presets: TurretPresets = toml::from_str("door = { pan = nan, tilt = 0.0 }").unwrap();
+        let path = std::env::temp_dir().join(format!("turret-presets-{}.toml", std::process::id()));
+        presets.save(&path).unwrap();
+        let loaded = TurretPresets::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+}
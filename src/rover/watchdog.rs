@@ -0,0 +1,162 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{error, warn};
+
+use super::{
+    queue::CommandQueue, Command, Direction, HorizontalDirection, Speed, VerticalDirection,
+};
+
+/// Default time without [`Rover::refresh_motion`](super::Rover::refresh_motion) or a motion
+/// command after which all motion is stopped.
+pub const MOTION_TIMEOUT: Duration = Duration::from_secs(1);
+
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Stops the rover when the controller goes silent while it is moving.
+pub(crate) struct Watchdog {
+    state: Arc<Mutex<MotionState>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+struct MotionState {
+    direction: Direction,
+    steer: HorizontalDirection,
+    speed: Speed,
+    turret_horizontal: HorizontalDirection,
+    turret_vertical: VerticalDirection,
+    last_refresh: Instant,
+    timeout: Duration,
+    shutdown: bool,
+}
+
+impl Watchdog {
+    pub fn spawn(commands: Arc<CommandQueue>) -> Self {
+        let state = Arc::new(Mutex::new(MotionState {
+            direction: Direction::Neutral,
+            steer: HorizontalDirection::Neutral,
+            speed: Speed::Fast,
+            turret_horizontal: HorizontalDirection::Neutral,
+            turret_vertical: VerticalDirection::Neutral,
+            last_refresh: Instant::now(),
+            timeout: MOTION_TIMEOUT,
+            shutdown: false,
+        }));
+
+        let thread = {
+            let state = state.clone();
+            std::thread::spawn(move || watchdog_loop(state, commands))
+        };
+
+        Self {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    /// Updates the tracked motion state with a command that is about to be sent.
+    pub fn track(&self, command: Command) {
+        self.state.lock().unwrap().track(command);
+    }
+
+    pub fn refresh(&self) {
+        self.state.lock().unwrap().last_refresh = Instant::now();
+    }
+
+    /// Whether the rover is driving or the turret is moving.
+    pub fn is_moving(&self) -> bool {
+        !self.state.lock().unwrap().stop_commands().is_empty()
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.state.lock().unwrap().timeout = timeout;
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().shutdown = true;
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl MotionState {
+    fn track(&mut self, command: Command) {
+        match command {
+            Command::Drive(direction, steer, speed) => {
+                self.direction = direction;
+                self.steer = steer;
+                self.speed = speed;
+            }
+            Command::SteerStop(speed) => {
+                self.steer = HorizontalDirection::Neutral;
+                self.speed = speed;
+            }
+            Command::CameraMoveHorizontal(direction) => self.turret_horizontal = direction,
+            Command::CameraMoveVertical(direction) => self.turret_vertical = direction,
+            _ => return,
+        }
+
+        self.last_refresh = Instant::now();
+    }
+
+    fn stop_commands(&self) -> Vec<Command> {
+        let mut commands = Vec::new();
+
+        if self.direction != Direction::Neutral {
+            commands.push(Command::Drive(
+                Direction::Neutral,
+                HorizontalDirection::Neutral,
+                self.speed,
+            ));
+        } else if self.steer != HorizontalDirection::Neutral {
+            commands.push(Command::SteerStop(self.speed));
+        }
+        if self.turret_horizontal != HorizontalDirection::Neutral {
+            commands.push(Command::CameraMoveHorizontal(HorizontalDirection::Neutral));
+        }
+        if self.turret_vertical != VerticalDirection::Neutral {
+            commands.push(Command::CameraMoveVertical(VerticalDirection::Neutral));
+        }
+
+        commands
+    }
+}
+
+fn watchdog_loop(state: Arc<Mutex<MotionState>>, commands: Arc<CommandQueue>) {
+    loop {
+        std::thread::sleep(CHECK_INTERVAL);
+
+        let mut state = state.lock().unwrap();
+        if state.shutdown {
+            break;
+        }
+
+        if state.last_refresh.elapsed() < state.timeout {
+            continue;
+        }
+
+        let stop_commands = state.stop_commands();
+        if stop_commands.is_empty() {
+            continue;
+        }
+
+        warn!(
+            "no motion refresh for {:?}, stopping rover",
+            state.last_refresh.elapsed()
+        );
+
+        for command in stop_commands {
+            state.track(command);
+            if let Err(e) = commands.push(command) {
+                error!("watchdog failed to send {command:?}: {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! WiFi settings of the rover, which acts as an access point out of the box but can also join
+//! an existing network.
+//!
+//! The opcodes (32 to 35) and the payload layout are not covered by any published AC13
+//! documentation, they follow the development simulator and have not been checked against a
+//! capture from real hardware yet. [`Rover::set_wifi_config`](super::Rover::set_wifi_config)
+//! therefore only writes a config after the current one decoded.
+
+use serde::{Deserialize, Serialize};
+
+use super::{device::c_string, request::Request};
+
+const SSID_LEN: usize = 33;
+const PASSPHRASE_LEN: usize = 65;
+const CONFIG_LEN: usize = 2 + SSID_LEN + PASSPHRASE_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiMode {
+    /// The rover opens its own network.
+    AccessPoint,
+    /// The rover joins an existing network.
+    Infrastructure,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiSecurity {
+    Open,
+    Wep,
+    WpaPsk,
+    Wpa2Psk,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WifiConfig {
+    pub mode: WifiMode,
+    pub ssid: String,
+    pub security: WifiSecurity,
+    pub passphrase: String,
+}
+
+impl WifiMode {
+    fn encode(self) -> u8 {
+        match self {
+            WifiMode::AccessPoint => 0,
+            WifiMode::Infrastructure => 1,
+        }
+    }
+
+    fn decode(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(WifiMode::AccessPoint),
+            1 => Ok(WifiMode::Infrastructure),
+            _ => anyhow::bail!("unknown wifi mode {byte}"),
+        }
+    }
+}
+
+impl WifiSecurity {
+    fn encode(self) -> u8 {
+        match self {
+            WifiSecurity::Open => 0,
+            WifiSecurity::Wep => 1,
+            WifiSecurity::WpaPsk => 2,
+            WifiSecurity::Wpa2Psk => 3,
+        }
+    }
+
+    fn decode(byte: u8) -> anyhow::Result<Self> {
+        match byte {
+            0 => Ok(WifiSecurity::Open),
+            1 => Ok(WifiSecurity::Wep),
+            2 => Ok(WifiSecurity::WpaPsk),
+            3 => Ok(WifiSecurity::Wpa2Psk),
+            _ => anyhow::bail!("unknown wifi security {byte}"),
+        }
+    }
+}
+
+impl WifiConfig {
+    /// Payload layout: mode, security, SSID NUL padded to 33 bytes and passphrase NUL padded to
+    /// 65 bytes, see the module docs for where it comes from.
+    pub(crate) fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        if self.ssid.is_empty() || self.ssid.len() >= SSID_LEN {
+            anyhow::bail!("ssid must have 1 to {} bytes", SSID_LEN - 1);
+        }
+        if self.passphrase.len() >= PASSPHRASE_LEN {
+            anyhow::bail!("passphrase must have at most {} bytes", PASSPHRASE_LEN - 1);
+        }
+        if self.security != WifiSecurity::Open && self.passphrase.is_empty() {
+            anyhow::bail!("{:?} requires a passphrase", self.security);
+        }
+
+        let mut bytes = vec![0; CONFIG_LEN];
+        bytes[0] = self.mode.encode();
+        bytes[1] = self.security.encode();
+        bytes[2..2 + self.ssid.len()].copy_from_slice(self.ssid.as_bytes());
+        bytes[2 + SSID_LEN..2 + SSID_LEN + self.passphrase.len()]
+            .copy_from_slice(self.passphrase.as_bytes());
+
+        Ok(bytes)
+    }
+
+    pub(crate) fn decode(reply: &Request) -> anyhow::Result<Self> {
+        let bytes = reply.bytes.as_slice();
+        if bytes.len() != CONFIG_LEN {
+            anyhow::bail!(
+                "wifi config has {} bytes, expected {CONFIG_LEN}",
+                bytes.len()
+            );
+        }
+
+        Ok(Self {
+            mode: WifiMode::decode(bytes[0])?,
+            security: WifiSecurity::decode(bytes[1])?,
+            ssid: c_string(&bytes[2..2 + SSID_LEN]),
+            passphrase: c_string(&bytes[2 + SSID_LEN..]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rover::request::opcode;
+
+    /// Wifi config reply of the simulator: header, mode and security, SSID, passphrase.
+    const REPLY: &str = "4d4f5f4f21000000000000000000006400000000000000\
+        0003\
+        524f56455200000000000000000000000000000000000000000000000000000000\
+        7365637265743132000000000000000000000000000000000000000000000000000000000000000000000000\
+        000000000000000000000000000000000000000000";
+
+    fn reply_bytes() -> Vec<u8> {
+        (0..REPLY.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&REPLY[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_and_encodes_simulator_reply() {
+        let reply = Request::from_bytes(&reply_bytes()).unwrap();
+        assert_eq!(reply.id, opcode::WIFI_CONFIG_GET_REPLY);
+
+        let config = WifiConfig::decode(&reply).unwrap();
+        assert_eq!(
+            config,
+            WifiConfig {
+                mode: WifiMode::AccessPoint,
+                ssid: "ROVER".into(),
+                security: WifiSecurity::Wpa2Psk,
+                passphrase: "secret12".into(),
+            }
+        );
+        assert_eq!(config.encode().unwrap(), reply.bytes);
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = WifiConfig {
+            mode: WifiMode::Infrastructure,
+            ssid: "x".repeat(SSID_LEN - 1),
+            security: WifiSecurity::WpaPsk,
+            passphrase: "p".repeat(PASSPHRASE_LEN - 1),
+        };
+        let request =
+            Request::from_command_byte(opcode::WIFI_CONFIG_SET_REQUEST, config.encode().unwrap());
+        assert_eq!(WifiConfig::decode(&request).unwrap(), config);
+
+        let too_long = WifiConfig {
+            ssid: "x".repeat(SSID_LEN),
+            ..config.clone()
+        };
+        assert!(too_long.encode().is_err());
+        let no_passphrase = WifiConfig {
+            passphrase: String::new(),
+            ..config
+        };
+        assert!(no_passphrase.encode().is_err());
+    }
+}
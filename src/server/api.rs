@@ -0,0 +1,564 @@
+//! HTTP/JSON control API for test harnesses.
+//!
+//! - `POST /lease {"client": "name"}` takes the control lease and returns a token identifying the
+//!   client, which every other `POST` expects as `Authorization: Bearer <token>`. Posting again
+//!   with the token renews the lease, `DELETE /lease` gives it up. Tokens of clients that lost
+//!   the lease stop working once another client registers, or the same one again. See
+//!   [`arbiter`](crate::rover::arbiter) for how the lease is shared with other operators.
+//!   `"handover": true` asks the current holder to hand over instead of failing, and
+//!   `"take_over": true` with the `"admin_token"` the server was started with takes it over.
+//! - `POST /drive {"direction": "forward", "steer": "left", "speed": "slow", "duration_ms": 500}`
+//! - `POST /turret {"horizontal": "left", "vertical": "up", "duration_ms": 300}` or
+//!   `POST /turret {"pan": 0.5, "tilt": 0}`
+//! - `POST /camera {"camera": "turret", "brightness": 128, "contrast": 3, "resolution": "vga",
+//!   "frame_rate": 15, "flip": false, "mirror": false}`, every field optional
+//! - `POST /stealth {"enabled": true}`
+//! - `POST /stop`, allowed without the lease
+//! - `GET /status`
+//!
+//! Motion with a `duration_ms` stops on its own, other motion stops after
+//! [`MOTION_TIMEOUT`](crate::rover::MOTION_TIMEOUT) unless repeated.
+
+use std::{
+    collections::HashMap,
+    io::BufReader,
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+
+use crate::{
+    relay::constant_time_eq,
+    rover::{
+        arbiter::{Operator, ViewOnly},
+        turret::TurretPosition,
+        Camera, Command, Direction, HorizontalDirection, Orientation, Resolution, Rover, Speed,
+        VerticalDirection,
+    },
+};
+
+use super::{
+    http::{self, HttpRequest},
+    listen, secret_token,
+};
+
+/// Longest motion a single request may ask for.
+const MAX_DURATION: Duration = Duration::from_secs(60);
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DriveRequest {
+    #[serde(default = "neutral_direction")]
+    direction: Direction,
+    #[serde(default = "neutral_horizontal")]
+    steer: HorizontalDirection,
+    #[serde(default = "fast")]
+    speed: Speed,
+    duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum TurretRequest {
+    Goto {
+        pan: f32,
+        tilt: f32,
+    },
+    Move {
+        #[serde(default = "neutral_horizontal")]
+        horizontal: HorizontalDirection,
+        #[serde(default = "neutral_vertical")]
+        vertical: VerticalDirection,
+        duration_ms: Option<u64>,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraRequest {
+    camera: Option<Camera>,
+    brightness: Option<u8>,
+    contrast: Option<u8>,
+    resolution: Option<Resolution>,
+    frame_rate: Option<u8>,
+    flip: Option<bool>,
+    mirror: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StealthRequest {
+    enabled: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LeaseRequest {
+    client: Option<String>,
+    #[serde(default)]
+    handover: bool,
+    #[serde(default)]
+    take_over: bool,
+    admin_token: Option<String>,
+}
+
+fn neutral_direction() -> Direction {
+    Direction::Neutral
+}
+
+fn neutral_horizontal() -> HorizontalDirection {
+    HorizontalDirection::Neutral
+}
+
+fn neutral_vertical() -> VerticalDirection {
+    VerticalDirection::Neutral
+}
+
+fn fast() -> Speed {
+    Speed::Fast
+}
+
+/// An error response.
+#[derive(Debug)]
+struct Failure {
+    status: u16,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Failure {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<ViewOnly>() {
+            Some(view_only) => Self::new(409, view_only.to_string()),
+            None => Self::new(500, e.to_string()),
+        }
+    }
+}
+
+struct Api {
+    rover: Arc<Rover>,
+    admin_token: Option<String>,
+    /// Clients by their token.
+    clients: Mutex<HashMap<String, Operator>>,
+    /// Bumped by every drive and turret request, ends timed motion that was overridden.
+    drive_generation: Arc<AtomicU64>,
+    turret_generation: Arc<AtomicU64>,
+}
+
+/// Serves the control API on `address`. Clients presenting `admin_token` are admins.
+pub fn spawn(
+    address: SocketAddr,
+    rover: Arc<Rover>,
+    admin_token: Option<String>,
+) -> anyhow::Result<JoinHandle<()>> {
+    // fills the cache /status reports the camera settings from
+    {
+        let rover = rover.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = rover.camera_settings() {
+                warn!("failed to read the camera settings: {e}");
+            }
+        });
+    }
+
+    let api = Api {
+        rover,
+        admin_token,
+        clients: Mutex::default(),
+        drive_generation: Arc::default(),
+        turret_generation: Arc::default(),
+    };
+
+    listen(address, "api", move |mut stream| {
+        let request = HttpRequest::read_from(&mut BufReader::new(&mut stream))?;
+        let (status, body) = match api.handle(&request, &stream) {
+            Ok(Value::Null) => (204, Value::Null),
+            Ok(body) => (200, body),
+            Err(failure) => {
+                if failure.status == 500 {
+                    warn!(
+                        "{} {} failed: {}",
+                        request.method, request.path, failure.message
+                    );
+                }
+                (failure.status, json!({ "error": failure.message }))
+            }
+        };
+
+        let body = match body {
+            Value::Null => Vec::new(),
+            body => serde_json::to_vec(&body)?,
+        };
+        Ok(http::respond(
+            &mut stream,
+            status,
+            "application/json",
+            &body,
+        )?)
+    })
+}
+
+impl Api {
+    fn handle(&self, request: &HttpRequest, stream: &TcpStream) -> Result<Value, Failure> {
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/status") => self.status(),
+            ("POST", "/lease") => self.acquire(request, stream),
+            ("DELETE", "/lease") => self.release(request),
+            ("POST", "/stop") => {
+                let operator = match bearer_token(request) {
+                    Some(_) => self.authorize(request)?,
+                    None => anonymous(stream)?,
+                };
+                self.stop_motion(&operator)?;
+                Ok(Value::Null)
+            }
+            ("POST", "/drive") => {
+                let operator = self.authorize(request)?;
+                self.drive(&operator, parse(request)?)
+            }
+            ("POST", "/turret") => {
+                let operator = self.authorize(request)?;
+                self.turret(&operator, parse(request)?)
+            }
+            ("POST", "/camera") => {
+                let operator = self.authorize(request)?;
+                self.camera(&operator, parse(request)?)
+            }
+            ("POST", "/stealth") => {
+                let operator = self.authorize(request)?;
+                let StealthRequest { enabled } = parse(request)?;
+                self.rover
+                    .send_command(&operator, Command::StealthMode(enabled))?;
+                Ok(Value::Null)
+            }
+            (_, "/status" | "/lease" | "/stop" | "/drive" | "/turret" | "/camera" | "/stealth") => {
+                Err(Failure::new(405, "method not allowed"))
+            }
+            _ => Err(Failure::new(404, "not found")),
+        }
+    }
+
+    fn status(&self) -> Result<Value, Failure> {
+        let info = self.rover.device_info();
+        let telemetry = self.rover.latest_telemetry();
+        let turret = self.rover.turret_position();
+        let camera = self.rover.cached_camera_settings().map(|settings| {
+            json!({
+                "resolution": settings.resolution,
+                "brightness": settings.brightness,
+                "contrast": settings.contrast,
+                "power_frequency": settings.power_frequency,
+                "frame_rate": settings.frame_rate,
+                "flip": settings.orientation.flip,
+                "mirror": settings.orientation.mirror,
+            })
+        });
+        let lease = self.rover.control_lease().map(|lease| {
+            json!({
+                "client": lease.holder.name,
+                "expires_in_ms": lease.expires_in.as_millis() as u64,
+                "handover_requested_by": lease.handover_requested_by.map(|operator| operator.name),
+            })
+        });
+
+        Ok(json!({
+            "camera_id": info.camera_id,
+            "system_version": info.system_version.to_string(),
+            "app_version": info.app_version.to_string(),
+            "battery_level": telemetry.battery_level,
+            "charging": telemetry.charging,
+            "signal_strength": telemetry.signal_strength,
+            "low_battery": telemetry.is_low_battery(),
+            "turret": turret,
+            "patrolling": self.rover.is_patrolling(),
+            "camera": camera,
+            "lease": lease,
+        }))
+    }
+
+    /// Takes the lease if it is free or expired, or renews it for its holder.
+    fn acquire(&self, request: &HttpRequest, stream: &TcpStream) -> Result<Value, Failure> {
+        let LeaseRequest {
+            client,
+            handover,
+            take_over,
+            admin_token,
+        } = if request.body.is_empty() {
+            LeaseRequest::default()
+        } else {
+            parse(request)?
+        };
+
+        let (token, operator) = match bearer_token(request) {
+            Some(token) => (token.to_string(), self.authorize(request)?),
+            None => {
+                let mut operator = match client {
+                    Some(client) => Operator::new(format!("api:{client}")),
+                    None => anonymous(stream)?,
+                };
+                operator.admin = match (&admin_token, &self.admin_token) {
+                    (Some(given), Some(expected)) => {
+                        constant_time_eq(given.as_bytes(), expected.as_bytes())
+                    }
+                    _ => false,
+                };
+
+                let mut clients = self.clients.lock().unwrap();
+                self.forget_idle_clients(&mut clients);
+                if clients.values().any(|known| known.name == operator.name) {
+                    return Err(Failure::new(
+                        409,
+                        format!("client {operator} is already known, pass its token"),
+                    ));
+                }
+                let token = secret_token()?;
+                clients.insert(token.clone(), operator.clone());
+                (token, operator)
+            }
+        };
+
+        let result = if take_over {
+            self.rover
+                .take_control(&operator)
+                .map_err(|e| Failure::new(403, e.to_string()))
+        } else if handover {
+            match self.rover.request_control(&operator) {
+                Err(e) if e.is::<ViewOnly>() => Ok(()),
+                result => result.map_err(Failure::from),
+            }
+        } else {
+            self.rover.acquire_control(&operator).map_err(Failure::from)
+        };
+        if let Err(failure) = result {
+            // a client that never got anywhere is forgotten again
+            if bearer_token(request).is_none() {
+                self.clients.lock().unwrap().remove(&token);
+            }
+            return Err(failure);
+        }
+
+        let lease = self.rover.control_lease();
+        Ok(json!({
+            "token": token,
+            "client": operator.name,
+            "holder": lease.as_ref().map(|lease| &lease.holder.name),
+            "expires_in_ms": lease.map(|lease| lease.expires_in.as_millis() as u64),
+        }))
+    }
+
+    fn release(&self, request: &HttpRequest) -> Result<Value, Failure> {
+        let operator = self.authorize(request)?;
+
+        self.drive_generation.fetch_add(1, Ordering::SeqCst);
+        self.turret_generation.fetch_add(1, Ordering::SeqCst);
+        self.rover.release_control(&operator)?;
+        if let Some(token) = bearer_token(request) {
+            self.clients.lock().unwrap().remove(token);
+        }
+
+        Ok(Value::Null)
+    }
+
+    /// Forgets clients whose lease expired or was taken over, so they can register again after
+    /// losing their token. Only the holder and whoever asked for a handover keep their tokens.
+    fn forget_idle_clients(&self, clients: &mut HashMap<String, Operator>) {
+        let lease = self.rover.control_lease();
+        clients.retain(|_, operator| {
+            lease.as_ref().is_some_and(|lease| {
+                lease.holder == *operator || lease.handover_requested_by.as_ref() == Some(operator)
+            })
+        });
+    }
+
+    /// The client the request's token belongs to.
+    fn authorize(&self, request: &HttpRequest) -> Result<Operator, Failure> {
+        bearer_token(request)
+            .and_then(|token| self.clients.lock().unwrap().get(token).cloned())
+            .ok_or_else(|| Failure::new(401, "take the control lease with POST /lease first"))
+    }
+
+    fn drive(&self, operator: &Operator, request: DriveRequest) -> Result<Value, Failure> {
+        let duration = parse_duration(request.duration_ms)?;
+        let generation = self.drive_generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        self.rover.send_command(
+            operator,
+            Command::Drive(request.direction, request.steer, request.speed),
+        )?;
+
+        if let Some(duration) = duration {
+            self.stop_after(
+                operator,
+                duration,
+                &self.drive_generation,
+                generation,
+                vec![Command::Drive(
+                    Direction::Neutral,
+                    HorizontalDirection::Neutral,
+                    request.speed,
+                )],
+            );
+        }
+
+        Ok(Value::Null)
+    }
+
+    fn turret(&self, operator: &Operator, request: TurretRequest) -> Result<Value, Failure> {
+        let generation = self.turret_generation.fetch_add(1, Ordering::SeqCst) + 1;
+
+        match request {
+            TurretRequest::Goto { pan, tilt } => {
+                self.rover
+                    .turret_goto(operator, TurretPosition { pan, tilt })?;
+            }
+            TurretRequest::Move {
+                horizontal,
+                vertical,
+                duration_ms,
+            } => {
+                let duration = parse_duration(duration_ms)?;
+
+                self.rover
+                    .send_command(operator, Command::CameraMoveHorizontal(horizontal))?;
+                self.rover
+                    .send_command(operator, Command::CameraMoveVertical(vertical))?;
+
+                if let Some(duration) = duration {
+                    self.stop_after(
+                        operator,
+                        duration,
+                        &self.turret_generation,
+                        generation,
+                        vec![
+                            Command::CameraMoveHorizontal(HorizontalDirection::Neutral),
+                            Command::CameraMoveVertical(VerticalDirection::Neutral),
+                        ],
+                    );
+                }
+            }
+        }
+
+        Ok(Value::Null)
+    }
+
+    fn camera(&self, operator: &Operator, request: CameraRequest) -> Result<Value, Failure> {
+        if let Some(camera) = request.camera {
+            self.rover
+                .send_command(operator, Command::UseCamera(camera))?;
+        }
+        if let Some(brightness) = request.brightness {
+            self.rover
+                .send_command(operator, Command::Brightness(brightness))?;
+        }
+        if let Some(contrast) = request.contrast {
+            self.rover
+                .send_command(operator, Command::Contrast(contrast))?;
+        }
+        if let Some(resolution) = request.resolution {
+            self.rover
+                .send_command(operator, Command::Resolution(resolution))?;
+        }
+        if let Some(frame_rate) = request.frame_rate {
+            self.rover
+                .send_command(operator, Command::FrameRate(frame_rate))?;
+        }
+        if request.flip.is_some() || request.mirror.is_some() {
+            // both are set at once, the missing one stays as it is
+            let current = self.rover.camera_settings()?.orientation;
+            self.rover.send_command(
+                operator,
+                Command::Orientation(Orientation {
+                    flip: request.flip.unwrap_or(current.flip),
+                    mirror: request.mirror.unwrap_or(current.mirror),
+                }),
+            )?;
+        }
+
+        Ok(Value::Null)
+    }
+
+    /// Stops all motion, including timed motion still running.
+    fn stop_motion(&self, operator: &Operator) -> anyhow::Result<()> {
+        self.drive_generation.fetch_add(1, Ordering::SeqCst);
+        self.turret_generation.fetch_add(1, Ordering::SeqCst);
+        self.rover.stop(operator)
+    }
+
+    /// Keeps the motion alive for `duration`, then sends `stop` unless a newer request took over.
+    fn stop_after(
+        &self,
+        operator: &Operator,
+        duration: Duration,
+        generation: &Arc<AtomicU64>,
+        started: u64,
+        stop: Vec<Command>,
+    ) {
+        let rover = self.rover.clone();
+        let operator = operator.clone();
+        let generation = generation.clone();
+        let end = Instant::now() + duration;
+
+        std::thread::spawn(move || {
+            while let Some(remaining) = end.checked_duration_since(Instant::now()) {
+                if generation.load(Ordering::SeqCst) != started {
+                    return;
+                }
+                std::thread::sleep(remaining.min(REFRESH_INTERVAL));
+                rover.refresh_motion(&operator);
+            }
+
+            if generation.load(Ordering::SeqCst) == started {
+                for command in stop {
+                    match rover.send_command(&operator, command) {
+                        // whoever took control has stopped the rover already
+                        Err(e) if e.is::<ViewOnly>() => {}
+                        Err(e) => warn!("failed to end timed motion: {e}"),
+                        Ok(()) => {}
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn parse<T: DeserializeOwned>(request: &HttpRequest) -> Result<T, Failure> {
+    serde_json::from_slice(&request.body)
+        .map_err(|e| Failure::new(400, format!("invalid request body: {e}")))
+}
+
+fn parse_duration(duration_ms: Option<u64>) -> Result<Option<Duration>, Failure> {
+    match duration_ms.map(Duration::from_millis) {
+        Some(duration) if duration > MAX_DURATION => Err(Failure::new(
+            400,
+            format!("duration_ms may be at most {}", MAX_DURATION.as_millis()),
+        )),
+        duration => Ok(duration),
+    }
+}
+
+/// Clients that did not name themselves are known by their IP.
+fn anonymous(stream: &TcpStream) -> Result<Operator, Failure> {
+    let peer = stream.peer_addr().map_err(anyhow::Error::from)?;
+    Ok(Operator::new(format!("api:{}", peer.ip())))
+}
+
+fn bearer_token(request: &HttpRequest) -> Option<&str> {
+    request.header("Authorization")?.strip_prefix("Bearer ")
+}
@@ -0,0 +1,87 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError},
+    Arc, Mutex,
+};
+use std::time::Duration;
+
+/// Fans items out to any number of subscribers. A subscriber that falls more than its backlog
+/// behind misses items instead of holding everyone else up, and is told so by
+/// [`Subscription::take_lagged`].
+pub struct Broadcast<T> {
+    subscribers: Mutex<Vec<Subscriber<T>>>,
+}
+
+struct Subscriber<T> {
+    tx: SyncSender<T>,
+    lagged: Arc<AtomicBool>,
+}
+
+pub struct Subscription<T> {
+    rx: Receiver<T>,
+    lagged: Arc<AtomicBool>,
+}
+
+impl<T: Clone> Broadcast<T> {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            subscribers: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn subscribe(&self, backlog: usize) -> Subscription<T> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(backlog);
+        let lagged = Arc::new(AtomicBool::new(false));
+
+        self.subscribers.lock().unwrap().push(Subscriber {
+            tx,
+            lagged: lagged.clone(),
+        });
+
+        Subscription { rx, lagged }
+    }
+
+    /// Returns how many subscribers missed `item`.
+    pub fn send(&self, item: T) -> usize {
+        let mut missed = 0;
+        self.subscribers.lock().unwrap().retain(|subscriber| {
+            match subscriber.tx.try_send(item.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::Relaxed);
+                    missed += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+
+        missed
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+}
+
+impl<T> Subscription<T> {
+    /// Waits for the next item, `None` on timeout. Fails once the broadcast is gone.
+    pub fn recv_timeout(&self, timeout: Duration) -> anyhow::Result<Option<T>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(item) => Ok(Some(item)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => anyhow::bail!("broadcast ended"),
+        }
+    }
+
+    pub fn recv(&self) -> anyhow::Result<T> {
+        self.rx
+            .recv()
+            .map_err(|_| anyhow::anyhow!("broadcast ended"))
+    }
+
+    /// Whether items were dropped since the last call.
+    pub fn take_lagged(&self) -> bool {
+        self.lagged.swap(false, Ordering::Relaxed)
+    }
+}
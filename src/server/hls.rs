@@ -0,0 +1,638 @@
+//! Rolling HLS recording: the H.264 stream is cut into MPEG-TS segments at keyframes and listed in
+//! a playlist, so any HTTP file server can publish the directory as a live and archived view.
+//!
+//! Segments are named after the wall clock time they start at, in milliseconds since the epoch,
+//! and the playlist lists every segment of the current recording still on disk. Audio is not
+//! recorded, HLS has no codec for the rover's ADPCM.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+
+use crate::rover::media::StreamPacket;
+
+use super::MediaBroadcast;
+
+pub const PLAYLIST: &str = "stream.m3u8";
+
+const SEGMENT_EXTENSION: &str = ".ts";
+
+/// Frame interval in milliseconds assumed for the last frame of a segment until one has been
+/// measured.
+const DEFAULT_FRAME_INTERVAL: u32 = 66;
+
+const CLIENT_BACKLOG: usize = 256;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const PID_PAT: u16 = 0;
+const PID_PMT: u16 = 0x1000;
+const PID_VIDEO: u16 = 0x100;
+const STREAM_TYPE_H264: u8 = 0x1B;
+const TS_PACKET_SIZE: usize = 188;
+
+/// Lets the decoder buffer a little before the first frame is due.
+const PTS_OFFSET: u64 = 90_000 / 5;
+
+#[derive(Debug, Clone)]
+pub struct HlsConfig {
+    pub directory: PathBuf,
+    /// Segments are cut at the first keyframe after this long.
+    pub segment_duration: Duration,
+    /// Segments older than this are deleted.
+    pub max_age: Option<Duration>,
+    /// The oldest segments are deleted while all of them take up more bytes than this.
+    pub max_size: Option<u64>,
+}
+
+impl HlsConfig {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+            segment_duration: Duration::from_secs(4),
+            max_age: None,
+            max_size: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    name: String,
+    started: SystemTime,
+    /// Seconds, `None` for segments of earlier recordings, which are only pruned.
+    duration: Option<f64>,
+    size: u64,
+    /// Whether the stream is discontinuous before this segment.
+    discontinuity: bool,
+}
+
+/// The segment being written.
+struct OpenSegment {
+    segment: Segment,
+    file: BufWriter<File>,
+    first_timestamp: u32,
+}
+
+/// Writes the video packets of a stream as HLS.
+pub struct HlsWriter {
+    config: HlsConfig,
+    /// Closed segments, oldest first.
+    segments: VecDeque<Segment>,
+    open: Option<OpenSegment>,
+    /// Kept across segments, the continuity counters carry on.
+    muxer: TsMuxer,
+    /// Media sequence number of the first listed segment.
+    sequence: u64,
+    discontinuity_sequence: u64,
+    pending_discontinuity: bool,
+    /// Rover timestamp at PTS 0.
+    base_timestamp: Option<u32>,
+    last_timestamp: u32,
+    frame_interval: u32,
+    video_type: Option<u8>,
+    /// Cleared after a gap until the next keyframe.
+    synced: bool,
+}
+
+impl HlsWriter {
+    /// Prepares `config.directory`. Segments left there by earlier recordings are not listed in
+    /// the new playlist, but still pruned.
+    pub fn create(config: HlsConfig) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&config.directory)
+            .map_err(|e| anyhow::anyhow!("failed to create {}: {e}", config.directory.display()))?;
+
+        let mut segments = Vec::new();
+        for entry in std::fs::read_dir(&config.directory)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(millis) = name
+                .strip_suffix(SEGMENT_EXTENSION)
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+
+            segments.push(Segment {
+                name,
+                started: UNIX_EPOCH + Duration::from_millis(millis),
+                duration: None,
+                size: entry.metadata()?.len(),
+                discontinuity: false,
+            });
+        }
+        segments.sort_by_key(|segment| segment.started);
+
+        let mut writer = Self {
+            config,
+            segments: segments.into(),
+            open: None,
+            muxer: TsMuxer::default(),
+            sequence: 0,
+            discontinuity_sequence: 0,
+            pending_discontinuity: false,
+            base_timestamp: None,
+            last_timestamp: 0,
+            frame_interval: DEFAULT_FRAME_INTERVAL,
+            video_type: None,
+            synced: false,
+        };
+        writer.prune()?;
+
+        Ok(writer)
+    }
+
+    /// Adds a packet, audio is ignored.
+    pub fn write(&mut self, packet: &StreamPacket) -> anyhow::Result<()> {
+        let StreamPacket::Video {
+            video_type,
+            timestamp,
+            data,
+            ..
+        } = packet
+        else {
+            return Ok(());
+        };
+        let keyframe = packet.is_keyframe();
+
+        // players have to reset their decoder for a new resolution
+        if self
+            .video_type
+            .is_some_and(|current| current != *video_type)
+        {
+            self.gap()?;
+        }
+        if !self.synced {
+            if !keyframe {
+                return Ok(());
+            }
+            self.synced = true;
+        }
+        self.video_type = Some(*video_type);
+
+        if let Some(open) = &self.open {
+            let interval = timestamp.wrapping_sub(self.last_timestamp);
+            if interval > 0 && interval < 1000 {
+                self.frame_interval = interval;
+            }
+
+            let length = timestamp.wrapping_sub(open.first_timestamp);
+            if keyframe && Duration::from_millis(length as u64) >= self.config.segment_duration {
+                self.close_segment(length)?;
+            }
+        }
+
+        let base = *self.base_timestamp.get_or_insert(*timestamp);
+        let open = match &mut self.open {
+            Some(open) => open,
+            None => self.open.insert(Self::open_segment(
+                &self.config,
+                *timestamp,
+                std::mem::take(&mut self.pending_discontinuity),
+            )?),
+        };
+
+        let pts = timestamp.wrapping_sub(base) as u64 * 90 + PTS_OFFSET;
+        open.segment.size += self
+            .muxer
+            .write_video(&mut open.file, pts, keyframe, data)? as u64;
+        self.last_timestamp = *timestamp;
+
+        Ok(())
+    }
+
+    /// Notes that packets were missed. Recording resumes with a new segment at the next keyframe.
+    pub fn gap(&mut self) -> anyhow::Result<()> {
+        self.synced = false;
+        if let Some(open) = &self.open {
+            let length = self
+                .last_timestamp
+                .wrapping_sub(open.first_timestamp)
+                .wrapping_add(self.frame_interval);
+            self.close_segment(length)?;
+            self.pending_discontinuity = true;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the last segment and marks the playlist as complete.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.gap()?;
+        self.write_playlist(true)
+    }
+
+    fn open_segment(
+        config: &HlsConfig,
+        timestamp: u32,
+        discontinuity: bool,
+    ) -> anyhow::Result<OpenSegment> {
+        let started = SystemTime::now();
+        let name = format!(
+            "{}{SEGMENT_EXTENSION}",
+            started.duration_since(UNIX_EPOCH)?.as_millis()
+        );
+        let path = config.directory.join(&name);
+
+        let file = File::create(&path)
+            .map_err(|e| anyhow::anyhow!("failed to create {}: {e}", path.display()))?;
+        debug!("recording {}", path.display());
+
+        Ok(OpenSegment {
+            segment: Segment {
+                name,
+                started,
+                duration: None,
+                size: 0,
+                discontinuity,
+            },
+            file: BufWriter::new(file),
+            first_timestamp: timestamp,
+        })
+    }
+
+    /// `length` is in rover milliseconds.
+    fn close_segment(&mut self, length: u32) -> anyhow::Result<()> {
+        let Some(mut open) = self.open.take() else {
+            return Ok(());
+        };
+
+        open.file.flush()?;
+        open.segment.duration = Some(length as f64 / 1000.0);
+        self.segments.push_back(open.segment);
+
+        self.prune()?;
+        self.write_playlist(false)
+    }
+
+    /// Deletes the oldest segments until the limits are met.
+    fn prune(&mut self) -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        let mut total = self
+            .segments
+            .iter()
+            .map(|segment| segment.size)
+            .sum::<u64>();
+
+        while let Some(oldest) = self.segments.front() {
+            let expired = self.config.max_age.is_some_and(|max_age| {
+                now.duration_since(oldest.started)
+                    .is_ok_and(|age| age > max_age)
+            });
+            let oversized = self
+                .config
+                .max_size
+                .is_some_and(|max_size| total > max_size);
+            if !expired && !oversized {
+                break;
+            }
+
+            let oldest = self.segments.pop_front().unwrap();
+            let path = self.config.directory.join(&oldest.name);
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("failed to delete {}: {e}", path.display());
+            }
+            total -= oldest.size;
+
+            if oldest.duration.is_some() {
+                self.sequence += 1;
+            }
+            // the next listed segment is the first one now, the marker would be meaningless
+            if let Some(next) = self.segments.front_mut() {
+                if next.discontinuity {
+                    next.discontinuity = false;
+                    self.discontinuity_sequence += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the playlist, going through a temporary file so readers never see half of it.
+    fn write_playlist(&self, complete: bool) -> anyhow::Result<()> {
+        let listed = self
+            .segments
+            .iter()
+            .filter_map(|segment| Some((segment, segment.duration?)))
+            .collect::<Vec<_>>();
+
+        let target_duration = listed
+            .iter()
+            .map(|(_, duration)| *duration)
+            .fold(self.config.segment_duration.as_secs_f64(), f64::max)
+            .ceil();
+
+        let mut playlist = format!(
+            "#EXTM3U\n\
+             #EXT-X-VERSION:3\n\
+             #EXT-X-TARGET-DURATION:{target_duration}\n\
+             #EXT-X-MEDIA-SEQUENCE:{}\n\
+             #EXT-X-DISCONTINUITY-SEQUENCE:{}\n",
+            self.sequence, self.discontinuity_sequence
+        );
+        for (segment, duration) in listed {
+            if segment.discontinuity {
+                playlist += "#EXT-X-DISCONTINUITY\n";
+            }
+            playlist += &format!(
+                "#EXT-X-PROGRAM-DATE-TIME:{}\n#EXTINF:{duration:.3},\n{}\n",
+                format_date_time(segment.started),
+                segment.name
+            );
+        }
+        if complete {
+            playlist += "#EXT-X-ENDLIST\n";
+        }
+
+        let path = self.config.directory.join(PLAYLIST);
+        let temporary = path.with_extension("m3u8.tmp");
+        std::fs::write(&temporary, playlist)?;
+        std::fs::rename(&temporary, &path)
+            .map_err(|e| anyhow::anyhow!("failed to write {}: {e}", path.display()))
+    }
+}
+
+/// A recording fed from the media broadcast on its own thread.
+pub struct HlsRecorder {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<anyhow::Result<()>>,
+}
+
+impl HlsRecorder {
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Stops recording and completes the playlist.
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.thread
+            .join()
+            .map_err(|_| anyhow::anyhow!("HLS recorder panicked"))?
+    }
+}
+
+/// Records `media` into `config.directory` until [`HlsRecorder::finish`].
+pub fn spawn(config: HlsConfig, media: &MediaBroadcast) -> anyhow::Result<HlsRecorder> {
+    info!(
+        "recording HLS to {}",
+        config.directory.join(PLAYLIST).display()
+    );
+
+    let mut writer = HlsWriter::create(config)?;
+    let packets = media.subscribe(CLIENT_BACKLOG);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let thread = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                let Some(packet) = packets.recv_timeout(POLL_INTERVAL)? else {
+                    continue;
+                };
+                if packets.take_lagged() {
+                    warn!("HLS recording fell behind, skipping to the next keyframe");
+                    writer.gap()?;
+                }
+
+                writer.write(&packet)?;
+            }
+
+            writer.finish()
+        })
+    };
+
+    Ok(HlsRecorder { stop, thread })
+}
+
+/// Writes H.264 access units as MPEG-TS with a single program.
+#[derive(Default)]
+struct TsMuxer {
+    /// Continuity counters of the PAT, PMT and video PIDs.
+    continuity: [u8; 3],
+    tables_written: bool,
+}
+
+impl TsMuxer {
+    /// Returns the number of bytes written.
+    fn write_video<W: Write>(
+        &mut self,
+        out: &mut W,
+        pts: u64,
+        keyframe: bool,
+        data: &[u8],
+    ) -> std::io::Result<usize> {
+        let mut packets = Vec::new();
+
+        // repeated at every keyframe so a player can join anywhere
+        if keyframe || !self.tables_written {
+            self.write_section(&mut packets, PID_PAT, &pat());
+            self.write_section(&mut packets, PID_PMT, &pmt());
+            self.tables_written = true;
+        }
+
+        let pts = pts & ((1 << 33) - 1);
+        let mut pes = vec![0, 0, 1, 0xE0, 0, 0, 0x80, 0x80, 5];
+        pes.extend([
+            0x21 | ((pts >> 29) & 0x0E) as u8,
+            (pts >> 22) as u8,
+            0x01 | ((pts >> 14) & 0xFE) as u8,
+            (pts >> 7) as u8,
+            0x01 | ((pts << 1) & 0xFE) as u8,
+        ]);
+        // access unit delimiter, expected by some HLS players
+        pes.extend([0, 0, 0, 1, 0x09, 0xF0]);
+        pes.extend_from_slice(data);
+
+        let pcr = pts.saturating_sub(PTS_OFFSET);
+        let mut first = true;
+        let mut remaining = &pes[..];
+
+        while !remaining.is_empty() {
+            let mut adaptation = Vec::new();
+            if first {
+                adaptation.push(if keyframe { 0x50 } else { 0x10 });
+                adaptation.extend([
+                    (pcr >> 25) as u8,
+                    (pcr >> 17) as u8,
+                    (pcr >> 9) as u8,
+                    (pcr >> 1) as u8,
+                    ((pcr & 1) << 7) as u8 | 0x7E,
+                    0,
+                ]);
+            }
+
+            let mut room = TS_PACKET_SIZE - 4;
+            if first {
+                room -= 1 + adaptation.len();
+            }
+            let length = remaining.len().min(room);
+
+            // the last packet is padded with adaptation field stuffing
+            let with_adaptation = first || length < room;
+            if length < room {
+                let mut missing = room - length;
+                if !first {
+                    // the length byte of a new adaptation field takes one byte
+                    missing -= 1;
+                    if missing > 0 {
+                        adaptation.push(0);
+                        missing -= 1;
+                    }
+                }
+                adaptation.extend(std::iter::repeat_n(0xFF, missing));
+            }
+
+            self.write_header(&mut packets, PID_VIDEO, first, with_adaptation);
+            if with_adaptation {
+                packets.push(adaptation.len() as u8);
+                packets.extend(adaptation);
+            }
+            packets.extend_from_slice(&remaining[..length]);
+
+            remaining = &remaining[length..];
+            first = false;
+        }
+
+        out.write_all(&packets)?;
+        Ok(packets.len())
+    }
+
+    fn write_section(&mut self, packets: &mut Vec<u8>, pid: u16, section: &[u8]) {
+        let start = packets.len();
+        self.write_header(packets, pid, true, false);
+        packets.push(0);
+        packets.extend_from_slice(section);
+        packets.resize(start + TS_PACKET_SIZE, 0xFF);
+    }
+
+    fn write_header(&mut self, packets: &mut Vec<u8>, pid: u16, start: bool, adaptation: bool) {
+        let counter = &mut self.continuity[match pid {
+            PID_PAT => 0,
+            PID_PMT => 1,
+            _ => 2,
+        }];
+
+        packets.extend([
+            0x47,
+            if start { 0x40 } else { 0 } | (pid >> 8) as u8,
+            pid as u8,
+            if adaptation { 0x30 } else { 0x10 } | *counter,
+        ]);
+        *counter = (*counter + 1) & 0x0F;
+    }
+}
+
+fn pat() -> Vec<u8> {
+    with_crc(vec![
+        0x00,
+        0xB0,
+        13,
+        0x00,
+        0x01,
+        0xC1,
+        0x00,
+        0x00,
+        0x00,
+        0x01,
+        0xE0 | (PID_PMT >> 8) as u8,
+        PID_PMT as u8,
+    ])
+}
+
+fn pmt() -> Vec<u8> {
+    with_crc(vec![
+        0x02,
+        0xB0,
+        18,
+        0x00,
+        0x01,
+        0xC1,
+        0x00,
+        0x00,
+        0xE0 | (PID_VIDEO >> 8) as u8,
+        PID_VIDEO as u8,
+        0xF0,
+        0x00,
+        STREAM_TYPE_H264,
+        0xE0 | (PID_VIDEO >> 8) as u8,
+        PID_VIDEO as u8,
+        0xF0,
+        0x00,
+    ])
+}
+
+/// Appends the MPEG-2 CRC32 of `section`.
+fn with_crc(mut section: Vec<u8>) -> Vec<u8> {
+    let mut crc = u32::MAX;
+    for byte in &section {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    section.extend(crc.to_be_bytes());
+    section
+}
+
+/// ISO 8601 in UTC, e.g. `2024-05-01T12:34:56.789Z`.
+fn format_date_time(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let days = (seconds / 86_400) as i64;
+
+    // civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_association_table() {
+        assert_eq!(pat()[12..], [0x2A, 0xB1, 0x04, 0xB2]);
+    }
+
+    #[test]
+    fn date_time() {
+        assert_eq!(format_date_time(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_date_time(UNIX_EPOCH + Duration::from_millis(1_709_210_096_789)),
+            "2024-02-29T12:34:56.789Z"
+        );
+    }
+}
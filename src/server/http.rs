@@ -0,0 +1,179 @@
+use std::io::{BufRead, Read, Write};
+
+/// Largest request body accepted, the API only takes small JSON documents.
+const MAX_BODY: usize = 64 * 1024;
+const MAX_HEADERS: usize = 64;
+/// Longest request or header line accepted, line break included.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// Just enough HTTP/1.1 for the embedded servers: one request per connection, no chunked
+/// bodies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    pub fn read_from<R: BufRead>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut line = String::new();
+        read_line(reader, &mut line)?;
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(_version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("invalid request line {line:?}");
+        };
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+
+        let mut request = Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+            headers: Vec::new(),
+            body: Vec::new(),
+        };
+
+        loop {
+            line.clear();
+            if read_line(reader, &mut line)? == 0 {
+                anyhow::bail!("connection closed in request header");
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if request.headers.len() == MAX_HEADERS {
+                anyhow::bail!("too many request headers");
+            }
+
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow::anyhow!("invalid header {line:?}"))?;
+            request
+                .headers
+                .push((name.trim().to_string(), value.trim().to_string()));
+        }
+
+        if let Some(length) = request.header("Content-Length") {
+            let length = length.parse::<usize>()?;
+            if length > MAX_BODY {
+                anyhow::bail!("request body of {length} bytes is too large");
+            }
+
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body)?;
+        }
+
+        Ok(request)
+    }
+
+    /// Value of the first header called `name`, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Value of `name` in the query string, undecoded.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Like [`BufRead::read_line`], failing on lines over [`MAX_LINE`] instead of buffering them.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> anyhow::Result<usize> {
+    let n = reader.take(MAX_LINE).read_line(line)?;
+    if n as u64 == MAX_LINE && !line.ends_with('\n') {
+        anyhow::bail!("request line longer than {MAX_LINE} bytes");
+    }
+
+    Ok(n)
+}
+
+pub fn status_text(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Writes a complete response and asks the client to close the connection.
+pub fn respond<W: Write>(
+    writer: &mut W,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> std::io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status_text(status),
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+pub fn respond_error<W: Write>(writer: &mut W, status: u16) -> std::io::Result<()> {
+    respond(
+        writer,
+        status,
+        "text/plain",
+        format!("{status} {}\n", status_text(status)).as_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_request() {
+        let raw =
+            b"POST /lease?client=ci HTTP/1.1\r\nContent-Length: 2\r\nX-Token:  abc \r\n\r\n{}";
+        let request = HttpRequest::read_from(&mut raw.as_slice()).unwrap();
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/lease");
+        assert_eq!(request.query_param("client"), Some("ci"));
+        assert_eq!(request.header("x-token"), Some("abc"));
+        assert_eq!(request.body, b"{}");
+    }
+
+    #[test]
+    fn rejects_overlong_lines() {
+        let mut raw = b"GET /".to_vec();
+        raw.resize(MAX_LINE as usize * 2, b'a');
+        assert!(HttpRequest::read_from(&mut raw.as_slice()).is_err());
+
+        let mut raw = b"GET / HTTP/1.1\r\nX-Long: ".to_vec();
+        raw.resize(MAX_LINE as usize * 2, b'a');
+        raw.extend(b"\r\n\r\n");
+        assert!(HttpRequest::read_from(&mut raw.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Prometheus endpoint on `/metrics`, exporting the session's [`Metrics`] and the link related
+//! telemetry in the text exposition format.
+
+use std::{
+    fmt::Write as _,
+    io::BufReader,
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread::JoinHandle,
+};
+
+use crate::rover::{metrics::MetricsSnapshot, telemetry::Telemetry, Rover};
+
+use super::{
+    http::{self, HttpRequest},
+    listen,
+};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+pub fn spawn(address: SocketAddr, rover: Arc<Rover>) -> anyhow::Result<JoinHandle<()>> {
+    listen(address, "metrics", move |stream| {
+        handle_connection(stream, &rover)
+    })
+}
+
+fn handle_connection(mut stream: TcpStream, rover: &Rover) -> anyhow::Result<()> {
+    let request = HttpRequest::read_from(&mut BufReader::new(&mut stream))?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => {
+            let body = render(&rover.metrics().snapshot(), &rover.latest_telemetry());
+            http::respond(&mut stream, 200, CONTENT_TYPE, body.as_bytes())?;
+        }
+        (_, "/metrics") => http::respond_error(&mut stream, 405)?,
+        _ => http::respond_error(&mut stream, 404)?,
+    }
+
+    Ok(())
+}
+
+/// Appends one metric with its help and type lines, `samples` are label sets and values.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{name}{labels} {value}");
+    }
+}
+
+fn render(metrics: &MetricsSnapshot, telemetry: &Telemetry) -> String {
+    let mut out = String::new();
+    let unlabeled = |value: f64| vec![(String::new(), value)];
+
+    metric(
+        &mut out,
+        "rover_packets_total",
+        "counter",
+        "Media packets received, by kind.",
+        &[
+            (
+                r#"{kind="video"}"#.to_string(),
+                metrics.video_packets as f64,
+            ),
+            (
+                r#"{kind="audio"}"#.to_string(),
+                metrics.audio_packets as f64,
+            ),
+        ],
+    );
+    metric(
+        &mut out,
+        "rover_video_bytes_total",
+        "counter",
+        "Video payload bytes received, by video type.",
+        &metrics
+            .video_bytes
+            .iter()
+            .map(|(video_type, bytes)| {
+                let labels = format!(r#"{{video_type="{video_type}"}}"#);
+                (labels, *bytes as f64)
+            })
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        &mut out,
+        "rover_decode_errors_total",
+        "counter",
+        "Video packets the decoder rejected.",
+        &unlabeled(metrics.decode_errors as f64),
+    );
+    metric(
+        &mut out,
+        "rover_stream_resyncs_total",
+        "counter",
+        "Times the media stream skipped ahead to find a packet header.",
+        &unlabeled(metrics.resyncs as f64),
+    );
+    metric(
+        &mut out,
+        "rover_dropped_frames_total",
+        "counter",
+        "Video packets skipped by consumers that fell behind or lost the decoder state.",
+        &unlabeled(metrics.dropped_frames as f64),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP rover_command_latency_seconds Time from queueing a command to having written it.\n\
+         # TYPE rover_command_latency_seconds summary\n\
+         rover_command_latency_seconds_sum {}\n\
+         rover_command_latency_seconds_count {}",
+        metrics.command_latency_sum.as_secs_f64(),
+        metrics.commands_sent
+    );
+    metric(
+        &mut out,
+        "rover_command_latency_max_seconds",
+        "gauge",
+        "Longest command latency so far.",
+        &unlabeled(metrics.command_latency_max.as_secs_f64()),
+    );
+    metric(
+        &mut out,
+        "rover_heartbeat_misses_total",
+        "counter",
+        "Heartbeats after which nothing had been received from the rover since the previous one.",
+        &unlabeled(metrics.heartbeat_misses as f64),
+    );
+    metric(
+        &mut out,
+        "rover_last_packet_age_seconds",
+        "gauge",
+        "Time since the last media packet.",
+        &metrics
+            .last_packet_age
+            .map(|age| unlabeled(age.as_secs_f64()))
+            .unwrap_or_default(),
+    );
+    metric(
+        &mut out,
+        "rover_signal_strength_percent",
+        "gauge",
+        "WiFi signal strength reported by the rover.",
+        &telemetry
+            .signal_strength
+            .map(|signal| unlabeled(signal.into()))
+            .unwrap_or_default(),
+    );
+    metric(
+        &mut out,
+        "rover_battery_level_percent",
+        "gauge",
+        "Battery level reported by the rover.",
+        &telemetry
+            .battery_level
+            .map(|level| unlabeled(level.into()))
+            .unwrap_or_default(),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn exposition_format() {
+        let metrics = MetricsSnapshot {
+            video_packets: 10,
+            audio_packets: 4,
+            video_bytes: BTreeMap::from([(1, 2048)]),
+            commands_sent: 2,
+            command_latency_sum: Duration::from_millis(30),
+            command_latency_max: Duration::from_millis(20),
+            ..MetricsSnapshot::default()
+        };
+        let telemetry = Telemetry {
+            signal_strength: Some(70),
+            ..Telemetry::default()
+        };
+
+        let text = render(&metrics, &telemetry);
+        for line in [
+            "# TYPE rover_packets_total counter",
+            r#"rover_packets_total{kind="video"} 10"#,
+            r#"rover_packets_total{kind="audio"} 4"#,
+            r#"rover_video_bytes_total{video_type="1"} 2048"#,
+            "rover_command_latency_seconds_sum 0.03",
+            "rover_command_latency_seconds_count 2",
+            "rover_command_latency_max_seconds 0.02",
+            "rover_signal_strength_percent 70",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+        // unknown values are left out rather than exported as zero
+        assert!(!text.contains("rover_battery_level_percent"));
+        assert!(!text.contains("rover_last_packet_age_seconds"));
+    }
+}
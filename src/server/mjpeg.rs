@@ -0,0 +1,248 @@
+//! MJPEG for dashboards that only embed `multipart/x-mixed-replace`: the video is decoded and
+//! re-encoded as JPEG on `/video.mjpg` and `/snapshot.jpg`. Nothing is decoded while nobody is
+//! watching.
+
+use std::{
+    io::{BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, warn};
+use openh264::decoder::Decoder;
+
+use crate::rover::metrics::Metrics;
+
+use super::{
+    http::{self, HttpRequest},
+    listen, MediaBroadcast,
+};
+
+const BOUNDARY: &str = "frame";
+
+const CLIENT_BACKLOG: usize = 64;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a snapshot may wait for the decoder to reach a keyframe.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Viewers that got no frame or could not take one for this long are disconnected.
+const VIEWER_TIMEOUT: Duration = Duration::from_secs(10);
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct MjpegConfig {
+    /// JPEG frames per second, at most the rate of the video.
+    pub frame_rate: f32,
+    /// 1 to 100.
+    pub quality: u8,
+}
+
+impl Default for MjpegConfig {
+    fn default() -> Self {
+        Self {
+            frame_rate: 5.0,
+            quality: 75,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    viewers: usize,
+    /// Counts encoded frames, lets viewers wait for the next one.
+    sequence: u64,
+    jpeg: Option<Arc<Vec<u8>>>,
+}
+
+/// Serves `/video.mjpg` and `/snapshot.jpg`.
+pub fn spawn(
+    address: SocketAddr,
+    media: Arc<MediaBroadcast>,
+    metrics: Arc<Metrics>,
+    config: MjpegConfig,
+) -> anyhow::Result<JoinHandle<()>> {
+    let shared = Arc::new(Shared::default());
+
+    {
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            while let Err(e) = encode_loop(&shared, &media, &metrics, config) {
+                error!("MJPEG encoder failed, restarting: {e}");
+                std::thread::sleep(RESTART_DELAY);
+            }
+            info!("media stream ended, MJPEG encoder stopped");
+        });
+    }
+
+    listen(address, "mjpeg", move |stream| {
+        handle_connection(stream, &shared)
+    })
+}
+
+/// Decodes the stream and publishes JPEGs while there are viewers, until the stream ends.
+fn encode_loop(
+    shared: &Shared,
+    media: &MediaBroadcast,
+    metrics: &Metrics,
+    config: MjpegConfig,
+) -> anyhow::Result<()> {
+    let interval = Duration::from_secs_f32(1.0 / config.frame_rate.max(0.1));
+
+    loop {
+        drop(
+            shared
+                .changed
+                .wait_while(shared.state.lock().unwrap(), |state| state.viewers == 0)
+                .unwrap(),
+        );
+        debug!("encoding MJPEG");
+
+        let packets = media.subscribe(CLIENT_BACKLOG);
+        let mut decoder = Decoder::new()?;
+        let mut synced = false;
+        let mut next_frame = Instant::now();
+
+        while shared.state.lock().unwrap().viewers > 0 {
+            let packet = match packets.recv_timeout(POLL_INTERVAL) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(_) => return Ok(()),
+            };
+            if packets.take_lagged() {
+                synced = false;
+            }
+            synced |= packet.is_keyframe();
+            if !synced {
+                continue;
+            }
+
+            // every frame is decoded, the following ones depend on it
+            let image = match packet.to_rgb(&mut decoder) {
+                Ok(Some(image)) => image,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("failed to decode frame: {e}");
+                    metrics.record_decode_error();
+                    synced = false;
+                    continue;
+                }
+            };
+
+            let now = Instant::now();
+            if now < next_frame {
+                continue;
+            }
+            next_frame = now + interval;
+
+            let jpeg = image.to_jpeg(config.quality)?;
+            let mut state = shared.state.lock().unwrap();
+            state.sequence += 1;
+            state.jpeg = Some(Arc::new(jpeg));
+            shared.changed.notify_all();
+        }
+
+        debug!("no MJPEG viewers left, pausing");
+    }
+}
+
+/// Keeps the encoder running while it lives.
+struct Viewer<'a> {
+    shared: &'a Shared,
+}
+
+impl<'a> Viewer<'a> {
+    fn new(shared: &'a Shared) -> Self {
+        shared.state.lock().unwrap().viewers += 1;
+        shared.changed.notify_all();
+
+        Self { shared }
+    }
+
+    fn sequence(&self) -> u64 {
+        self.shared.state.lock().unwrap().sequence
+    }
+
+    /// Waits for a frame newer than `sequence`.
+    fn next_frame(&self, sequence: u64, timeout: Duration) -> Option<(u64, Arc<Vec<u8>>)> {
+        let (state, _) = self
+            .shared
+            .changed
+            .wait_timeout_while(self.shared.state.lock().unwrap(), timeout, |state| {
+                state.sequence <= sequence
+            })
+            .unwrap();
+
+        match &state.jpeg {
+            Some(jpeg) if state.sequence > sequence => Some((state.sequence, jpeg.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Viewer<'_> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().viewers -= 1;
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, shared: &Shared) -> anyhow::Result<()> {
+    let request = HttpRequest::read_from(&mut BufReader::new(&mut stream))?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/video.mjpg") => {
+            let peer = stream.peer_addr()?;
+            info!("MJPEG viewer connected from {peer}");
+            let result = stream_frames(&mut stream, &Viewer::new(shared));
+            info!("MJPEG viewer at {peer} disconnected");
+
+            return result;
+        }
+        ("GET", "/snapshot.jpg") => {
+            let viewer = Viewer::new(shared);
+            match viewer.next_frame(viewer.sequence(), SNAPSHOT_TIMEOUT) {
+                Some((_, jpeg)) => http::respond(&mut stream, 200, "image/jpeg", &jpeg)?,
+                None => http::respond_error(&mut stream, 503)?,
+            }
+        }
+        (_, "/video.mjpg" | "/snapshot.jpg") => http::respond_error(&mut stream, 405)?,
+        _ => http::respond_error(&mut stream, 404)?,
+    }
+
+    Ok(())
+}
+
+/// Writes frames until the viewer hangs up, stops taking them or none arrive for
+/// [`VIEWER_TIMEOUT`].
+fn stream_frames(stream: &mut TcpStream, viewer: &Viewer) -> anyhow::Result<()> {
+    stream.set_write_timeout(Some(VIEWER_TIMEOUT))?;
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={BOUNDARY}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+    )?;
+
+    let mut sequence = viewer.sequence();
+    loop {
+        let Some((next, jpeg)) = viewer.next_frame(sequence, VIEWER_TIMEOUT) else {
+            warn!("no MJPEG frame for {VIEWER_TIMEOUT:?}, disconnecting viewer");
+            return Ok(());
+        };
+        sequence = next;
+
+        write!(
+            stream,
+            "--{BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        )?;
+        stream.write_all(&jpeg)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+    }
+}
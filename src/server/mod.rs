@@ -0,0 +1,126 @@
+//! Embedded servers that share one rover session with other clients on the network.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc::Receiver, Arc},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{debug, error, info};
+
+use crate::rover::{media::StreamPacket, metrics::Metrics};
+
+use self::broadcast::Broadcast;
+
+pub mod api;
+pub mod broadcast;
+pub mod hls;
+pub mod http;
+pub mod metrics;
+pub mod mjpeg;
+pub mod mqtt;
+pub mod rtsp;
+pub mod transcode;
+pub mod web;
+
+/// Read timeout of accepted connections, long enough for a client to send its request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Every packet streamed by the rover, shared by all servers.
+pub type MediaBroadcast = Broadcast<Arc<StreamPacket>>;
+
+/// Forwards the rover's media stream into a broadcast until the rover disconnects. Video packets
+/// missed by lagging subscribers count as dropped frames.
+pub fn spawn_media_broadcast(
+    packets: Receiver<StreamPacket>,
+    metrics: Arc<Metrics>,
+) -> (Arc<MediaBroadcast>, JoinHandle<()>) {
+    let media = MediaBroadcast::new();
+
+    let thread = {
+        let media = media.clone();
+        std::thread::spawn(move || {
+            for packet in packets {
+                let video = matches!(packet, StreamPacket::Video { .. });
+                let missed = media.send(Arc::new(packet));
+                if video {
+                    metrics.record_dropped_frames(missed as u64);
+                }
+            }
+        })
+    };
+
+    (media, thread)
+}
+
+/// Accepts connections on `address` and handles each on its own thread. Reads time out after
+/// [`REQUEST_TIMEOUT`], handlers that keep a connection open set their own timeout.
+pub fn listen<F>(
+    address: SocketAddr,
+    name: &'static str,
+    handler: F,
+) -> anyhow::Result<JoinHandle<()>>
+where
+    F: Fn(TcpStream) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(address)
+        .map_err(|e| anyhow::anyhow!("failed to listen on {address}: {e}"))?;
+
+    accept(listener, name, handler)
+}
+
+/// Like [`listen`], for a listener bound by the caller, e.g. to learn its port.
+pub fn accept<F>(
+    listener: TcpListener,
+    name: &'static str,
+    handler: F,
+) -> anyhow::Result<JoinHandle<()>>
+where
+    F: Fn(TcpStream) -> anyhow::Result<()> + Send + Sync + 'static,
+{
+    info!("{name} server listening on {}", listener.local_addr()?);
+
+    let handler = Arc::new(handler);
+
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("{name} server failed to accept: {e}");
+                    continue;
+                }
+            };
+
+            if let Err(e) = stream.set_read_timeout(Some(REQUEST_TIMEOUT)) {
+                error!("{name} server failed to set a read timeout: {e}");
+                continue;
+            }
+
+            let handler = handler.clone();
+            std::thread::spawn(move || {
+                let peer = stream.peer_addr();
+                if let Err(e) = handler(stream) {
+                    debug!("{name} connection from {peer:?} ended: {e}");
+                }
+            });
+        }
+    }))
+}
+
+/// Unpredictable enough for session ids on a local network, not for cryptography.
+pub(crate) fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// A secret token from the OS random number generator, as 32 hex digits.
+pub(crate) fn secret_token() -> anyhow::Result<String> {
+    let mut bytes = [0; 16];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|e| anyhow::anyhow!("failed to generate a token: {e}"))?;
+
+    Ok(bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+}
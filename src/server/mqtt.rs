@@ -0,0 +1,518 @@
+//! MQTT bridge that makes the rover a Home Assistant device.
+//!
+//! Under the base topic, `rover/<camera id>` by default:
+//! - `availability`: `online` or `offline`, also the last will
+//! - `stealth`, `camera`: last state set, `ON`/`OFF` and `driving`/`turret`
+//! - `telemetry`: JSON with `battery_level`, `charging`, `signal_strength` and `low_battery`
+//! - `stealth/set`, `camera/set`: commands taking the same payloads as the states
+//! - `stop`: stops all motion, whatever the payload
+//! - `command`: a [`Command`] in its serde representation, e.g. `{"stealth_mode": true}`
+//!
+//! States are retained. Discovery configs for a switch, a select, sensors and a stop button are
+//! published under `homeassistant/` unless discovery is turned off.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::{json, Value};
+
+use crate::rover::{arbiter::Operator, device::DeviceInfo, Camera, Command, Rover};
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often states the rover does not report are checked for changes.
+const STATE_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_CAPACITY: usize = 64;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// User name and password.
+    pub credentials: Option<(String, String)>,
+    /// Defaults to `rover/<camera id>`.
+    pub base_topic: Option<String>,
+    /// Home Assistant discovery prefix, `None` to skip discovery.
+    pub discovery_prefix: Option<String>,
+}
+
+impl MqttConfig {
+    /// Splits a broker given as `host`, `host:port`, `[ipv6]:port` or a bare IPv6 address.
+    pub fn parse_broker(broker: &str) -> anyhow::Result<(String, u16)> {
+        if let Some(rest) = broker.strip_prefix('[') {
+            let Some((host, port)) = rest.split_once(']') else {
+                anyhow::bail!("expected [host]:port, got {broker:?}");
+            };
+            let port = match port {
+                "" => DEFAULT_PORT,
+                port => match port.strip_prefix(':') {
+                    Some(port) => port.parse()?,
+                    None => anyhow::bail!("expected [host]:port, got {broker:?}"),
+                },
+            };
+            return Ok((host.to_string(), port));
+        }
+
+        match broker.split_once(':') {
+            // more than one colon is an IPv6 address without a port
+            Some((_, rest)) if rest.contains(':') => Ok((broker.to_string(), DEFAULT_PORT)),
+            Some((host, port)) => Ok((host.to_string(), port.parse()?)),
+            None => Ok((broker.to_string(), DEFAULT_PORT)),
+        }
+    }
+
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+            credentials: None,
+            base_topic: None,
+            discovery_prefix: Some(DEFAULT_DISCOVERY_PREFIX.to_string()),
+        }
+    }
+}
+
+/// A running bridge, publishes `offline` when finished.
+pub struct MqttBridge {
+    client: Client,
+    base: String,
+    stop: Arc<AtomicBool>,
+    connection_thread: JoinHandle<()>,
+    state_thread: JoinHandle<()>,
+}
+
+impl MqttBridge {
+    /// Does not wait for an unreachable broker, the last will covers that case.
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        // must not publish `online` after `offline`
+        let _ = self.state_thread.join();
+
+        self.client.try_publish(
+            format!("{}/availability", self.base),
+            QoS::AtLeastOnce,
+            true,
+            OFFLINE,
+        )?;
+        self.client.try_disconnect()?;
+        let _ = self.connection_thread.join();
+
+        Ok(())
+    }
+}
+
+/// Connects to the broker and keeps reconnecting until [`MqttBridge::finish`].
+pub fn spawn(config: MqttConfig, rover: Arc<Rover>) -> anyhow::Result<MqttBridge> {
+    let id = rover
+        .device_info()
+        .camera_id
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let base = config
+        .base_topic
+        .clone()
+        .unwrap_or_else(|| format!("rover/{id}"));
+
+    let mut options = MqttOptions::new(format!("rover-{id}"), &config.host, config.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    options.set_last_will(LastWill::new(
+        format!("{base}/availability"),
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some((user, password)) = &config.credentials {
+        options.set_credentials(user, password);
+    }
+
+    info!(
+        "bridging to MQTT broker {}:{} under {base}",
+        config.host, config.port
+    );
+    let (client, mut connection) = Client::new(options, REQUEST_CAPACITY);
+    let stop = Arc::new(AtomicBool::new(false));
+    // states are published again after every reconnect
+    let republish = Arc::new(AtomicBool::new(true));
+
+    let connection_thread = {
+        let client = client.clone();
+        let rover = rover.clone();
+        let base = base.clone();
+        let republish = republish.clone();
+        let stop = stop.clone();
+        let discovery = config.discovery_prefix.map(|prefix| {
+            discovery_configs(&prefix, &id, &base, &device(&id, rover.device_info()))
+        });
+
+        std::thread::spawn(move || {
+            let operator = Operator::new("mqtt");
+
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("connected to MQTT broker");
+                        // the event loop is this thread, blocking on a full queue would hang it
+                        let result = ["stealth/set", "camera/set", "stop", "command"]
+                            .iter()
+                            .try_for_each(|topic| {
+                                client.try_subscribe(format!("{base}/{topic}"), QoS::AtLeastOnce)
+                            })
+                            .and_then(|()| {
+                                discovery.iter().flatten().try_for_each(|(topic, config)| {
+                                    client.try_publish(
+                                        topic,
+                                        QoS::AtLeastOnce,
+                                        true,
+                                        config.to_string(),
+                                    )
+                                })
+                            });
+                        if let Err(e) = result {
+                            warn!("failed to set up MQTT topics: {e}");
+                        }
+                        republish.store(true, Ordering::Relaxed);
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let payload = String::from_utf8_lossy(&publish.payload);
+                        let topic = publish.topic.strip_prefix(&base).unwrap_or(&publish.topic);
+                        if let Err(e) = execute(&rover, &operator, topic, payload.trim()) {
+                            warn!("MQTT command {payload:?} on {} failed: {e}", publish.topic);
+                        }
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        warn!("MQTT connection failed: {e}, retrying");
+                        std::thread::sleep(RECONNECT_INTERVAL);
+                    }
+                }
+            }
+        })
+    };
+
+    let state_thread = {
+        let client = client.clone();
+        let base = base.clone();
+        let stop = stop.clone();
+
+        std::thread::spawn(move || {
+            let telemetry = rover.telemetry();
+            let mut published: Vec<(String, String)> = Vec::new();
+
+            while !stop.load(Ordering::Relaxed) {
+                let states = [
+                    ("availability", Some(ONLINE.to_string())),
+                    (
+                        "stealth",
+                        rover
+                            .stealth_mode()
+                            .map(|enabled| if enabled { "ON" } else { "OFF" }.to_string()),
+                    ),
+                    (
+                        "camera",
+                        rover.active_camera().map(|camera| match camera {
+                            Camera::Driving => "driving".to_string(),
+                            Camera::Turret => "turret".to_string(),
+                        }),
+                    ),
+                    ("telemetry", Some(telemetry_json(&rover).to_string())),
+                ];
+
+                if republish.swap(false, Ordering::Relaxed) {
+                    published.clear();
+                }
+                for (name, state) in states {
+                    let Some(state) = state else {
+                        continue;
+                    };
+                    let topic = format!("{base}/{name}");
+                    if published.contains(&(topic.clone(), state.clone())) {
+                        continue;
+                    }
+
+                    // retried next time while the broker is unreachable
+                    if client
+                        .try_publish(&topic, QoS::AtLeastOnce, true, state.clone())
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    published.retain(|(published, _)| *published != topic);
+                    published.push((topic, state));
+                }
+
+                // telemetry is pushed right away, the rest is polled
+                let _ = telemetry.recv_timeout(STATE_INTERVAL);
+            }
+        })
+    };
+
+    Ok(MqttBridge {
+        client,
+        base,
+        stop,
+        connection_thread,
+        state_thread,
+    })
+}
+
+/// What a message on one of the command topics asks for.
+#[derive(Debug, PartialEq)]
+enum Action {
+    Stop,
+    Send(Command),
+}
+
+/// Runs a command received on `topic`, relative to the base topic.
+fn execute(rover: &Rover, operator: &Operator, topic: &str, payload: &str) -> anyhow::Result<()> {
+    match action(topic, payload)? {
+        Action::Stop => rover.stop(operator),
+        Action::Send(command) => rover.send_command(operator, command),
+    }
+}
+
+fn action(topic: &str, payload: &str) -> anyhow::Result<Action> {
+    let command = match topic {
+        "/stealth/set" => match payload {
+            "ON" => Command::StealthMode(true),
+            "OFF" => Command::StealthMode(false),
+            _ => anyhow::bail!("expected ON or OFF"),
+        },
+        "/camera/set" => match payload {
+            "driving" => Command::UseCamera(Camera::Driving),
+            "turret" => Command::UseCamera(Camera::Turret),
+            _ => anyhow::bail!("expected driving or turret"),
+        },
+        "/stop" => return Ok(Action::Stop),
+        "/command" => serde_json::from_str(payload)?,
+        _ => anyhow::bail!("unexpected topic"),
+    };
+
+    Ok(Action::Send(command))
+}
+
+fn telemetry_json(rover: &Rover) -> Value {
+    let telemetry = rover.latest_telemetry();
+
+    json!({
+        "battery_level": telemetry.battery_level,
+        "charging": telemetry.charging,
+        "signal_strength": telemetry.signal_strength,
+        "low_battery": telemetry.is_low_battery(),
+    })
+}
+
+/// The Home Assistant device all entities belong to.
+fn device(id: &str, info: &DeviceInfo) -> Value {
+    json!({
+        "identifiers": [id],
+        "name": format!("Rover {}", info.camera_id),
+        "manufacturer": "Brookstone",
+        "model": "Rover Revolution",
+        "sw_version": info.app_version.to_string(),
+    })
+}
+
+/// Home Assistant discovery topics and their configs.
+fn discovery_configs(prefix: &str, id: &str, base: &str, device: &Value) -> Vec<(String, Value)> {
+    let availability = format!("{base}/availability");
+
+    let entities = [
+        (
+            "switch",
+            "stealth",
+            json!({
+                "name": "Stealth",
+                "icon": "mdi:weather-night",
+                "state_topic": format!("{base}/stealth"),
+                "command_topic": format!("{base}/stealth/set"),
+            }),
+        ),
+        (
+            "select",
+            "camera",
+            json!({
+                "name": "Camera",
+                "icon": "mdi:cctv",
+                "state_topic": format!("{base}/camera"),
+                "command_topic": format!("{base}/camera/set"),
+                "options": ["driving", "turret"],
+            }),
+        ),
+        (
+            "sensor",
+            "battery",
+            json!({
+                "name": "Battery",
+                "device_class": "battery",
+                "unit_of_measurement": "%",
+                "state_topic": format!("{base}/telemetry"),
+                "value_template": "{{ value_json.battery_level }}",
+            }),
+        ),
+        (
+            "sensor",
+            "signal",
+            json!({
+                "name": "Signal strength",
+                "icon": "mdi:wifi",
+                "unit_of_measurement": "%",
+                "state_topic": format!("{base}/telemetry"),
+                "value_template": "{{ value_json.signal_strength }}",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "charging",
+            json!({
+                "name": "Charging",
+                "device_class": "battery_charging",
+                "state_topic": format!("{base}/telemetry"),
+                "value_template": "{{ 'ON' if value_json.charging else 'OFF' }}",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "low_battery",
+            json!({
+                "name": "Low battery",
+                "device_class": "battery",
+                "state_topic": format!("{base}/telemetry"),
+                "value_template": "{{ 'ON' if value_json.low_battery else 'OFF' }}",
+            }),
+        ),
+        (
+            "button",
+            "stop",
+            json!({
+                "name": "Stop",
+                "icon": "mdi:stop",
+                "command_topic": format!("{base}/stop"),
+            }),
+        ),
+    ];
+
+    entities
+        .into_iter()
+        .map(|(component, object, mut config)| {
+            config["unique_id"] = json!(format!("{id}_{object}"));
+            config["availability_topic"] = json!(availability);
+            config["device"] = device.clone();
+            (format!("{prefix}/{component}/{id}/{object}/config"), config)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rover::{Direction, HorizontalDirection, Speed};
+
+    use super::*;
+
+    #[test]
+    fn parses_brokers() {
+        let parse = |broker| MqttConfig::parse_broker(broker).unwrap();
+        assert_eq!(parse("broker"), ("broker".to_string(), DEFAULT_PORT));
+        assert_eq!(parse("broker:8883"), ("broker".to_string(), 8883));
+        assert_eq!(parse("10.0.0.2:8883"), ("10.0.0.2".to_string(), 8883));
+        assert_eq!(parse("[::1]:8883"), ("::1".to_string(), 8883));
+        assert_eq!(parse("[::1]"), ("::1".to_string(), DEFAULT_PORT));
+        assert_eq!(parse("::1"), ("::1".to_string(), DEFAULT_PORT));
+        assert_eq!(parse("fe80::2"), ("fe80::2".to_string(), DEFAULT_PORT));
+
+        assert!(MqttConfig::parse_broker("broker:mqtt").is_err());
+        assert!(MqttConfig::parse_broker("[::1").is_err());
+        assert!(MqttConfig::parse_broker("[::1]8883").is_err());
+    }
+
+    #[test]
+    fn maps_topics_to_commands() {
+        assert_eq!(
+            action("/stealth/set", "ON").unwrap(),
+            Action::Send(Command::StealthMode(true))
+        );
+        assert_eq!(
+            action("/stealth/set", "OFF").unwrap(),
+            Action::Send(Command::StealthMode(false))
+        );
+        assert_eq!(
+            action("/camera/set", "driving").unwrap(),
+            Action::Send(Command::UseCamera(Camera::Driving))
+        );
+        assert_eq!(
+            action("/camera/set", "turret").unwrap(),
+            Action::Send(Command::UseCamera(Camera::Turret))
+        );
+        assert_eq!(action("/stop", "").unwrap(), Action::Stop);
+        assert_eq!(action("/stop", "PRESS").unwrap(), Action::Stop);
+        assert_eq!(
+            action("/command", r#"{"drive": ["forward", "left", "slow"]}"#).unwrap(),
+            Action::Send(Command::Drive(
+                Direction::Forward,
+                HorizontalDirection::Left,
+                Speed::Slow
+            ))
+        );
+
+        assert!(action("/stealth/set", "on").is_err());
+        assert!(action("/camera/set", "rear").is_err());
+        assert!(action("/command", "{}").is_err());
+        assert!(action("/stealth", "ON").is_err());
+    }
+
+    #[test]
+    fn publishes_discovery_configs() {
+        let device = json!({ "identifiers": ["SIM1"] });
+        let configs = discovery_configs("homeassistant", "SIM1", "rover/SIM1", &device);
+
+        let topics = configs
+            .iter()
+            .map(|(topic, _)| topic.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/switch/SIM1/stealth/config",
+                "homeassistant/select/SIM1/camera/config",
+                "homeassistant/sensor/SIM1/battery/config",
+                "homeassistant/sensor/SIM1/signal/config",
+                "homeassistant/binary_sensor/SIM1/charging/config",
+                "homeassistant/binary_sensor/SIM1/low_battery/config",
+                "homeassistant/button/SIM1/stop/config",
+            ]
+        );
+
+        for (topic, config) in &configs {
+            let object = topic.split('/').nth(3).unwrap();
+            assert_eq!(config["unique_id"], format!("SIM1_{object}"));
+            assert_eq!(config["availability_topic"], "rover/SIM1/availability");
+            assert_eq!(config["device"], device);
+        }
+
+        let (_, stealth) = &configs[0];
+        assert_eq!(stealth["state_topic"], "rover/SIM1/stealth");
+        assert_eq!(stealth["command_topic"], "rover/SIM1/stealth/set");
+        let (_, camera) = &configs[1];
+        assert_eq!(camera["options"], json!(["driving", "turret"]));
+        let (_, stop) = &configs[6];
+        assert_eq!(stop["command_topic"], "rover/SIM1/stop");
+    }
+}
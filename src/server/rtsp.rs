@@ -0,0 +1,696 @@
+//! RTSP server re-publishing the rover camera for VLC, ffmpeg and NVRs.
+//!
+//! Each video type has its own path, `/video1` (640x480) and `/video2` (320x240), with an H.264
+//! track (RFC 6184, packetization mode 1) and a PCMU audio track. RTP goes over UDP or
+//! interleaved in the RTSP connection, whichever the client asks for. RTCP is not sent.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{debug, info};
+
+use crate::rover::media::{nal_units, StreamPacket, AUDIO_SAMPLE_RATE};
+
+use super::{http::HttpRequest, listen, random, MediaBroadcast};
+
+const VIDEO_TYPES: [u8; 2] = [1, 2];
+
+const VIDEO_TRACK: usize = 0;
+const AUDIO_TRACK: usize = 1;
+
+const VIDEO_PAYLOAD_TYPE: u8 = 96;
+const AUDIO_PAYLOAD_TYPE: u8 = 0;
+const VIDEO_CLOCK_RATE: u32 = 90_000;
+
+/// Largest RTP payload, keeps packets below a typical MTU.
+const MAX_PAYLOAD: usize = 1400;
+
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_FU_A: u8 = 28;
+
+const CLIENT_BACKLOG: usize = 64;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Clients keep their session alive with a request at least this often.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serves `rtsp://<address>/video1` and `/video2`.
+pub fn spawn(address: SocketAddr, media: Arc<MediaBroadcast>) -> anyhow::Result<JoinHandle<()>> {
+    let parameter_sets = ParameterSets::track(&media);
+
+    listen(address, "rtsp", move |stream| {
+        Connection::new(stream, &media, &parameter_sets)?.run()
+    })
+}
+
+/// Latest SPS and PPS of a video type, NAL units without start codes.
+#[derive(Debug, Clone, Default)]
+struct ParameterSet {
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+}
+
+/// Parameter sets of each video type, for the SDP of new clients.
+#[derive(Default)]
+struct ParameterSets {
+    sets: Mutex<[ParameterSet; 2]>,
+}
+
+impl ParameterSets {
+    fn track(media: &MediaBroadcast) -> Arc<Self> {
+        let parameter_sets = Arc::new(Self::default());
+        let packets = media.subscribe(CLIENT_BACKLOG);
+
+        {
+            let parameter_sets = parameter_sets.clone();
+            std::thread::spawn(move || {
+                while let Ok(packet) = packets.recv() {
+                    let StreamPacket::Video {
+                        video_type, data, ..
+                    } = &*packet
+                    else {
+                        continue;
+                    };
+                    let Some(index) = VIDEO_TYPES.iter().position(|t| t == video_type) else {
+                        continue;
+                    };
+
+                    // the rover may send SPS and PPS in separate packets
+                    for nal in nal_units(data) {
+                        let set = &mut parameter_sets.sets.lock().unwrap()[index];
+                        match nal[0] & 0x1F {
+                            NAL_SPS => set.sps = Some(nal.to_vec()),
+                            NAL_PPS => set.pps = Some(nal.to_vec()),
+                            _ => {}
+                        }
+                    }
+                }
+            });
+        }
+
+        parameter_sets
+    }
+
+    fn get(&self, video_type: u8) -> Option<(Vec<u8>, Vec<u8>)> {
+        let index = VIDEO_TYPES.iter().position(|t| *t == video_type)?;
+        let set = self.sets.lock().unwrap()[index].clone();
+        Some((set.sps?, set.pps?))
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    sdp: String,
+}
+
+impl Response {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            sdp: String::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+/// Where the RTP packets of one track go.
+enum Transport {
+    Udp {
+        socket: UdpSocket,
+        client: SocketAddr,
+    },
+    Interleaved {
+        channel: u8,
+    },
+}
+
+struct Session {
+    id: String,
+    video_type: u8,
+    tracks: [Option<Transport>; 2],
+    playing: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+impl Session {
+    /// Value of the `Session` header, which also tells the client how often to keep it alive.
+    fn header(&self) -> String {
+        format!("{};timeout={}", self.id, SESSION_TIMEOUT.as_secs())
+    }
+
+    fn stop(&mut self) {
+        if let Some((stop, thread)) = self.playing.take() {
+            stop.store(true, Ordering::Relaxed);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct Connection<'a> {
+    reader: BufReader<TcpStream>,
+    /// Shared with the streaming thread for interleaved RTP.
+    writer: Arc<Mutex<TcpStream>>,
+    media: &'a MediaBroadcast,
+    parameter_sets: &'a ParameterSets,
+    session: Option<Session>,
+}
+
+impl<'a> Connection<'a> {
+    fn new(
+        stream: TcpStream,
+        media: &'a MediaBroadcast,
+        parameter_sets: &'a ParameterSets,
+    ) -> anyhow::Result<Self> {
+        stream.set_read_timeout(Some(SESSION_TIMEOUT))?;
+
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: Arc::new(Mutex::new(stream)),
+            media,
+            parameter_sets,
+            session: None,
+        })
+    }
+
+    fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            // interleaved RTCP from the client, ignored
+            if self.reader.fill_buf()?.first() == Some(&b'$') {
+                let mut header = [0; 4];
+                self.reader.read_exact(&mut header)?;
+                let length = u16::from_be_bytes([header[2], header[3]]);
+                std::io::copy(
+                    &mut (&mut self.reader).take(length as u64),
+                    &mut std::io::sink(),
+                )?;
+                continue;
+            }
+
+            let request = HttpRequest::read_from(&mut self.reader)?;
+            let cseq = request.header("CSeq").unwrap_or("0").to_string();
+            debug!("rtsp {} {}", request.method, request.path);
+
+            let response = self.handle(&request).unwrap_or_else(|e| {
+                debug!("rtsp {} failed: {e}", request.method);
+                Response::new(400)
+            });
+
+            let mut head = format!(
+                "RTSP/1.0 {} {}\r\nCSeq: {cseq}\r\n",
+                response.status,
+                super::http::status_text(response.status)
+            );
+            for (name, value) in &response.headers {
+                head += &format!("{name}: {value}\r\n");
+            }
+            if !response.sdp.is_empty() {
+                head += &format!(
+                    "Content-Type: application/sdp\r\nContent-Length: {}\r\n",
+                    response.sdp.len()
+                );
+            }
+            head += "\r\n";
+            head += &response.sdp;
+
+            self.writer.lock().unwrap().write_all(head.as_bytes())?;
+
+            if request.method == "TEARDOWN" {
+                return Ok(());
+            }
+        }
+    }
+
+    fn handle(&mut self, request: &HttpRequest) -> anyhow::Result<Response> {
+        let (video_type, track) = parse_target(&request.path)?;
+
+        match request.method.as_str() {
+            "OPTIONS" => Ok(Response::new(200).header(
+                "Public",
+                "OPTIONS, DESCRIBE, SETUP, PLAY, TEARDOWN, GET_PARAMETER",
+            )),
+            "DESCRIBE" => Ok(Response {
+                sdp: self.sdp(video_type)?,
+                ..Response::new(200).header("Content-Base", format!("{}/", request.path))
+            }),
+            "SETUP" => {
+                let track = track.ok_or_else(|| anyhow::anyhow!("SETUP without track"))?;
+                let transport = request
+                    .header("Transport")
+                    .ok_or_else(|| anyhow::anyhow!("SETUP without transport"))?;
+                let (transport, reply) = self.setup_transport(transport)?;
+
+                let session = self.session.get_or_insert_with(|| Session {
+                    id: format!("{:016X}", random()),
+                    video_type,
+                    tracks: [None, None],
+                    playing: None,
+                });
+                if session.video_type != video_type {
+                    anyhow::bail!(
+                        "session already set up for video type {}",
+                        session.video_type
+                    );
+                }
+                session.tracks[track] = Some(transport);
+
+                Ok(Response::new(200)
+                    .header("Transport", reply)
+                    .header("Session", session.header()))
+            }
+            "PLAY" => {
+                let session = self
+                    .session
+                    .as_mut()
+                    .ok_or_else(|| anyhow::anyhow!("PLAY before SETUP"))?;
+
+                if session.playing.is_none() {
+                    info!("rtsp client playing video type {}", session.video_type);
+
+                    let stop = Arc::new(AtomicBool::new(false));
+                    let thread =
+                        spawn_streamer(session, self.media, self.writer.clone(), stop.clone())?;
+                    session.playing = Some((stop, thread));
+                }
+
+                Ok(Response::new(200).header("Session", session.header()))
+            }
+            "TEARDOWN" => {
+                self.session = None;
+                Ok(Response::new(200))
+            }
+            "GET_PARAMETER" => Ok(Response::new(200)),
+            _ => Ok(Response::new(405)),
+        }
+    }
+
+    fn sdp(&self, video_type: u8) -> anyhow::Result<String> {
+        let address = self.writer.lock().unwrap().local_addr()?.ip();
+        let family = if address.is_ipv4() { "IP4" } else { "IP6" };
+
+        let mut fmtp = "packetization-mode=1".to_string();
+        if let Some((sps, pps)) = self.parameter_sets.get(video_type) {
+            fmtp += &format!(
+                ";profile-level-id={:02X}{:02X}{:02X};sprop-parameter-sets={},{}",
+                sps.get(1).unwrap_or(&0),
+                sps.get(2).unwrap_or(&0),
+                sps.get(3).unwrap_or(&0),
+                STANDARD.encode(&sps),
+                STANDARD.encode(&pps)
+            );
+        }
+
+        Ok(format!(
+            "v=0\r\n\
+             o=- 0 0 IN {family} {address}\r\n\
+             s=Rover Revolution\r\n\
+             c=IN {family} {address}\r\n\
+             t=0 0\r\n\
+             a=control:*\r\n\
+             m=video 0 RTP/AVP {VIDEO_PAYLOAD_TYPE}\r\n\
+             a=rtpmap:{VIDEO_PAYLOAD_TYPE} H264/{VIDEO_CLOCK_RATE}\r\n\
+             a=fmtp:{VIDEO_PAYLOAD_TYPE} {fmtp}\r\n\
+             a=control:trackID={VIDEO_TRACK}\r\n\
+             m=audio 0 RTP/AVP {AUDIO_PAYLOAD_TYPE}\r\n\
+             a=rtpmap:{AUDIO_PAYLOAD_TYPE} PCMU/{AUDIO_SAMPLE_RATE}\r\n\
+             a=control:trackID={AUDIO_TRACK}\r\n"
+        ))
+    }
+
+    /// Picks the transport from the client's `Transport` header, returns it with the header
+    /// for the reply.
+    fn setup_transport(&self, header: &str) -> anyhow::Result<(Transport, String)> {
+        let param = |name: &str| {
+            header
+                .split(';')
+                .find_map(|part| part.trim().strip_prefix(name)?.strip_prefix('='))
+        };
+        let range = |value: &str| -> anyhow::Result<(u16, u16)> {
+            let (first, second) = value.split_once('-').unwrap_or((value, value));
+            Ok((first.parse()?, second.parse()?))
+        };
+
+        if header.contains("RTP/AVP/TCP") {
+            let (rtp, rtcp) = range(param("interleaved").unwrap_or("0-1"))?;
+            let channel = u8::try_from(rtp)?;
+
+            return Ok((
+                Transport::Interleaved { channel },
+                format!("RTP/AVP/TCP;unicast;interleaved={rtp}-{rtcp}"),
+            ));
+        }
+
+        let (rtp, rtcp) = range(
+            param("client_port").ok_or_else(|| anyhow::anyhow!("no client_port in {header}"))?,
+        )?;
+        let stream = self.writer.lock().unwrap();
+        let peer: IpAddr = stream.peer_addr()?.ip();
+        let socket = UdpSocket::bind((stream.local_addr()?.ip(), 0))?;
+        let server_port = socket.local_addr()?.port();
+
+        Ok((
+            Transport::Udp {
+                socket,
+                client: SocketAddr::new(peer, rtp),
+            },
+            // no RTCP is sent, so there is no port for it
+            format!("RTP/AVP;unicast;client_port={rtp}-{rtcp};server_port={server_port}"),
+        ))
+    }
+}
+
+/// Splits `rtsp://host/video1/trackID=0` into video type and track.
+fn parse_target(target: &str) -> anyhow::Result<(u8, Option<usize>)> {
+    if target == "*" {
+        return Ok((VIDEO_TYPES[0], None));
+    }
+
+    let path = match target.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => target,
+    };
+    let mut segments = path.trim_matches('/').split('/');
+
+    let video_type = match segments.next() {
+        Some("video1") => 1,
+        Some("video2") => 2,
+        _ => anyhow::bail!("unknown stream {path}"),
+    };
+    let track = match segments.next() {
+        Some(track) => Some(match track.strip_prefix("trackID=") {
+            Some("0") => VIDEO_TRACK,
+            Some("1") => AUDIO_TRACK,
+            _ => anyhow::bail!("unknown track {track}"),
+        }),
+        None => None,
+    };
+
+    Ok((video_type, track))
+}
+
+/// RTP state of one track.
+struct RtpTrack {
+    transport: Transport,
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+}
+
+impl RtpTrack {
+    fn send(
+        &mut self,
+        writer: &Mutex<TcpStream>,
+        timestamp: u32,
+        marker: bool,
+        payload: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut packet = Vec::with_capacity(12 + payload.len());
+        packet.push(0x80);
+        packet.push(self.payload_type | if marker { 0x80 } else { 0 });
+        packet.extend(self.sequence.to_be_bytes());
+        packet.extend(timestamp.to_be_bytes());
+        packet.extend(self.ssrc.to_be_bytes());
+        packet.extend(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+
+        match &self.transport {
+            Transport::Udp { socket, client } => {
+                socket.send_to(&packet, client)?;
+            }
+            Transport::Interleaved { channel } => {
+                let mut frame = vec![b'$', *channel];
+                frame.extend((packet.len() as u16).to_be_bytes());
+                frame.extend(packet);
+                writer.lock().unwrap().write_all(&frame)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends one access unit, fragmenting NAL units that do not fit into one packet (FU-A).
+    fn send_video(
+        &mut self,
+        writer: &Mutex<TcpStream>,
+        timestamp: u32,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let nals = nal_units(data).collect::<Vec<_>>();
+
+        for (i, nal) in nals.iter().enumerate() {
+            let last = i + 1 == nals.len();
+
+            if nal.len() <= MAX_PAYLOAD {
+                self.send(writer, timestamp, last, nal)?;
+                continue;
+            }
+
+            let indicator = (nal[0] & 0xE0) | NAL_FU_A;
+            let nal_type = nal[0] & 0x1F;
+            let chunks = nal[1..].chunks(MAX_PAYLOAD - 2).collect::<Vec<_>>();
+
+            for (j, chunk) in chunks.iter().enumerate() {
+                let start = if j == 0 { 0x80 } else { 0 };
+                let end = if j + 1 == chunks.len() { 0x40 } else { 0 };
+
+                let mut payload = vec![indicator, start | end | nal_type];
+                payload.extend_from_slice(chunk);
+                self.send(writer, timestamp, last && end != 0, &payload)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn spawn_streamer(
+    session: &mut Session,
+    media: &MediaBroadcast,
+    writer: Arc<Mutex<TcpStream>>,
+    stop: Arc<AtomicBool>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let mut tracks = [VIDEO_TRACK, AUDIO_TRACK].map(|track| {
+        session.tracks[track].take().map(|transport| RtpTrack {
+            transport,
+            payload_type: if track == VIDEO_TRACK {
+                VIDEO_PAYLOAD_TYPE
+            } else {
+                AUDIO_PAYLOAD_TYPE
+            },
+            ssrc: random() as u32,
+            sequence: random() as u16,
+        })
+    });
+    if tracks.iter().all(Option::is_none) {
+        anyhow::bail!("PLAY without any track set up");
+    }
+
+    let video_type = session.video_type;
+    let packets = media.subscribe(CLIENT_BACKLOG);
+
+    Ok(std::thread::spawn(move || {
+        let [video, audio] = &mut tracks;
+        let mut synced = false;
+
+        while !stop.load(Ordering::Relaxed) {
+            let packet = match packets.recv_timeout(POLL_INTERVAL) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+            if packets.take_lagged() {
+                synced = false;
+            }
+
+            // media header timestamps are in milliseconds
+            let result = match (&*packet, video.as_mut(), audio.as_mut()) {
+                (
+                    StreamPacket::Video {
+                        video_type: packet_type,
+                        timestamp,
+                        data,
+                        ..
+                    },
+                    Some(video),
+                    _,
+                ) if *packet_type == video_type => {
+                    synced |= packet.is_keyframe();
+                    if !synced {
+                        continue;
+                    }
+
+                    let timestamp = timestamp.wrapping_mul(VIDEO_CLOCK_RATE / 1000);
+                    video.send_video(&writer, timestamp, data)
+                }
+                (StreamPacket::Audio { timestamp, .. }, _, Some(audio)) => {
+                    let samples = packet.to_pcm().unwrap_or_default();
+                    let payload = samples.into_iter().map(mulaw).collect::<Vec<_>>();
+
+                    let timestamp = timestamp.wrapping_mul(AUDIO_SAMPLE_RATE / 1000);
+                    audio.send(&writer, timestamp, true, &payload)
+                }
+                _ => continue,
+            };
+
+            if let Err(e) = result {
+                debug!("rtsp client gone: {e}");
+                break;
+            }
+        }
+    }))
+}
+
+/// G.711 µ-law encoding of one sample.
+fn mulaw(sample: i16) -> u8 {
+    const BIAS: i32 = 0x84;
+    const CLIP: i32 = 32635;
+
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = (sample as i32).abs().min(CLIP) + BIAS;
+
+    // position of the highest set bit above the 7 bits covered by the bias
+    let exponent = (31 - (magnitude >> 7).leading_zeros()) as i32;
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0F;
+
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Sends one access unit over an interleaved track and returns the RTP packets written.
+    fn packetize(data: &[u8]) -> Vec<Vec<u8>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let writer = Mutex::new(TcpStream::connect(listener.local_addr().unwrap()).unwrap());
+        let (mut reader, _) = listener.accept().unwrap();
+
+        let mut track = RtpTrack {
+            transport: Transport::Interleaved { channel: 0 },
+            payload_type: VIDEO_PAYLOAD_TYPE,
+            ssrc: 1,
+            sequence: u16::MAX,
+        };
+        track.send_video(&writer, 1234, data).unwrap();
+        drop(writer);
+
+        let mut stream = Vec::new();
+        reader.read_to_end(&mut stream).unwrap();
+
+        let mut packets = Vec::new();
+        let mut rest = stream.as_slice();
+        while !rest.is_empty() {
+            assert_eq!(rest[..2], [b'$', 0]);
+            let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            packets.push(rest[4..4 + len].to_vec());
+            rest = &rest[4 + len..];
+        }
+        packets
+    }
+
+    #[test]
+    fn fragments_large_nal_units() {
+        let sps = [0x67, 0x42, 0x00, 0x1E];
+        // no zeros, so the payload never looks like a start code
+        let idr = [0x65]
+            .into_iter()
+            .chain((0..3000).map(|i| (i % 255 + 1) as u8))
+            .collect::<Vec<_>>();
+
+        let mut data = vec![0, 0, 0, 1];
+        data.extend(sps);
+        data.extend([0, 0, 0, 1]);
+        data.extend(&idr);
+
+        let packets = packetize(&data);
+        // the SPS fits into one packet, the 3000 bytes after the IDR header need three
+        assert_eq!(packets.len(), 4);
+
+        for (i, packet) in packets.iter().enumerate() {
+            let marker = i == packets.len() - 1;
+            assert_eq!(packet[0], 0x80);
+            assert_eq!(
+                packet[1],
+                VIDEO_PAYLOAD_TYPE | if marker { 0x80 } else { 0 }
+            );
+            assert_eq!(
+                u16::from_be_bytes([packet[2], packet[3]]),
+                u16::MAX.wrapping_add(i as u16)
+            );
+            assert_eq!(packet[4..8], 1234u32.to_be_bytes());
+            assert!(packet.len() - 12 <= MAX_PAYLOAD);
+        }
+
+        assert_eq!(packets[0][12..], sps);
+
+        let fragments = &packets[1..];
+        // NRI of the IDR header with type 28, then start and end bits with type 5
+        let headers = fragments
+            .iter()
+            .map(|packet| (packet[12], packet[13]))
+            .collect::<Vec<_>>();
+        assert_eq!(headers, [(0x7C, 0x85), (0x7C, 0x05), (0x7C, 0x45)]);
+
+        let reassembled = fragments
+            .iter()
+            .flat_map(|packet| packet[14..].iter().copied())
+            .collect::<Vec<_>>();
+        assert_eq!(reassembled, idr[1..]);
+    }
+
+    #[test]
+    fn encodes_mulaw() {
+        assert_eq!(mulaw(0), 0xFF);
+        assert_eq!(mulaw(1), 0xFF);
+        assert_eq!(mulaw(-1), 0x7F);
+        assert_eq!(mulaw(i16::MAX), 0x80);
+        assert_eq!(mulaw(i16::MIN), 0x00);
+        // the first step of the second segment
+        assert_eq!(mulaw(0x84), 0xEF);
+        assert_eq!(mulaw(-0x84), 0x6F);
+    }
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(parse_target("*").unwrap(), (1, None));
+        assert_eq!(parse_target("rtsp://rover:8554/video1").unwrap(), (1, None));
+        assert_eq!(
+            parse_target("rtsp://rover:8554/video2/").unwrap(),
+            (2, None)
+        );
+        assert_eq!(
+            parse_target("rtsp://rover/video2/trackID=1").unwrap(),
+            (2, Some(AUDIO_TRACK))
+        );
+        assert_eq!(
+            parse_target("/video1/trackID=0").unwrap(),
+            (1, Some(VIDEO_TRACK))
+        );
+
+        assert!(parse_target("rtsp://rover").is_err());
+        assert!(parse_target("rtsp://rover/video3").is_err());
+        assert!(parse_target("rtsp://rover/video1/trackID=2").is_err());
+        assert!(parse_target("rtsp://rover/video1/audio").is_err());
+    }
+}
@@ -0,0 +1,399 @@
+//! Low-bandwidth transcoding for slow links: the video is decoded, scaled down and re-encoded at
+//! a lower frame rate and bitrate into a broadcast of its own, which any server or recording can
+//! take in place of the rover's. Audio is passed through, and nothing is decoded while nobody
+//! subscribes.
+//!
+//! The bitrate adapts to the throughput subscribers actually manage: when any of them falls
+//! behind it backs off below what was delivered, otherwise it creeps back up to the configured
+//! one. It changes at keyframes only, which are produced every [`KEYFRAME_INTERVAL_MS`].
+
+use std::{
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info};
+use openh264::{
+    decoder::{DecodedYUV, Decoder},
+    encoder::{Encoder, EncoderConfig, RateControlMode},
+    formats::YUVSource,
+    Timestamp,
+};
+
+use crate::rover::{media::StreamPacket, metrics::Metrics};
+
+use super::{broadcast::Subscription, MediaBroadcast};
+
+const BACKLOG: usize = 64;
+/// Stream time between keyframes, which is also how often the bitrate adapts.
+pub const KEYFRAME_INTERVAL_MS: u32 = 2000;
+/// Share of the delivered throughput to back off to.
+const BACKOFF: f64 = 0.8;
+const GROWTH: f64 = 1.1;
+/// Bytes of a video payload in front of the H.264 data.
+const VIDEO_HEADER_LEN: i32 = 13;
+/// Video types of the rover's 640x480 and 320x240 streams.
+const VIDEO_TYPE_VGA: u8 = 1;
+const VIDEO_TYPE_QVGA: u8 = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct TranscodeConfig {
+    /// Output size, both even. Up to 320x240 the output is labeled as the rover's 320x240 video
+    /// type, larger ones as its 640x480 one.
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second, at most the rate of the video.
+    pub frame_rate: f32,
+    /// Bits per second while subscribers keep up.
+    pub bitrate: u32,
+    /// Bits per second the adaptation does not go below.
+    pub min_bitrate: u32,
+}
+
+impl Default for TranscodeConfig {
+    fn default() -> Self {
+        Self {
+            width: 320,
+            height: 240,
+            frame_rate: 10.0,
+            bitrate: 250_000,
+            min_bitrate: 50_000,
+        }
+    }
+}
+
+/// Transcodes `media` until it ends. Frames the decoder rejects count as decode errors, and
+/// frames subscribers of the output miss as dropped frames.
+pub fn spawn(
+    media: &MediaBroadcast,
+    metrics: Arc<Metrics>,
+    config: TranscodeConfig,
+) -> anyhow::Result<(Arc<MediaBroadcast>, JoinHandle<()>)> {
+    if config.width == 0 || config.height == 0 || config.width % 2 + config.height % 2 > 0 {
+        anyhow::bail!(
+            "transcoding needs an even width and height, got {}x{}",
+            config.width,
+            config.height
+        );
+    }
+    if !config.frame_rate.is_finite()
+        || config.frame_rate <= 0.0
+        || config.min_bitrate > config.bitrate
+    {
+        anyhow::bail!("invalid transcoding frame rate or bitrates: {config:?}");
+    }
+
+    let packets = media.subscribe(BACKLOG);
+    let output = MediaBroadcast::new();
+
+    let thread = {
+        let output = output.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = transcode_loop(&packets, &output, &metrics, config) {
+                error!("transcoder stopped: {e}");
+            }
+        })
+    };
+
+    Ok((output, thread))
+}
+
+fn transcode_loop(
+    packets: &Subscription<Arc<StreamPacket>>,
+    output: &MediaBroadcast,
+    metrics: &Metrics,
+    config: TranscodeConfig,
+) -> anyhow::Result<()> {
+    let interval = (1000.0 / config.frame_rate) as u32;
+    let output_type = if config.width <= 320 && config.height <= 240 {
+        VIDEO_TYPE_QVGA
+    } else {
+        VIDEO_TYPE_VGA
+    };
+    let mut decoder = Decoder::new()?;
+    let mut synced = false;
+    // with the stream time it started at
+    let mut encoder: Option<(Encoder, u32)> = None;
+    let mut last_frame: Option<u32> = None;
+    let mut rate = RateControl::new(&config, Instant::now());
+
+    while let Ok(packet) = packets.recv() {
+        let StreamPacket::Video {
+            timestamp, data, ..
+        } = &*packet
+        else {
+            // also forgets subscribers that left
+            output.send(packet);
+            continue;
+        };
+
+        if packets.take_lagged() {
+            synced = false;
+        }
+        if output.subscriber_count() == 0 {
+            synced = false;
+            encoder = None;
+            continue;
+        }
+        synced |= packet.is_keyframe();
+        if !synced {
+            continue;
+        }
+
+        // every frame is decoded, the following ones depend on it
+        let due = last_frame.is_none_or(|last| timestamp.wrapping_sub(last) >= interval);
+        let frame = match decoder.decode(data) {
+            Ok(Some(frame)) if due => Frame::scaled(&frame, config.width, config.height),
+            Ok(_) => continue,
+            Err(e) => {
+                debug!("failed to decode frame: {e}");
+                metrics.record_decode_error();
+                synced = false;
+                continue;
+            }
+        };
+        last_frame = Some(*timestamp);
+
+        let (encoder, _) = match &mut encoder {
+            Some((_, started)) if timestamp.wrapping_sub(*started) < KEYFRAME_INTERVAL_MS => {
+                encoder.as_mut().unwrap()
+            }
+            _ => {
+                if let Some(bitrate) = rate.adapt(Instant::now()) {
+                    info!("transcoding at {} kbit/s", bitrate / 1000);
+                }
+                let config = EncoderConfig::new(config.width, config.height)
+                    .rate_control_mode(RateControlMode::Bitrate)
+                    .set_bitrate_bps(rate.bitrate)
+                    .max_frame_rate(config.frame_rate);
+                encoder.insert((Encoder::with_config(config)?, *timestamp))
+            }
+        };
+
+        let encoded = encoder
+            .encode_at(&frame, Timestamp::from_millis(*timestamp as u64))?
+            .to_vec();
+        // skipped by the rate control
+        if encoded.is_empty() {
+            continue;
+        }
+
+        let len = encoded.len();
+        let missed = output.send(Arc::new(StreamPacket::Video {
+            length: VIDEO_HEADER_LEN + len as i32,
+            video_type: output_type,
+            video_length: len as i32,
+            timestamp: *timestamp,
+            data: encoded,
+        }));
+        metrics.record_dropped_frames(missed as u64);
+        rate.record(len, missed > 0);
+    }
+
+    Ok(())
+}
+
+/// Picks the bitrate for the next keyframe interval from what was delivered in the last one.
+struct RateControl {
+    bitrate: u32,
+    min: u32,
+    max: u32,
+    since: Instant,
+    /// Bytes of the frames no subscriber missed.
+    delivered: u64,
+    congested: bool,
+}
+
+impl RateControl {
+    fn new(config: &TranscodeConfig, now: Instant) -> Self {
+        Self {
+            bitrate: config.bitrate,
+            min: config.min_bitrate,
+            max: config.bitrate,
+            since: now,
+            delivered: 0,
+            congested: false,
+        }
+    }
+
+    fn record(&mut self, bytes: usize, missed: bool) {
+        if missed {
+            self.congested = true;
+        } else {
+            self.delivered += bytes as u64;
+        }
+    }
+
+    /// Starts a new interval, returns the new bitrate if it changed.
+    fn adapt(&mut self, now: Instant) -> Option<u32> {
+        let elapsed = now.duration_since(self.since);
+        let delivered =
+            (self.delivered * 8) as f64 / elapsed.max(Duration::from_millis(1)).as_secs_f64();
+        let target = if self.congested {
+            delivered.min(self.bitrate as f64) * BACKOFF
+        } else {
+            self.bitrate as f64 * GROWTH
+        };
+        let target = (target as u32).clamp(self.min, self.max);
+
+        self.since = now;
+        self.delivered = 0;
+        self.congested = false;
+
+        (target != self.bitrate).then(|| {
+            self.bitrate = target;
+            target
+        })
+    }
+}
+
+/// An I420 frame scaled from a decoded one.
+struct Frame {
+    width: usize,
+    height: usize,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+}
+
+impl Frame {
+    fn scaled(source: &DecodedYUV, width: u32, height: u32) -> Self {
+        let (width, height) = (width as usize, height as usize);
+        let (source_width, source_height) = source.dimension_y();
+        let (y_stride, u_stride, v_stride) = source.strides_yuv();
+
+        Self {
+            width,
+            height,
+            y: scale_plane(
+                source.y_with_stride(),
+                y_stride,
+                (source_width, source_height),
+                (width, height),
+            ),
+            u: scale_plane(
+                source.u_with_stride(),
+                u_stride,
+                (source_width / 2, source_height / 2),
+                (width / 2, height / 2),
+            ),
+            v: scale_plane(
+                source.v_with_stride(),
+                v_stride,
+                (source_width / 2, source_height / 2),
+                (width / 2, height / 2),
+            ),
+        }
+    }
+}
+
+impl YUVSource for Frame {
+    fn width(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn height(&self) -> i32 {
+        self.height as i32
+    }
+
+    fn y(&self) -> &[u8] {
+        &self.y
+    }
+
+    fn u(&self) -> &[u8] {
+        &self.u
+    }
+
+    fn v(&self) -> &[u8] {
+        &self.v
+    }
+
+    fn y_stride(&self) -> i32 {
+        self.width as i32
+    }
+
+    fn u_stride(&self) -> i32 {
+        self.width as i32 / 2
+    }
+
+    fn v_stride(&self) -> i32 {
+        self.width as i32 / 2
+    }
+}
+
+/// Scales a plane by averaging the source pixels each target pixel covers, or repeating them
+/// when scaling up.
+fn scale_plane(
+    source: &[u8],
+    stride: usize,
+    (source_width, source_height): (usize, usize),
+    (width, height): (usize, usize),
+) -> Vec<u8> {
+    let span = |i: usize, from: usize, to: usize| {
+        let start = i * from / to;
+        start..((i + 1) * from / to).max(start + 1)
+    };
+
+    let mut plane = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let rows = span(y, source_height, height);
+        for x in 0..width {
+            let columns = span(x, source_width, width);
+            let count = rows.len() * columns.len();
+            let sum = rows
+                .clone()
+                .flat_map(|row| &source[row * stride..][columns.clone()])
+                .map(|&pixel| pixel as usize)
+                .sum::<usize>();
+            plane.push((sum / count) as u8);
+        }
+    }
+
+    plane
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_plane_averages() {
+        // 4x2 with a stride of 5, the padding must not leak in
+        let source = [10, 20, 30, 40, 255, 30, 40, 50, 60, 255];
+        assert_eq!(scale_plane(&source, 5, (4, 2), (2, 1)), [25, 45]);
+        assert_eq!(
+            scale_plane(&source, 5, (4, 2), (4, 2)),
+            [10, 20, 30, 40, 30, 40, 50, 60]
+        );
+        assert_eq!(scale_plane(&[7, 9], 2, (2, 1), (4, 1)), [7, 7, 9, 9]);
+    }
+
+    #[test]
+    fn rate_adapts_to_delivered_throughput() {
+        let start = Instant::now();
+        let config = TranscodeConfig {
+            bitrate: 200_000,
+            min_bitrate: 20_000,
+            ..TranscodeConfig::default()
+        };
+        let mut rate = RateControl::new(&config, start);
+
+        // 100 kbit/s got through while a subscriber fell behind
+        rate.record(25_000, false);
+        rate.record(5_000, true);
+        assert_eq!(rate.adapt(start + Duration::from_secs(2)), Some(80_000));
+
+        // recovers, but not beyond the configured bitrate
+        rate.record(20_000, false);
+        assert_eq!(rate.adapt(start + Duration::from_secs(4)), Some(88_000));
+        for i in 3..20 {
+            rate.adapt(start + Duration::from_secs(2 * i));
+        }
+        assert_eq!(rate.bitrate, 200_000);
+
+        // nothing delivered at all
+        rate.record(1_000, true);
+        assert_eq!(rate.adapt(start + Duration::from_secs(50)), Some(20_000));
+    }
+}
@@ -0,0 +1,208 @@
+//! Browser remote control: serves a page that decodes the video with WebCodecs and talks to the
+//! rover over a WebSocket.
+//!
+//! Binary messages to the browser start with a kind byte, a flags byte and the little endian
+//! `u32` timestamp of the packet:
+//! - kind 1: H.264 Annex B data, flag bit 0 set on keyframes
+//! - kind 2: mono 16 bit little endian PCM at [`AUDIO_SAMPLE_RATE`](crate::rover::media::AUDIO_SAMPLE_RATE)
+//!
+//! Text messages from the browser are JSON, either `"refresh"` to keep the current motion alive
+//! or `{"command": ...}` with a [`Command`] in its serde representation, e.g.
+//! `{"command": {"drive": ["forward", "neutral", "fast"]}}`. Each browser is its own operator, see
+//! [`arbiter`](crate::rover::arbiter): `"request_control"` asks the holder of the control lease to
+//! hand over and `"release_control"` gives it up.
+
+use std::{
+    io::{BufReader, ErrorKind},
+    net::{SocketAddr, TcpStream},
+    sync::Arc,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::{info, warn};
+use serde::Deserialize;
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+use crate::rover::{arbiter::Operator, media::StreamPacket, Command, Rover};
+
+use super::{
+    http::{self, HttpRequest},
+    listen, MediaBroadcast,
+};
+
+const INDEX_HTML: &str = include_str!("web.html");
+
+const KIND_VIDEO: u8 = 1;
+const KIND_AUDIO: u8 = 2;
+const FLAG_KEYFRAME: u8 = 1;
+
+/// Packets a browser may fall behind before it misses some.
+const CLIENT_BACKLOG: usize = 64;
+/// How long reading from and writing to a browser may block the other direction.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClientMessage {
+    Refresh,
+    Command(Command),
+    RequestControl,
+    ReleaseControl,
+}
+
+/// Serves the control page on `/` and the WebSocket on `/ws`.
+pub fn spawn(
+    address: SocketAddr,
+    rover: Arc<Rover>,
+    media: Arc<MediaBroadcast>,
+) -> anyhow::Result<JoinHandle<()>> {
+    listen(address, "web", move |stream| {
+        handle_connection(stream, &rover, &media)
+    })
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    rover: &Rover,
+    media: &MediaBroadcast,
+) -> anyhow::Result<()> {
+    let request = HttpRequest::read_from(&mut BufReader::new(&mut stream))?;
+
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/") => http::respond(
+            &mut stream,
+            200,
+            "text/html; charset=utf-8",
+            INDEX_HTML.as_bytes(),
+        )?,
+        ("GET", "/ws") => {
+            let Some(key) = request.header("Sec-WebSocket-Key") else {
+                return Ok(http::respond_error(&mut stream, 400)?);
+            };
+
+            std::io::Write::write_all(
+                &mut stream,
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                     Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                    derive_accept_key(key.as_bytes())
+                )
+                .as_bytes(),
+            )?;
+
+            let peer = stream.peer_addr()?;
+            info!("browser connected from {peer}");
+            let operator = Operator::new(format!("web:{peer}"));
+            let result = relay(
+                WebSocket::from_raw_socket(stream, Role::Server, None),
+                rover,
+                &operator,
+                media,
+            );
+            rover.release_control(&operator)?;
+            info!("browser at {peer} disconnected");
+
+            return result;
+        }
+        (_, "/" | "/ws") => http::respond_error(&mut stream, 405)?,
+        _ => http::respond_error(&mut stream, 404)?,
+    }
+
+    Ok(())
+}
+
+/// Streams media to the browser and executes its commands until either side hangs up.
+fn relay(
+    mut socket: WebSocket<TcpStream>,
+    rover: &Rover,
+    operator: &Operator,
+    media: &MediaBroadcast,
+) -> anyhow::Result<()> {
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    let packets = media.subscribe(CLIENT_BACKLOG);
+
+    // the decoder can only start at a keyframe, and has to restart at one after a gap
+    let mut synced = false;
+
+    loop {
+        let mut timeout = POLL_INTERVAL;
+        while let Some(packet) = packets.recv_timeout(timeout)? {
+            timeout = Duration::ZERO;
+
+            if packets.take_lagged() {
+                synced = false;
+            }
+            if packet.is_keyframe() {
+                synced = true;
+            }
+
+            if let Some(message) = encode(&packet, synced) {
+                socket.send(Message::Binary(message))?;
+            }
+        }
+
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                if let Err(e) = execute(rover, operator, &text) {
+                    warn!("browser message {text:?} failed: {e}");
+                    socket.send(Message::Text(
+                        serde_json::json!({ "error": e.to_string() }).to_string(),
+                    ))?;
+                }
+            }
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+fn execute(rover: &Rover, operator: &Operator, text: &str) -> anyhow::Result<()> {
+    match serde_json::from_str(text)? {
+        ClientMessage::Refresh => {
+            rover.refresh_motion(operator);
+            Ok(())
+        }
+        ClientMessage::Command(command) => rover.send_command(operator, command),
+        ClientMessage::RequestControl => rover.request_control(operator),
+        ClientMessage::ReleaseControl => rover.release_control(operator),
+    }
+}
+
+fn encode(packet: &StreamPacket, synced: bool) -> Option<Vec<u8>> {
+    let (kind, flags, timestamp, payload) = match packet {
+        StreamPacket::Video {
+            timestamp, data, ..
+        } if synced => (
+            KIND_VIDEO,
+            if packet.is_keyframe() {
+                FLAG_KEYFRAME
+            } else {
+                0
+            },
+            timestamp,
+            data.clone(),
+        ),
+        StreamPacket::Video { .. } => return None,
+        StreamPacket::Audio { timestamp, .. } => (
+            KIND_AUDIO,
+            0,
+            timestamp,
+            packet
+                .to_pcm()?
+                .into_iter()
+                .flat_map(i16::to_le_bytes)
+                .collect(),
+        ),
+    };
+
+    let mut message = vec![kind, flags];
+    message.extend(timestamp.to_le_bytes());
+    message.extend(payload);
+
+    Some(message)
+}